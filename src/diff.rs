@@ -0,0 +1,156 @@
+//! Compare two extractions, e.g. of two revisions of a flake, to report what changed between them.
+//! Descriptions are matched on their system and attribute path, a derivation changed when its
+//! output path did.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::DerivationDescription;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// Only in the new extraction
+    Added,
+    /// Only in the old extraction
+    Removed,
+    /// In both, with a different output path
+    Changed,
+}
+
+/// A derivation that differs between two extractions. The `old_` fields are empty for added
+/// derivations, the `new_` fields for removed ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct Change {
+    pub change: ChangeKind,
+    pub attribute_path: String,
+    pub system: String,
+    pub old_output_path: Option<String>,
+    pub new_output_path: Option<String>,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+}
+
+/// Descriptions keyed by system and attribute path. An attribute path described several times,
+/// e.g. as the dependency of several derivations with different outputs, keeps the description
+/// with the lowest output path, so the result does not depend on the order they arrived in.
+fn by_attribute_path(
+    descriptions: impl IntoIterator<Item = DerivationDescription>,
+) -> BTreeMap<(String, String), DerivationDescription> {
+    let mut keyed: BTreeMap<_, DerivationDescription> = BTreeMap::new();
+    for description in descriptions {
+        let key = (
+            description.system.clone(),
+            description.attribute_path.clone(),
+        );
+        let keep = match keyed.get(&key) {
+            Some(kept) => description.output_path < kept.output_path,
+            None => true,
+        };
+        if keep {
+            keyed.insert(key, description);
+        }
+    }
+    keyed
+}
+
+/// The derivations that were added, removed or changed from `old` to `new`, sorted by system and
+/// attribute path
+pub fn diff(
+    old: impl IntoIterator<Item = DerivationDescription>,
+    new: impl IntoIterator<Item = DerivationDescription>,
+) -> Vec<Change> {
+    let mut old = by_attribute_path(old);
+    let new = by_attribute_path(new);
+    let version = |description: &DerivationDescription| {
+        Some(description.parsed_name.version.clone()).filter(|version| !version.is_empty())
+    };
+
+    let mut changes = Vec::new();
+    for ((system, attribute_path), new) in new {
+        let old = old.remove(&(system.clone(), attribute_path.clone()));
+        if old
+            .as_ref()
+            .is_some_and(|old| old.output_path == new.output_path)
+        {
+            continue;
+        }
+        changes.push(Change {
+            change: if old.is_some() {
+                ChangeKind::Changed
+            } else {
+                ChangeKind::Added
+            },
+            attribute_path,
+            system,
+            old_version: old.as_ref().and_then(version),
+            old_output_path: old.and_then(|old| old.output_path),
+            new_version: version(&new),
+            new_output_path: new.output_path,
+        });
+    }
+    for ((system, attribute_path), old) in old {
+        changes.push(Change {
+            change: ChangeKind::Removed,
+            attribute_path,
+            system,
+            old_version: version(&old),
+            old_output_path: old.output_path,
+            new_output_path: None,
+            new_version: None,
+        });
+    }
+
+    changes.sort_by(|a, b| (&a.system, &a.attribute_path).cmp(&(&b.system, &b.attribute_path)));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    #[test]
+    fn test_diff() {
+        let hello = description("hello", "2.12.1", &[]);
+        let zlib = description("zlib", "1.3", &[]);
+        let openssl = description("openssl", "3.0", &[]);
+        // Same attribute path, with a different output path
+        let new_hello = description("hello", "2.12.2", &[]);
+        let curl = description("curl", "8.5", &[]);
+
+        let changes = diff(
+            vec![hello.clone(), zlib.clone(), openssl.clone()],
+            vec![openssl, curl.clone(), new_hello.clone(), zlib.clone()],
+        );
+
+        assert_eq!(
+            changes,
+            vec![
+                Change {
+                    change: ChangeKind::Added,
+                    attribute_path: curl.attribute_path.clone(),
+                    system: curl.system.clone(),
+                    old_output_path: None,
+                    new_output_path: curl.output_path.clone(),
+                    old_version: None,
+                    new_version: Some("8.5".to_owned()),
+                },
+                Change {
+                    change: ChangeKind::Changed,
+                    attribute_path: hello.attribute_path.clone(),
+                    system: hello.system.clone(),
+                    old_output_path: hello.output_path.clone(),
+                    new_output_path: new_hello.output_path.clone(),
+                    old_version: Some("2.12.1".to_owned()),
+                    new_version: Some("2.12.2".to_owned()),
+                },
+            ]
+        );
+
+        let changes = diff(vec![zlib.clone()], Vec::new());
+        assert_eq!(changes[0].change, ChangeKind::Removed);
+        assert_eq!(changes[0].old_output_path, zlib.output_path);
+    }
+}
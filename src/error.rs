@@ -8,6 +8,36 @@ pub enum Error {
     #[error("Nix exited with a non-zero exit code: {0:#?}: {1}")]
     NixCommand(Option<i32>, String),
 
+    #[error("The flake could not be found: {0}")]
+    FlakeNotFound(String),
+
+    #[error("The attribute path does not exist in the flake: {0}")]
+    AttributePathNotFound(String),
+
+    #[error("Nix failed to evaluate the derivation: {0}")]
+    EvaluationError(String),
+
+    #[error("Nix could not fetch something it needed in offline mode: {0}")]
+    OfflineFetchFailed(String),
+
+    #[error("Nix could not fetch something it needed, this may be a network failure: {0}")]
+    FetchFailed(String),
+
+    #[error("Nix did not finish evaluating {0} before the timeout")]
+    NixTimeout(String),
+
+    #[error("The run did not finish within its total timeout of {0:?}")]
+    TimeoutTotal(std::time::Duration),
+
+    #[error("Nix ran out of memory evaluating {0}, it exceeded the maximum evaluation memory")]
+    EvalOutOfMemory(String),
+
+    #[error("Unknown system `{0}`, expected one of {}", crate::KNOWN_SYSTEMS.join(", "))]
+    InvalidSystem(String),
+
+    #[error("Could not find `{0}` on the PATH, nixtract needs Nix to be installed, see https://nixos.org/download")]
+    NixNotInstalled(String),
+
     #[error("IO error when calling Nix: {0}")]
     NixIO(#[from] std::io::Error),
 
@@ -17,15 +47,21 @@ pub enum Error {
     #[error("Error when sending a status message to the caller: {0}")]
     MessageMpsc(Box<std::sync::mpsc::SendError<crate::message::Message>>),
 
-    #[error("The provided value could not be parsed as an integer: {0}")]
-    NarInfoParseIntError(#[from] std::num::ParseIntError),
+    #[error("The value on line {0} of the narinfo could not be parsed as an integer: {1}")]
+    NarInfoParseIntError(usize, std::num::ParseIntError),
 
-    #[error("The provided NarInfo could not be parsed because we encountered a line without a delimiter: {0}")]
-    NarInfoNoDelimiter(String),
+    #[error("The provided NarInfo could not be parsed because line {0} has no delimiter: {1}")]
+    NarInfoNoDelimiter(usize, String),
 
     #[error("The provided NarInfo is missing a required field: {0}")]
     NarInfoMissingField(String),
 
+    #[error("The provided NarInfo has the field {0} more than once")]
+    NarInfoDuplicateField(String),
+
+    #[error("The provided NarInfo has the unknown field {0}")]
+    NarInfoUnknownField(String),
+
     #[error("The store path is malformed and cannot be used to fetch the narinfo: {0}")]
     NarInfoInvalidPath(String),
 
@@ -34,6 +70,57 @@ pub enum Error {
 
     #[error("The field {0} of the parsed narinfo file was invalid for reason: {1}")]
     NarInfoInvalidField(String, String),
+
+    #[error("The narinfo served by {0} is invalid: {1}")]
+    NarInfoInvalid(String, Box<Error>),
+
+    #[error("Could not start the narinfo fetch pool: {0}")]
+    NarInfoPool(#[from] rayon::ThreadPoolBuildError),
+
+    #[error("Could not start the worker pool: {0}")]
+    WorkerPool(rayon::ThreadPoolBuildError),
+
+    #[error("{0} has no known deriver, it may have been added to the store directly or its .drv file garbage collected")]
+    NoDeriver(String),
+
+    #[error("The provided exclude pattern is not a valid regular expression: {0}")]
+    InvalidExcludePattern(#[from] regex::Error),
+}
+
+impl Error {
+    /// Exit code of the CLI when it stops on this error, so that scripts can tell the categories
+    /// apart: 2 when the flake or attribute path does not exist, 3 when nix fails to evaluate, 4
+    /// when fetching from the network or a binary cache fails, 124 when the run exceeds its total
+    /// timeout and 1 for everything else
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::FlakeNotFound(_) | Error::AttributePathNotFound(_) => 2,
+            Error::EvaluationError(_) | Error::NixTimeout(_) | Error::EvalOutOfMemory(_) => 3,
+            Error::OfflineFetchFailed(_)
+            | Error::FetchFailed(_)
+            | Error::NarInfoParseIntError(..)
+            | Error::NarInfoNoDelimiter(..)
+            | Error::NarInfoMissingField(_)
+            | Error::NarInfoDuplicateField(_)
+            | Error::NarInfoUnknownField(_)
+            | Error::NarInfoInvalidPath(_)
+            | Error::NarInfoReqwest(_)
+            | Error::NarInfoInvalidField(..)
+            | Error::NarInfoInvalid(..) => 4,
+            Error::TimeoutTotal(_) => 124,
+            Error::SerdeJSON(..)
+            | Error::NixCommand(..)
+            | Error::InvalidSystem(_)
+            | Error::NixNotInstalled(_)
+            | Error::NixIO(_)
+            | Error::Mpsc(_)
+            | Error::MessageMpsc(_)
+            | Error::NarInfoPool(_)
+            | Error::WorkerPool(_)
+            | Error::NoDeriver(_)
+            | Error::InvalidExcludePattern(_) => 1,
+        }
+    }
 }
 
 // Cannot automatically derive using #[from] because of the Box
@@ -48,3 +135,27 @@ impl From<std::sync::mpsc::SendError<crate::message::Message>> for Error {
         Error::MessageMpsc(Box::new(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(Error::FlakeNotFound("github:a/b".to_owned()).exit_code(), 2);
+        assert_eq!(Error::EvaluationError("hello".to_owned()).exit_code(), 3);
+        assert_eq!(
+            Error::NarInfoInvalid(
+                "https://cache.nixos.org".to_owned(),
+                Box::new(Error::NarInfoMissingField("StorePath".to_owned()))
+            )
+            .exit_code(),
+            4
+        );
+        assert_eq!(
+            Error::TimeoutTotal(std::time::Duration::from_secs(60)).exit_code(),
+            124
+        );
+        assert_eq!(Error::NixCommand(Some(1), String::new()).exit_code(), 1);
+    }
+}
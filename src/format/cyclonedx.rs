@@ -0,0 +1,210 @@
+//! CycloneDX 1.5 JSON SBOM, see <https://cyclonedx.org/docs/1.5/json/>
+
+use serde::Serialize;
+
+use crate::{license, DerivationDescription, License};
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Bom {
+    pub bom_format: String,
+    pub spec_version: String,
+    pub version: u32,
+    pub metadata: Metadata,
+    pub components: Vec<Component>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub tools: Tools,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tools {
+    pub components: Vec<Tool>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Component {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    #[serde(rename = "bom-ref")]
+    pub bom_ref: String,
+    pub name: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub purl: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub licenses: Option<Vec<LicenseChoice>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LicenseChoice {
+    pub license: ComponentLicense,
+}
+
+/// A license is identified either by its SPDX id, or by its name when it has none
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ComponentLicense {
+    Id(String),
+    Name(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Dependency {
+    #[serde(rename = "ref")]
+    pub dependency_ref: String,
+    pub depends_on: Vec<String>,
+}
+
+/// The reference used to link a component to its dependencies
+fn bom_ref(description: &DerivationDescription) -> String {
+    description
+        .output_path
+        .clone()
+        .unwrap_or_else(|| description.attribute_path.clone())
+}
+
+impl From<&License> for LicenseChoice {
+    fn from(license: &License) -> Self {
+        LicenseChoice {
+            license: match &license.spdx_id {
+                Some(spdx_id) => ComponentLicense::Id(license::normalize_spdx_id(spdx_id)),
+                None => ComponentLicense::Name(license.full_name.clone()),
+            },
+        }
+    }
+}
+
+impl From<&DerivationDescription> for Component {
+    fn from(description: &DerivationDescription) -> Self {
+        Component {
+            component_type: "library".to_owned(),
+            bom_ref: bom_ref(description),
+            name: description.parsed_name.name.clone(),
+            version: description.parsed_name.version.clone(),
+            description: Some(description.nixpkgs_metadata.description.clone())
+                .filter(|d| !d.is_empty()),
+            purl: description.purl.clone(),
+            licenses: description
+                .nixpkgs_metadata
+                .licenses
+                .as_ref()
+                .map(|licenses| licenses.iter().map(LicenseChoice::from).collect()),
+        }
+    }
+}
+
+/// Build a CycloneDX BOM with one component per derivation, and the dependency graph between them.
+/// Dependencies on derivations that are not part of `descriptions` are left out.
+pub fn to_cyclonedx(descriptions: &[DerivationDescription]) -> Bom {
+    let refs = super::ids_by_output_path(descriptions, |_, description| bom_ref(description));
+
+    let dependencies = descriptions
+        .iter()
+        .map(|description| {
+            let mut depends_on: Vec<String> = description
+                .build_inputs
+                .iter()
+                .filter_map(|build_input| build_input.output_path.as_deref())
+                .filter_map(|output_path| refs.get(output_path).cloned())
+                .collect();
+            depends_on.sort();
+            depends_on.dedup();
+
+            Dependency {
+                dependency_ref: bom_ref(description),
+                depends_on,
+            }
+        })
+        .collect();
+
+    Bom {
+        bom_format: "CycloneDX".to_owned(),
+        spec_version: "1.5".to_owned(),
+        version: 1,
+        metadata: Metadata {
+            tools: Tools {
+                components: vec![Tool {
+                    component_type: "application".to_owned(),
+                    name: env!("CARGO_PKG_NAME").to_owned(),
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                }],
+            },
+        },
+        components: descriptions.iter().map(Component::from).collect(),
+        dependencies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    #[test]
+    fn test_to_cyclonedx() {
+        let mut hello = description("hello", "2.12.1", &[]);
+        hello.nixpkgs_metadata.licenses = Some(vec![
+            License {
+                spdx_id: Some("GPL-3.0-or-later".to_owned()),
+                full_name: "GNU General Public License v3.0 or later".to_owned(),
+                short_name: None,
+                free: None,
+            },
+            License {
+                spdx_id: None,
+                full_name: "Unfree".to_owned(),
+                short_name: None,
+                free: None,
+            },
+        ]);
+        let hello_path = hello.output_path.clone().unwrap();
+        // The second input is not part of the extraction and should be dropped
+        let app = description("app", "1.0", &[&hello_path, "/nix/store/missing"]);
+
+        let bom = to_cyclonedx(&[app.clone(), hello.clone()]);
+
+        assert_eq!(bom.components.len(), 2);
+        assert_eq!(bom.components[0].purl, "pkg:nix/app@1.0");
+        assert_eq!(bom.components[0].licenses, None);
+        assert_eq!(
+            bom.components[1].licenses,
+            Some(vec![
+                LicenseChoice {
+                    license: ComponentLicense::Id("GPL-3.0-or-later".to_owned())
+                },
+                LicenseChoice {
+                    license: ComponentLicense::Name("Unfree".to_owned())
+                },
+            ])
+        );
+        assert_eq!(
+            bom.dependencies[0],
+            Dependency {
+                dependency_ref: app.output_path.unwrap(),
+                depends_on: vec![hello_path],
+            }
+        );
+
+        // Components without licenses must not serialize a null license field
+        let json = serde_json::to_value(&bom).unwrap();
+        assert!(json["components"][0].get("licenses").is_none());
+        assert_eq!(
+            json["components"][1]["licenses"][0]["license"]["id"],
+            "GPL-3.0-or-later"
+        );
+    }
+}
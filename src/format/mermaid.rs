@@ -0,0 +1,96 @@
+//! Mermaid flowchart of the dependency graph, to embed in Markdown, see
+//! <https://mermaid.js.org/syntax/flowchart.html>
+
+use std::fmt::Write;
+
+use crate::DerivationDescription;
+
+/// Mermaid node ids may only contain letters, digits and underscores. Store paths start with a
+/// unique hash, so sanitizing their base name keeps them unique.
+fn node_id(description: &DerivationDescription) -> String {
+    let key = description
+        .output_path
+        .as_deref()
+        .unwrap_or(&description.attribute_path);
+    let base_name = key.rsplit('/').next().unwrap_or(key);
+    let sanitized: String = base_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    // An id starting with a digit is fine, but some words such as `end` are reserved
+    format!("n_{}", sanitized)
+}
+
+/// Labels are quoted, quotes inside them have to be escaped as an entity
+fn label(description: &DerivationDescription) -> String {
+    let label = if description.parsed_name.version.is_empty() {
+        description.parsed_name.name.clone()
+    } else {
+        format!(
+            "{} {}",
+            description.parsed_name.name, description.parsed_name.version
+        )
+    };
+    label.replace('"', "#quot;")
+}
+
+/// Build a Mermaid `graph LR` with one node per derivation and an edge to each of its build
+/// inputs. Build inputs that are not part of `descriptions` are left out.
+pub fn to_mermaid(descriptions: &[DerivationDescription]) -> String {
+    let ids = super::ids_by_output_path(descriptions, |_, description| node_id(description));
+
+    let mut graph = String::from("graph LR\n");
+    for description in descriptions {
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            graph,
+            "    {}[\"{}\"]",
+            node_id(description),
+            label(description)
+        );
+    }
+    for description in descriptions {
+        let id = node_id(description);
+        let mut inputs: Vec<&String> = description
+            .build_inputs
+            .iter()
+            .filter_map(|build_input| build_input.output_path.as_deref())
+            .filter_map(|output_path| ids.get(output_path))
+            .collect();
+        inputs.sort();
+        inputs.dedup();
+        for input in inputs {
+            let _ = writeln!(graph, "    {} --> {}", id, input);
+        }
+    }
+
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::{description, hash};
+
+    #[test]
+    fn test_to_mermaid() {
+        let mut hello = description("hello", "2.12.1", &[]);
+        hello.parsed_name.name = "hello \"world\"".to_owned();
+        let hello_path = hello.output_path.clone().unwrap();
+        // The second input is not part of the extraction and should be dropped
+        let app = description(
+            "app",
+            "1.0",
+            &[&hello_path, &hello_path, "/nix/store/missing"],
+        );
+
+        let app_id = format!("n_{}_app_1_0", hash("app"));
+        let hello_id = format!("n_{}_hello_2_12_1", hash("hello"));
+        assert_eq!(
+            to_mermaid(&[app, hello]),
+            format!(
+                "graph LR\n    {app_id}[\"app 1.0\"]\n    {hello_id}[\"hello #quot;world#quot; 2.12.1\"]\n    {app_id} --> {hello_id}\n"
+            )
+        );
+    }
+}
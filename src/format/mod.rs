@@ -0,0 +1,105 @@
+//! Serialization of the extracted derivations into formats other than the default JSON lines.
+//! These formats describe the graph as a whole, so all derivations have to be collected first.
+//! [`sink`] writes any of them, including JSON lines, as the derivations are extracted.
+
+pub mod cyclonedx;
+pub mod mermaid;
+pub mod sink;
+pub mod spdx;
+pub mod tree;
+
+use std::collections::HashMap;
+
+use crate::DerivationDescription;
+
+/// Map the output paths of every description to its id, given its index in `descriptions`, since
+/// build inputs refer to a specific output of a derivation
+pub(crate) fn ids_by_output_path<T: Clone>(
+    descriptions: &[DerivationDescription],
+    id: impl Fn(usize, &DerivationDescription) -> T,
+) -> HashMap<&str, T> {
+    descriptions
+        .iter()
+        .enumerate()
+        .flat_map(|(index, description)| {
+            let id = id(index, description);
+            description
+                .outputs
+                .iter()
+                .filter_map(|output| output.output_path.as_deref())
+                .chain(description.output_path.as_deref())
+                .map(move |output_path| (output_path, id.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{
+        BuildInputType, BuiltInput, DerivationDescription, FlakeOutput, NixpkgsMetadata, Output,
+        ParsedName,
+    };
+
+    /// A minimal description of `name-version` depending on the given output paths
+    pub(crate) fn description(name: &str, version: &str, inputs: &[&str]) -> DerivationDescription {
+        let output_path = format!("/nix/store/{}-{}-{}", hash(name), name, version);
+        DerivationDescription {
+            schema_version: crate::SCHEMA_VERSION.to_owned(),
+            attribute_path: name.to_owned(),
+            derivation_path: Some(format!("{}.drv", output_path)),
+            output_path: Some(output_path.clone()),
+            outputs: vec![Output {
+                name: "out".to_owned(),
+                output_path: Some(output_path),
+                hash: None,
+                hash_algo: None,
+                hash_mode: None,
+                nar_info: None,
+            }],
+            outputs_to_install: vec!["out".to_owned()],
+            name: format!("{}-{}", name, version),
+            parsed_name: ParsedName {
+                name: name.to_owned(),
+                version: version.to_owned(),
+            },
+            nixpkgs_metadata: NixpkgsMetadata {
+                description: String::new(),
+                pname: name.to_owned(),
+                version: version.to_owned(),
+                broken: false,
+                unfree: false,
+                insecure: false,
+                known_vulnerabilities: Vec::new(),
+                homepage: String::new(),
+                licenses: None,
+                maintainers: Vec::new(),
+            },
+            meta_position: None,
+            sources: Vec::new(),
+            system: "x86_64-linux".to_owned(),
+            flake_output: FlakeOutput::Packages,
+            output_type: "packages".to_owned(),
+            platforms: Vec::new(),
+            build_inputs: inputs
+                .iter()
+                .map(|input| BuiltInput {
+                    attribute_path: format!("{}.drvAttrs.buildInputs", name),
+                    build_input_type: BuildInputType::BuildInput,
+                    output_path: Some(input.to_string()),
+                    all_output_paths: Vec::new(),
+                })
+                .collect(),
+            eval_warnings: Vec::new(),
+            purl: format!("pkg:nix/{}@{}", name, version),
+            nar_info: None,
+            self_size: None,
+            closure_size: None,
+            drv: None,
+        }
+    }
+
+    /// A fake store hash, unique per (short) name
+    pub(crate) fn hash(name: &str) -> String {
+        format!("{:x>32}", name)
+    }
+}
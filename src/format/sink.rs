@@ -0,0 +1,206 @@
+//! Writing descriptions as they are extracted, whatever the output format. Streaming formats
+//! write every description as soon as it is emitted, aggregate formats buffer them and write the
+//! whole document in [`OutputSink::finish`].
+
+use std::io::{self, Write};
+
+use crate::DerivationDescription;
+
+/// Destination of the extracted descriptions, in a given format. `begin` is called once before
+/// the first description, `emit` once per description and `finish` once all of them are emitted,
+/// after which everything is written and flushed.
+pub trait OutputSink {
+    fn begin(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn emit(&mut self, description: &DerivationDescription) -> io::Result<()>;
+
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Write `value` as JSON followed by a newline, indented if `pretty`. Every format, and every
+/// other JSON nixtract writes, goes through here so that `pretty` applies to all of them.
+pub fn write_json(
+    mut out: impl Write,
+    value: &impl serde::Serialize,
+    pretty: bool,
+) -> io::Result<()> {
+    if pretty {
+        serde_json::to_writer_pretty(&mut out, value)?;
+    } else {
+        serde_json::to_writer(&mut out, value)?;
+    }
+    out.write_all(b"\n")
+}
+
+/// One JSON description per line, written as soon as it is emitted
+pub struct Jsonl<W: Write> {
+    out: W,
+    pretty: bool,
+    flush_per_record: bool,
+    checkpoint: Option<Box<dyn Write>>,
+}
+
+impl<W: Write> Jsonl<W> {
+    pub fn new(out: W, pretty: bool, flush_per_record: bool) -> Self {
+        Self {
+            out,
+            pretty,
+            flush_per_record,
+            checkpoint: None,
+        }
+    }
+
+    /// Record the output path of every description on its own line in `checkpoint`, once the
+    /// description is flushed to the output
+    pub fn checkpoint(mut self, checkpoint: impl Write + 'static) -> Self {
+        self.checkpoint = Some(Box::new(checkpoint));
+        self
+    }
+}
+
+impl<W: Write> OutputSink for Jsonl<W> {
+    fn emit(&mut self, description: &DerivationDescription) -> io::Result<()> {
+        write_json(&mut self.out, description, self.pretty)?;
+
+        // Only record derivations whose description actually made it to the output
+        if self.flush_per_record || self.checkpoint.is_some() {
+            self.out.flush()?;
+        }
+        if let (Some(checkpoint), Some(output_path)) =
+            (&mut self.checkpoint, &description.output_path)
+        {
+            writeln!(checkpoint, "{}", output_path)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// A format describing all descriptions at once, rendered by `render` in [`OutputSink::finish`]
+struct Buffered<W: Write, F> {
+    out: W,
+    descriptions: Vec<DerivationDescription>,
+    render: F,
+}
+
+impl<W, F> OutputSink for Buffered<W, F>
+where
+    W: Write,
+    F: FnMut(&mut W, &[DerivationDescription]) -> io::Result<()>,
+{
+    fn emit(&mut self, description: &DerivationDescription) -> io::Result<()> {
+        self.descriptions.push(description.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (self.render)(&mut self.out, &self.descriptions)?;
+        self.out.flush()
+    }
+}
+
+fn buffered<'a, W: Write + 'a>(
+    out: W,
+    render: impl FnMut(&mut W, &[DerivationDescription]) -> io::Result<()> + 'a,
+) -> Box<dyn OutputSink + 'a> {
+    Box::new(Buffered {
+        out,
+        descriptions: Vec::new(),
+        render,
+    })
+}
+
+/// CycloneDX 1.5 JSON SBOM, see [`super::cyclonedx`]
+pub fn cyclonedx<'a>(out: impl Write + 'a, pretty: bool) -> Box<dyn OutputSink + 'a> {
+    buffered(out, move |out, descriptions| {
+        write_json(out, &super::cyclonedx::to_cyclonedx(descriptions), pretty)
+    })
+}
+
+/// SPDX 2.3 JSON document named `name`, created when it is written, see [`super::spdx`]
+pub fn spdx<'a>(out: impl Write + 'a, name: String, pretty: bool) -> Box<dyn OutputSink + 'a> {
+    buffered(out, move |out, descriptions| {
+        let document = super::spdx::to_spdx(&name, chrono::Utc::now(), descriptions);
+        write_json(out, &document, pretty)
+    })
+}
+
+/// Mermaid flowchart of the dependency graph, see [`super::mermaid`]
+pub fn mermaid<'a>(out: impl Write + 'a) -> Box<dyn OutputSink + 'a> {
+    buffered(out, |out, descriptions| {
+        out.write_all(super::mermaid::to_mermaid(descriptions).as_bytes())
+    })
+}
+
+/// Indented tree of the dependency graph, see [`super::tree`]
+pub fn tree<'a>(out: impl Write + 'a) -> Box<dyn OutputSink + 'a> {
+    buffered(out, |out, descriptions| {
+        out.write_all(super::tree::to_tree(descriptions).as_bytes())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    /// A writer whose contents can still be read once it is moved into a sink
+    #[derive(Clone, Default)]
+    struct Shared(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sinks() {
+        let hello = description("hello", "2.12.1", &[]);
+        let app = description("app", "1.0", &[hello.output_path.as_deref().unwrap()]);
+
+        let mut out = Vec::new();
+        let checkpoint = Shared::default();
+        let mut jsonl = Jsonl::new(&mut out, false, false).checkpoint(checkpoint.clone());
+        jsonl.begin().unwrap();
+        jsonl.emit(&hello).unwrap();
+        // Streamed before the end of the extraction
+        assert_eq!(
+            checkpoint.0.borrow().as_slice(),
+            format!("{}\n", hello.output_path.as_ref().unwrap()).as_bytes()
+        );
+        jsonl.emit(&app).unwrap();
+        jsonl.finish().unwrap();
+        drop(jsonl);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "{}\n{}\n",
+                serde_json::to_string(&hello).unwrap(),
+                serde_json::to_string(&app).unwrap()
+            )
+        );
+
+        let out = Shared::default();
+        let mut mermaid = mermaid(out.clone());
+        mermaid.begin().unwrap();
+        mermaid.emit(&app).unwrap();
+        mermaid.emit(&hello).unwrap();
+        // Nothing is written until the graph is complete
+        assert!(out.0.borrow().is_empty());
+        mermaid.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(out.0.borrow().clone()).unwrap(),
+            crate::format::mermaid::to_mermaid(&[app, hello])
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! SPDX 2.3 JSON document, see <https://spdx.github.io/spdx-spec/v2.3/>
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+
+use crate::license::normalize_spdx_id;
+use crate::{DerivationDescription, Source};
+
+const NOASSERTION: &str = "NOASSERTION";
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Document {
+    pub spdx_version: String,
+    pub data_license: String,
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub name: String,
+    pub document_namespace: String,
+    pub creation_info: CreationInfo,
+    pub packages: Vec<Package>,
+    pub relationships: Vec<Relationship>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationInfo {
+    pub created: String,
+    pub creators: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Package {
+    #[serde(rename = "SPDXID")]
+    pub spdx_id: String,
+    pub name: String,
+    pub version_info: String,
+    pub download_location: String,
+    pub license_concluded: String,
+    pub files_analyzed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Relationship {
+    pub spdx_element_id: String,
+    pub relationship_type: String,
+    pub related_spdx_element: String,
+}
+
+/// SPDX ids may only contain letters, numbers, `.` and `-`
+fn sanitize(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// The SPDX id of a derivation, derived from the hash of its store path
+fn spdx_id(description: &DerivationDescription) -> String {
+    let hash = description
+        .output_path
+        .as_deref()
+        .and_then(|output_path| output_path.strip_prefix("/nix/store/"))
+        .and_then(|base_name| base_name.split('-').next());
+
+    match hash {
+        Some(hash) => format!("SPDXRef-{}", hash),
+        None => format!("SPDXRef-{}", sanitize(&description.attribute_path)),
+    }
+}
+
+/// All licenses joined into a single expression, or `NOASSERTION` when any of them has no SPDX id
+fn license_concluded(description: &DerivationDescription) -> String {
+    let spdx_ids: Option<Vec<String>> = description
+        .nixpkgs_metadata
+        .licenses
+        .as_ref()
+        .filter(|licenses| !licenses.is_empty())
+        .and_then(|licenses| {
+            licenses
+                .iter()
+                .map(|license| license.spdx_id.as_deref().map(normalize_spdx_id))
+                .collect()
+        });
+
+    match spdx_ids {
+        Some(spdx_ids) => spdx_ids.join(" AND "),
+        None => NOASSERTION.to_owned(),
+    }
+}
+
+/// Where the first source of the derivation was downloaded from, paths are not a download location
+fn download_location(description: &DerivationDescription) -> String {
+    match description.sources.first() {
+        Some(Source::Git { url, rev }) if url.starts_with("git+") => format!("{}@{}", url, rev),
+        Some(Source::Git { url, rev }) => format!("git+{}@{}", url, rev),
+        Some(Source::Url { url, .. }) => url.clone(),
+        Some(Source::Path { .. }) | None => NOASSERTION.to_owned(),
+    }
+}
+
+impl From<&DerivationDescription> for Package {
+    fn from(description: &DerivationDescription) -> Self {
+        let metadata = &description.nixpkgs_metadata;
+        Package {
+            spdx_id: spdx_id(description),
+            name: description.parsed_name.name.clone(),
+            version_info: description.parsed_name.version.clone(),
+            download_location: download_location(description),
+            license_concluded: license_concluded(description),
+            files_analyzed: false,
+            description: Some(metadata.description.clone()).filter(|d| !d.is_empty()),
+            homepage: Some(metadata.homepage.clone()).filter(|h| !h.is_empty()),
+        }
+    }
+}
+
+/// Build an SPDX document with one package per derivation, and `DEPENDS_ON` relationships for
+/// their build inputs. The document `DESCRIBES` the derivations no other derivation depends on.
+pub fn to_spdx(
+    name: &str,
+    created: chrono::DateTime<chrono::Utc>,
+    descriptions: &[DerivationDescription],
+) -> Document {
+    let ids = super::ids_by_output_path(descriptions, |_, description| spdx_id(description));
+
+    let mut depends_on = Vec::new();
+    let mut dependencies: HashSet<&str> = HashSet::new();
+    for description in descriptions {
+        let id = spdx_id(description);
+        let mut related: Vec<&String> = description
+            .build_inputs
+            .iter()
+            .filter_map(|build_input| build_input.output_path.as_deref())
+            .filter_map(|output_path| ids.get(output_path))
+            .collect();
+        related.sort();
+        related.dedup();
+
+        for related in related {
+            dependencies.insert(related);
+            depends_on.push(Relationship {
+                spdx_element_id: id.clone(),
+                relationship_type: "DEPENDS_ON".to_owned(),
+                related_spdx_element: related.clone(),
+            });
+        }
+    }
+
+    let packages: Vec<Package> = descriptions.iter().map(Package::from).collect();
+    let mut relationships: Vec<Relationship> = packages
+        .iter()
+        .filter(|package| !dependencies.contains(package.spdx_id.as_str()))
+        .map(|package| Relationship {
+            spdx_element_id: "SPDXRef-DOCUMENT".to_owned(),
+            relationship_type: "DESCRIBES".to_owned(),
+            related_spdx_element: package.spdx_id.clone(),
+        })
+        .collect();
+    relationships.extend(depends_on);
+
+    let created = created.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+    Document {
+        spdx_version: "SPDX-2.3".to_owned(),
+        data_license: "CC0-1.0".to_owned(),
+        spdx_id: "SPDXRef-DOCUMENT".to_owned(),
+        name: name.to_owned(),
+        document_namespace: format!(
+            "https://github.com/tweag/nixtract/spdx/{}-{}",
+            sanitize(name),
+            created
+        ),
+        creation_info: CreationInfo {
+            created,
+            creators: vec![format!(
+                "Tool: {}-{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )],
+        },
+        packages,
+        relationships,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::{description, hash};
+    use crate::License;
+
+    #[test]
+    fn test_to_spdx() {
+        let mut hello = description("hello", "2.12.1", &[]);
+        hello.nixpkgs_metadata.licenses = Some(vec![License {
+            spdx_id: Some("GPL-3.0-or-later".to_owned()),
+            full_name: "GNU General Public License v3.0 or later".to_owned(),
+            short_name: None,
+            free: None,
+        }]);
+        hello.sources = vec![Source::Git {
+            url: "https://example.org/hello.git".to_owned(),
+            rev: "v2.12.1".to_owned(),
+        }];
+        let app = description("app", "1.0", &[hello.output_path.as_deref().unwrap()]);
+
+        let created = chrono::DateTime::from_timestamp(0, 0).unwrap();
+        let document = to_spdx("nixpkgs", created, &[app, hello]);
+
+        let app_id = format!("SPDXRef-{}", hash("app"));
+        let hello_id = format!("SPDXRef-{}", hash("hello"));
+
+        assert_eq!(document.creation_info.created, "1970-01-01T00:00:00Z");
+        assert_eq!(document.packages[0].spdx_id, app_id);
+        assert_eq!(document.packages[0].license_concluded, NOASSERTION);
+        assert_eq!(document.packages[0].download_location, NOASSERTION);
+        assert_eq!(document.packages[1].license_concluded, "GPL-3.0-or-later");
+        assert_eq!(
+            document.packages[1].download_location,
+            "git+https://example.org/hello.git@v2.12.1"
+        );
+        assert_eq!(
+            document.relationships,
+            vec![
+                Relationship {
+                    spdx_element_id: "SPDXRef-DOCUMENT".to_owned(),
+                    relationship_type: "DESCRIBES".to_owned(),
+                    related_spdx_element: app_id.clone(),
+                },
+                Relationship {
+                    spdx_element_id: app_id,
+                    relationship_type: "DEPENDS_ON".to_owned(),
+                    related_spdx_element: hello_id,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Indented tree of the dependency graph for terminals, like `cargo tree`. Every top-level
+//! derivation is a root, and a derivation that is already shown is marked with `(*)` instead of
+//! being expanded again.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::DerivationDescription;
+
+fn label(description: &DerivationDescription) -> String {
+    if description.parsed_name.version.is_empty() {
+        description.parsed_name.name.clone()
+    } else {
+        format!(
+            "{} {}",
+            description.parsed_name.name, description.parsed_name.version
+        )
+    }
+}
+
+struct Tree<'a> {
+    descriptions: &'a [DerivationDescription],
+    /// Indices of the build inputs of every description, sorted by label
+    inputs: Vec<Vec<usize>>,
+    expanded: HashSet<usize>,
+    out: String,
+}
+
+impl Tree<'_> {
+    fn render(&mut self, node: usize, prefix: &str, connector: &str, child_prefix: &str) {
+        let repeated = !self.expanded.insert(node);
+        // Writing to a String cannot fail
+        let _ = writeln!(
+            self.out,
+            "{}{}{}{}",
+            prefix,
+            connector,
+            label(&self.descriptions[node]),
+            if repeated && !self.inputs[node].is_empty() {
+                " (*)"
+            } else {
+                ""
+            }
+        );
+        if repeated {
+            return;
+        }
+
+        let prefix = format!("{}{}", prefix, child_prefix);
+        let inputs = self.inputs[node].clone();
+        for (index, &input) in inputs.iter().enumerate() {
+            if index + 1 == inputs.len() {
+                self.render(input, &prefix, "└── ", "    ");
+            } else {
+                self.render(input, &prefix, "├── ", "│   ");
+            }
+        }
+    }
+}
+
+/// Render the derivations that are not a build input of any other one as roots, each followed by
+/// its build inputs. Build inputs that are not part of `descriptions` are left out.
+pub fn to_tree(descriptions: &[DerivationDescription]) -> String {
+    let nodes = super::ids_by_output_path(descriptions, |node, _| node);
+
+    let inputs: Vec<Vec<usize>> = descriptions
+        .iter()
+        .map(|description| {
+            let mut inputs: Vec<usize> = description
+                .build_inputs
+                .iter()
+                .filter_map(|build_input| build_input.output_path.as_deref())
+                .filter_map(|output_path| nodes.get(output_path).copied())
+                .collect();
+            inputs.sort_by_key(|&input| (label(&descriptions[input]), input));
+            inputs.dedup();
+            inputs
+        })
+        .collect();
+
+    let dependencies: HashSet<usize> = inputs.iter().flatten().copied().collect();
+    let mut tree = Tree {
+        descriptions,
+        inputs,
+        expanded: HashSet::new(),
+        out: String::new(),
+    };
+    for root in (0..descriptions.len()).filter(|node| !dependencies.contains(node)) {
+        tree.render(root, "", "", "");
+    }
+    tree.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    #[test]
+    fn test_to_tree() {
+        let glibc = description("glibc", "2.38", &[]);
+        let glibc_path = glibc.output_path.clone().unwrap();
+        let zlib = description("zlib", "1.3", &[&glibc_path]);
+        let zlib_path = zlib.output_path.clone().unwrap();
+        let hello = description("hello", "2.12.1", &[&glibc_path]);
+        let hello_path = hello.output_path.clone().unwrap();
+        // The last input is not part of the extraction and should be dropped
+        let app = description(
+            "app",
+            "1.0",
+            &[&zlib_path, &hello_path, "/nix/store/missing"],
+        );
+
+        assert_eq!(
+            to_tree(&[glibc, zlib, hello, app]),
+            "app 1.0
+├── hello 2.12.1
+│   └── glibc 2.38
+└── zlib 1.3
+    └── glibc 2.38
+"
+        );
+
+        // Shared dependencies with inputs of their own are only expanded once
+        let curl = description("curl", "8.5", &[&zlib_path]);
+        let tree = to_tree(&[
+            description("zlib", "1.3", &[&glibc_path]),
+            description("glibc", "2.38", &[]),
+            description("app", "1.0", &[&zlib_path]),
+            curl,
+        ]);
+        assert_eq!(
+            tree,
+            "app 1.0
+└── zlib 1.3
+    └── glibc 2.38
+curl 8.5
+└── zlib 1.3 (*)
+"
+        );
+    }
+}
@@ -0,0 +1,185 @@
+//! Collect the extracted derivations into a [`petgraph`] graph, enabled with the `petgraph`
+//! feature.
+
+use std::collections::HashMap;
+
+use petgraph::graph::{DiGraph, NodeIndex};
+
+use crate::{BuildInputType, DerivationDescription, FlakeOutput, NixpkgsMetadata};
+
+/// Stand-in for a build input that was not described, e.g. because it was excluded or failed.
+/// Only its attribute path, output path and name are known, its `system` is empty.
+fn placeholder(attribute_path: String, output_path: Option<String>) -> DerivationDescription {
+    // Store paths are `<hash>-<name>`
+    let name = output_path
+        .as_deref()
+        .and_then(|output_path| output_path.rsplit('/').next())
+        .and_then(|base_name| base_name.split_once('-'))
+        .map(|(_, name)| name.to_owned())
+        .unwrap_or_default();
+    let parsed_name = crate::parse_drv_name(&name);
+
+    DerivationDescription {
+        schema_version: crate::SCHEMA_VERSION.to_owned(),
+        attribute_path,
+        system: String::new(),
+        flake_output: FlakeOutput::default(),
+        output_type: String::new(),
+        derivation_path: None,
+        output_path,
+        outputs: Vec::new(),
+        outputs_to_install: Vec::new(),
+        purl: String::new(),
+        nixpkgs_metadata: NixpkgsMetadata {
+            description: String::new(),
+            pname: parsed_name.name.clone(),
+            version: parsed_name.version.clone(),
+            broken: false,
+            unfree: false,
+            insecure: false,
+            known_vulnerabilities: Vec::new(),
+            homepage: String::new(),
+            licenses: None,
+            maintainers: Vec::new(),
+        },
+        name,
+        parsed_name,
+        meta_position: None,
+        sources: Vec::new(),
+        platforms: Vec::new(),
+        build_inputs: Vec::new(),
+        eval_warnings: Vec::new(),
+        nar_info: None,
+        self_size: None,
+        closure_size: None,
+        drv: None,
+    }
+}
+
+/// Collect descriptions into a graph with an edge from every derivation to each of its build
+/// inputs, weighted with the type of the input.
+///
+/// Nodes are keyed on output paths: a description of an output path that is already in the graph
+/// is ignored, and build inputs are linked to the description of their output path whenever it
+/// arrives. Build inputs that are never described become placeholder nodes, with an empty
+/// `system`.
+///
+/// ```no_run
+/// let descriptions = nixtract::nixtract_builder()
+///     .attribute_path("hello")
+///     .run()?;
+/// let graph = nixtract::into_graph(descriptions);
+/// println!("{} derivations", graph.node_count());
+/// # Ok::<(), nixtract::error::Error>(())
+/// ```
+pub fn into_graph(
+    descriptions: impl Iterator<Item = DerivationDescription>,
+) -> DiGraph<DerivationDescription, BuildInputType> {
+    let mut graph = DiGraph::new();
+    let mut nodes: HashMap<String, NodeIndex> = HashMap::new();
+    // Build inputs whose description has not arrived yet, by output path
+    let mut pending: HashMap<String, Vec<(NodeIndex, BuildInputType, String)>> = HashMap::new();
+
+    for mut description in descriptions {
+        let output_paths: Vec<String> = description
+            .output_path
+            .iter()
+            .cloned()
+            .chain(
+                description
+                    .outputs
+                    .iter()
+                    .filter_map(|output| output.output_path.clone()),
+            )
+            .collect();
+        if output_paths.iter().any(|path| nodes.contains_key(path)) {
+            continue;
+        }
+
+        let build_inputs = std::mem::take(&mut description.build_inputs);
+        let node = graph.add_node(description);
+        for output_path in output_paths {
+            for (dependent, build_input_type, _) in pending.remove(&output_path).unwrap_or_default()
+            {
+                graph.add_edge(dependent, node, build_input_type);
+            }
+            nodes.insert(output_path, node);
+        }
+
+        for build_input in &build_inputs {
+            match &build_input.output_path {
+                Some(output_path) => match nodes.get(output_path) {
+                    Some(&input) => {
+                        graph.add_edge(node, input, build_input.build_input_type.clone());
+                    }
+                    None => pending.entry(output_path.clone()).or_default().push((
+                        node,
+                        build_input.build_input_type.clone(),
+                        build_input.attribute_path.clone(),
+                    )),
+                },
+                // Without an output path there is nothing to match it with
+                None => {
+                    let input =
+                        graph.add_node(placeholder(build_input.attribute_path.clone(), None));
+                    graph.add_edge(node, input, build_input.build_input_type.clone());
+                }
+            }
+        }
+        graph[node].build_inputs = build_inputs;
+    }
+
+    for (output_path, dependents) in pending {
+        let attribute_path = dependents[0].2.clone();
+        let input = graph.add_node(placeholder(attribute_path, Some(output_path)));
+        for (dependent, build_input_type, _) in dependents {
+            graph.add_edge(dependent, input, build_input_type);
+        }
+    }
+
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    #[test]
+    fn test_into_graph() {
+        let hello = description("hello", "2.12.1", &[]);
+        let hello_path = hello.output_path.clone().unwrap();
+        let app = description(
+            "app",
+            "1.0",
+            &[
+                &hello_path,
+                "/nix/store/00000000000000000000000000000000-zlib-1.3",
+            ],
+        );
+
+        // hello arrives after app depends on it, and again through another attribute path
+        let graph = into_graph(vec![app, hello.clone(), hello].into_iter());
+
+        assert_eq!(graph.node_count(), 3);
+        assert_eq!(graph.edge_count(), 2);
+        let app = graph
+            .node_indices()
+            .find(|&node| graph[node].attribute_path == "app")
+            .unwrap();
+        let mut inputs: Vec<_> = graph
+            .neighbors(app)
+            .map(|node| (graph[node].name.clone(), graph[node].system.clone()))
+            .collect();
+        inputs.sort();
+        assert_eq!(
+            inputs,
+            vec![
+                ("hello-2.12.1".to_owned(), "x86_64-linux".to_owned()),
+                ("zlib-1.3".to_owned(), String::new()),
+            ]
+        );
+        // Build inputs are kept on the nodes
+        assert_eq!(graph[app].build_inputs.len(), 2);
+    }
+}
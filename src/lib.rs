@@ -23,6 +23,19 @@
 //!     Ok(())
 //! }
 //! ```
+//! The same can be achieved with the builder returned by `nixtract_builder`:
+//! ```no_run
+//! let derivations = nixtract::nixtract_builder()
+//!     .flake_ref("nixpkgs")
+//!     .system("x86_64-linux")
+//!     .attribute_path("haskellPackages.hello")
+//!     .run()?;
+//! # Ok::<(), nixtract::error::Error>(())
+//! ```
+//! To describe a single derivation without exploring its dependencies, use `describe_one`.
+//! With the `async` feature enabled, `nixtract_stream` and `NixtractBuilder::run_stream` return a
+//! `futures::Stream` instead, for use from async code. Its items are results, a failed run ends
+//! it with its error.
 //! ## Command Line
 //! nixtract can also be used as a command line tool. For example:
 //! ```sh
@@ -31,6 +44,7 @@
 
 use ::std::sync::{Arc, Mutex};
 use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 
 use error::Result;
@@ -38,25 +52,144 @@ use error::Result;
 mod nix;
 pub use nix::*;
 
+pub mod diff;
 pub mod error;
+pub mod format;
+pub mod license;
 pub mod message;
+pub mod stats;
+pub mod validate;
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::*;
+
+#[cfg(feature = "petgraph")]
+mod graph;
+#[cfg(feature = "petgraph")]
+pub use graph::*;
+
+/// Output paths that were already processed, with the shallowest depth they were reached at
+pub type CollectedPaths = Arc<Mutex<std::collections::HashMap<String, usize>>>;
+
+/// What to do with an output path that was reached, see [`visit`]
+#[derive(Debug, PartialEq, Eq)]
+enum Visit {
+    /// It was not reached before
+    New,
+    /// It was already sent, but reached shallower than before, so more of its dependencies are
+    /// within `max_depth`
+    Shallower,
+    /// It was already processed at this depth or shallower
+    Done,
+}
+
+/// Record that `output_path` was reached at `depth`. Without a maximum depth, every output path
+/// is only processed once.
+fn visit(
+    collected_paths: &CollectedPaths,
+    output_path: &str,
+    depth: usize,
+    max_depth: Option<usize>,
+) -> Visit {
+    let mut collected_paths = collected_paths.lock().unwrap();
+    match collected_paths.get_mut(output_path) {
+        None => {
+            collected_paths.insert(output_path.to_owned(), depth);
+            Visit::New
+        }
+        Some(seen_depth) if max_depth.is_some() && depth < *seen_depth => {
+            *seen_depth = depth;
+            Visit::Shallower
+        }
+        Some(_) => Visit::Done,
+    }
+}
+
+/// Descriptions made ahead of their derivation being processed, e.g. by a batch, keyed by the
+/// output path they were made for. Each is removed once it is used, so only the ones still
+/// waiting are kept in memory.
+pub type DescriptionCache = Arc<Mutex<std::collections::HashMap<String, DerivationDescription>>>;
 
 #[derive(Debug, Clone)]
 pub struct ProcessingArgs<'a> {
-    pub collected_paths: &'a Arc<Mutex<std::collections::HashSet<String>>>,
+    /// Output paths that were already processed, with the shallowest depth they were reached at
+    pub collected_paths: &'a CollectedPaths,
+    /// Output paths written by a previous run, they are not sent again but their dependencies
+    /// are still explored
+    pub completed_paths: &'a std::collections::HashSet<String>,
+    /// Used to avoid calling nix again for an output path that was described ahead of time
+    pub described: &'a DescriptionCache,
     pub flake_ref: &'a String,
     pub system: &'a Option<String>,
     pub attribute_path: String,
+    /// The flake output category `attribute_path` is relative to
+    pub flake_output: FlakeOutput,
+    /// The output path of the derivation, if it is known before describing it
+    pub output_path: Option<String>,
+    /// Distance from the top-level derivations, which have depth 0
+    pub depth: usize,
+    /// The derivation was already sent when it was reached deeper, it is only processed again to
+    /// explore the dependencies `max_depth` cut off then
+    pub already_sent: bool,
+    /// Derivation paths, or names and systems, of the derivations this one was reached through,
+    /// to detect cycles
+    pub ancestors: Vec<String>,
+    /// Stop exploring dependencies of derivations at this depth
+    pub max_depth: Option<usize>,
+    /// Derivations whose attribute path matches any of these patterns are not described
+    pub exclude: &'a regex::RegexSet,
+    /// Describe derivations from their `.drv` file, `attribute_path` being the derivation path,
+    /// instead of evaluating the flake
+    pub from_store: bool,
+    /// Derivations it does not match are not sent, their dependencies are still explored
+    pub only: MetaFilter,
+    /// Derivations whose licenses it does not allow are dropped or flagged
+    pub license_policy: Option<&'a license::LicensePolicy>,
+    /// Corrects the SPDX ids of the licenses before the policy sees them
+    pub license_map: Option<&'a license::LicenseMap>,
+    /// The dependencies of derivations it rejects are not explored
+    pub descend_filter: Option<&'a DescendFilter>,
     pub offline: bool,
+    /// Run this nix executable instead of the one on the PATH
+    pub nix_binary: Option<&'a std::path::Path>,
+    /// Extra arguments passed to every `nix eval` as is
+    pub nix_args: &'a [String],
+    pub nixpkgs_allow: NixpkgsAllow,
     pub include_nar_info: bool,
+    pub include_drv: bool,
+    pub include_meta_position: bool,
+    pub include_store_size: bool,
     pub runtime_only: bool,
+    pub runtime_source: RuntimeSource,
+    pub eval_timeout: Option<std::time::Duration>,
+    /// Limit the memory of every nix evaluation to this many bytes, on Linux
+    pub max_eval_memory: Option<u64>,
     pub binary_caches: &'a Vec<String>,
+    /// Shared between all narinfo fetches so connections to the caches are reused
+    pub http_client: &'a reqwest::blocking::Client,
+    pub narinfo_fetch: &'a narinfo::FetchConfig,
+    /// Narinfo is fetched on this pool after describing, instead of by the worker itself
+    pub narinfo_pool: Option<&'a rayon::ThreadPool>,
     pub lib: &'a nix::lib::Lib,
     pub tx: mpsc::Sender<DerivationDescription>,
     /// Used by the worker threads to communicate their status back to the main thread.
     /// This can for instance be used to update the UI.
     /// main.rs uses this channel to update the indicatif status bard.
     pub message_tx: Option<mpsc::Sender<message::Message>>,
+    /// Do not send [`message::Status::Skipped`] for derivations that were already described
+    pub quiet_skipped: bool,
+    /// Skip build inputs without an output path instead of describing them
+    pub skip_null_output_paths: bool,
+    /// Describe the dependencies of a derivation with one nix evaluation per this many of them
+    pub describe_batch: Option<usize>,
+    /// Counts of what happened to the derivations, if the caller asked for them
+    pub stats: &'a Option<stats::SharedStats>,
+    /// Fail the whole run on errors that are otherwise only logged, see [`NixtractConfig::strict`]
+    pub strict: bool,
+    /// Cancelled once the run is, no new derivations are processed after that
+    pub(crate) failure: &'a Arc<Failure>,
 }
 
 fn send_message(
@@ -70,7 +203,200 @@ fn send_message(
     }
 }
 
+fn record_stats(stats: &Option<stats::SharedStats>, record: impl FnOnce(&mut stats::Stats)) {
+    if let Some(stats) = stats {
+        record(&mut stats.lock().unwrap());
+    }
+}
+
+/// Check whether the attribute path is excluded, informing the calling thread if it is
+fn is_excluded(args: &ProcessingArgs, attribute_path: &str) -> Result<bool> {
+    if !args.exclude.is_match(attribute_path) {
+        return Ok(false);
+    }
+
+    log::debug!("Excluding derivation: {}", attribute_path);
+    record_stats(args.stats, |stats| stats.excluded += 1);
+    send_message(
+        &args.message_tx,
+        message::Message {
+            status: message::Status::Excluded,
+            id: rayon::current_thread_index().unwrap(),
+            path: attribute_path.to_owned(),
+        },
+    )?;
+
+    Ok(true)
+}
+
+/// Check the licenses of the description against the license policy, returns whether it should be
+/// sent to the caller
+fn is_license_allowed(args: &ProcessingArgs, description: &DerivationDescription) -> bool {
+    let Some(license_policy) = args.license_policy else {
+        return true;
+    };
+    if license_policy.allows(description.nixpkgs_metadata.licenses.as_deref()) {
+        return true;
+    }
+
+    record_stats(args.stats, |stats| stats.license_violations += 1);
+    match license_policy.action {
+        license::LicenseAction::Drop => {
+            log::debug!(
+                "Dropping derivation with a license that is not allowed: {}",
+                description.attribute_path
+            );
+            false
+        }
+        license::LicenseAction::Flag => {
+            log::warn!(
+                "Derivation with a license that is not allowed: {}",
+                description.attribute_path
+            );
+            true
+        }
+    }
+}
+
+/// Fetch the narinfo of the description on the narinfo pool, and send the description once it is
+/// fetched. The worker continues with the dependencies in the meantime.
+fn send_with_narinfo(
+    args: &ProcessingArgs,
+    narinfo_pool: &rayon::ThreadPool,
+    mut description: DerivationDescription,
+) {
+    let http_client = args.http_client.clone();
+    let narinfo_fetch = args.narinfo_fetch.clone();
+    let binary_caches = args.binary_caches.clone();
+    let tx = args.tx.clone();
+    let stats = args.stats.clone();
+    let strict = args.strict;
+    let failure = Arc::clone(args.failure);
+
+    narinfo_pool.spawn(move || {
+        if let Err(e) = description.fetch_nar_info(&http_client, &narinfo_fetch, &binary_caches) {
+            // The description would be incomplete
+            if strict {
+                failure.fail(e);
+                return;
+            }
+            log::warn!(
+                "Error fetching narinfo for {}: {}",
+                description.attribute_path,
+                e
+            );
+        }
+        record_stats(&stats, |stats| stats.record_nar_info(&description));
+
+        // The receiver only hangs up when the caller is no longer interested
+        let _ = tx.send(description);
+    });
+}
+
+/// Describe the derivation, using the description made ahead of time for its output path if
+/// there is one
+fn describe_derivation_cached(args: &ProcessingArgs) -> Result<DerivationDescription> {
+    // Every output path is processed once, the description is not needed anymore afterwards
+    let cached = args.output_path.as_ref().and_then(|output_path| {
+        let mut described = args.described.lock().unwrap();
+        described.remove(output_path)
+    });
+
+    if let Some(mut description) = cached {
+        log::debug!(
+            "Reusing the description of {} for {}",
+            description.attribute_path,
+            args.attribute_path
+        );
+        // The same output path can be reached through different attribute paths
+        description.attribute_path = args.attribute_path.clone();
+        description.purl = nix::purl(
+            args.flake_ref,
+            &description.attribute_path,
+            &description.parsed_name,
+        );
+        return Ok(description);
+    }
+
+    let description = if args.from_store {
+        nix::describe_store_derivation(&nix::DescribeDerivationArgs::from(args.clone()))?
+    } else {
+        nix::describe_derivation(&nix::DescribeDerivationArgs::from(args.clone()))?
+    };
+    // With a narinfo pool, the narinfo is fetched and counted once the description is sent
+    if args.include_nar_info && args.narinfo_pool.is_none() {
+        record_stats(args.stats, |stats| stats.record_nar_info(&description));
+    }
+
+    Ok(description)
+}
+
+/// Describe the build inputs that were not described yet in batches of `batch_size`, with one nix
+/// evaluation per batch, and keep the descriptions in the cache for `process` to pick up. A batch
+/// that fails is left to `process`, which describes its derivations one by one and reports the
+/// ones that fail.
+fn describe_batches<'b>(
+    args: &ProcessingArgs,
+    build_inputs: impl Iterator<Item = &'b BuiltInput>,
+    batch_size: usize,
+) {
+    let uncached: Vec<&BuiltInput> = {
+        let described = args.described.lock().unwrap();
+        build_inputs
+            .filter(|build_input| {
+                build_input
+                    .output_path
+                    .as_ref()
+                    .is_some_and(|output_path| !described.contains_key(output_path))
+            })
+            .collect()
+    };
+
+    uncached.par_chunks(batch_size).for_each(|batch| {
+        // A batch of one costs as much as describing it on its own
+        if batch.len() < 2 || args.failure.cancelled.is_cancelled() {
+            return;
+        }
+
+        let attribute_paths: Vec<String> = batch
+            .iter()
+            .map(|build_input| build_input.attribute_path.clone())
+            .collect();
+        let describe_args = nix::DescribeDerivationArgs::from(args.clone());
+        let descriptions = match nix::describe_derivations(&describe_args, &attribute_paths) {
+            Ok(descriptions) => descriptions,
+            Err(e) => {
+                log::debug!(
+                    "Describing {} derivations at once failed, describing them one by one: {}",
+                    batch.len(),
+                    e
+                );
+                return;
+            }
+        };
+
+        for (build_input, description) in batch.iter().zip(descriptions) {
+            // With a narinfo pool, the narinfo is fetched and counted once the description is sent
+            if args.include_nar_info && args.narinfo_pool.is_none() {
+                record_stats(args.stats, |stats| stats.record_nar_info(&description));
+            }
+
+            // Looked up by the output path the build input is processed with
+            if let Some(output_path) = &build_input.output_path {
+                args.described
+                    .lock()
+                    .unwrap()
+                    .insert(output_path.clone(), description);
+            }
+        }
+    });
+}
+
 fn process(args: ProcessingArgs) -> Result<()> {
+    if args.failure.cancelled.is_cancelled() {
+        return Ok(());
+    }
+
     log::debug!("Processing derivation: {:?}", args.attribute_path);
 
     // Inform the calling thread that we are starting to process the derivation
@@ -83,47 +409,202 @@ fn process(args: ProcessingArgs) -> Result<()> {
         },
     )?;
 
-    let description = nix::describe_derivation(&nix::DescribeDerivationArgs::from(args.clone()))?;
+    let description = match describe_derivation_cached(&args) {
+        Ok(description) => description,
+        Err(e) => {
+            record_stats(args.stats, |stats| stats.failed += 1);
+            // Inform the calling thread that this derivation will be missing from the output
+            send_message(
+                &args.message_tx,
+                message::Message {
+                    status: message::Status::Failed {
+                        error: e.to_string(),
+                    },
+                    id: rayon::current_thread_index().unwrap(),
+                    path: args.attribute_path.clone(),
+                },
+            )?;
+            return Err(e);
+        }
+    };
+
+    // Attribute paths grow with every level, so a cycle is only recognizable by the derivation,
+    // or by its name and system when it has no paths, at the cost of stopping at a derivation
+    // that only shares them with one of its ancestors
+    let key = description
+        .derivation_path
+        .clone()
+        .or_else(|| description.output_path.clone())
+        .unwrap_or_else(|| format!("{}-{}", description.name, description.system));
+    if args.ancestors.contains(&key) {
+        log::warn!(
+            "Not descending into dependency cycle through {}",
+            description.attribute_path
+        );
+        send_message(
+            &args.message_tx,
+            message::Message {
+                status: message::Status::Cycle,
+                id: rayon::current_thread_index().unwrap(),
+                path: description.attribute_path.clone(),
+            },
+        )?;
+        return Ok(());
+    }
 
     // Abort if we have reached to bootstrap stage
     if description.name == "bootstrap-tools" || description.name.starts_with("bootstrap-stage") {
+        send_message(
+            &args.message_tx,
+            message::Message {
+                status: message::Status::Skipped,
+                id: rayon::current_thread_index().unwrap(),
+                path: description.attribute_path.clone(),
+            },
+        )?;
         return Ok(());
     }
 
+    // Derivations that are not audited are not checked against the license policy either
+    let dropped = !args.only.matches(&description.nixpkgs_metadata)
+        || !is_license_allowed(&args, &description);
+
+    let completed = args.already_sent
+        || description
+            .output_path
+            .as_ref()
+            .is_some_and(|output_path| args.completed_paths.contains(output_path));
+
     // Inform the calling thread that we have described the derivation
     send_message(
         &args.message_tx,
         message::Message {
-            status: message::Status::Completed,
+            status: if completed {
+                message::Status::Skipped
+            } else if dropped {
+                message::Status::Excluded
+            } else {
+                message::Status::Completed
+            },
             id: rayon::current_thread_index().unwrap(),
             path: description.attribute_path.clone(),
         },
     )?;
 
-    // Send the DerivationDescription to the main thread
-    args.tx.send(description.clone())?;
+    // Derivations seeded without an output path are only known once described, remember them
+    // so they are not described again when they show up as a dependency
+    if let (None, Some(output_path)) = (&args.output_path, &description.output_path) {
+        visit(
+            args.collected_paths,
+            output_path,
+            args.depth,
+            args.max_depth,
+        );
+    }
 
-    // use par_iter to call process on all children of this derivation
-    description
+    // Send the DerivationDescription to the main thread, dropped ones still have their
+    // dependencies explored as those have licenses of their own. So do completed ones, the
+    // previous run may have stopped before describing all of them, and the ones already sent.
+    if completed {
+        record_stats(args.stats, |stats| stats.skipped += 1);
+    } else if !dropped {
+        match args.narinfo_pool {
+            Some(narinfo_pool) if args.include_nar_info => {
+                send_with_narinfo(&args, narinfo_pool, description.clone())
+            }
+            _ => args.tx.send(description.clone())?,
+        }
+        record_stats(args.stats, |stats| stats.described += 1);
+    }
+
+    // Do not explore the dependencies if we reached the maximum depth
+    if args
+        .max_depth
+        .is_some_and(|max_depth| args.depth >= max_depth)
+    {
+        send_message(
+            &args.message_tx,
+            message::Message {
+                status: message::Status::MaxDepthReached,
+                id: rayon::current_thread_index().unwrap(),
+                path: description.attribute_path.clone(),
+            },
+        )?;
+
+        return Ok(());
+    }
+
+    if let Some(descend_filter) = args.descend_filter {
+        if !descend_filter.descends(&description) {
+            log::debug!(
+                "Not exploring the dependencies of {}, rejected by the filter",
+                description.attribute_path
+            );
+            return Ok(());
+        }
+    }
+
+    let mut ancestors = args.ancestors.clone();
+    ancestors.push(key);
+
+    // Leave out the children that are excluded or were already processed
+    let build_inputs = description.build_inputs.len();
+    let quietly_skipped = std::sync::atomic::AtomicUsize::new(0);
+    let pending: Vec<(BuiltInput, bool)> = description
         .build_inputs
         .into_par_iter()
-        .map(|build_input| -> Result<()> {
+        .map(|build_input| -> Result<Option<(BuiltInput, bool)>> {
+            if is_excluded(&args, &build_input.attribute_path)? {
+                return Ok(None);
+            }
+
+            // They cannot be deduplicated, so they would be described again by every dependent
+            if build_input.output_path.is_none() && args.skip_null_output_paths {
+                record_stats(args.stats, |stats| stats.skipped += 1);
+                if args.quiet_skipped {
+                    quietly_skipped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(None);
+                }
+
+                log::debug!(
+                    "Skipping derivation without an output_path: {}",
+                    build_input.attribute_path
+                );
+                send_message(
+                    &args.message_tx,
+                    message::Message {
+                        status: message::Status::Skipped,
+                        id: rayon::current_thread_index().unwrap(),
+                        path: build_input.attribute_path.clone(),
+                    },
+                )?;
+                return Ok(None);
+            }
+
             // check if the build_input has already be processed
-            let done = {
-                let mut collected_paths = args.collected_paths.lock().unwrap();
-                match &build_input.output_path {
-                    None => {
-                        log::warn!(
-                            "Found a derivation without an output_path: {:?}",
-                            build_input
-                        );
-                        false
-                    }
-                    Some(output_path) => !collected_paths.insert(output_path.clone()),
+            let visited = match &build_input.output_path {
+                None => {
+                    log::warn!(
+                        "Found a derivation without an output_path: {:?}",
+                        build_input
+                    );
+                    Visit::New
                 }
+                Some(output_path) => visit(
+                    args.collected_paths,
+                    output_path,
+                    args.depth + 1,
+                    args.max_depth,
+                ),
             };
 
-            if done {
+            if visited == Visit::Done {
+                record_stats(args.stats, |stats| stats.skipped += 1);
+                if args.quiet_skipped {
+                    quietly_skipped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(None);
+                }
+
                 log::debug!(
                     "Skipping already processed derivation: {}",
                     build_input.attribute_path.to_string()
@@ -140,12 +621,47 @@ fn process(args: ProcessingArgs) -> Result<()> {
                     },
                 )?;
 
-                return Ok(());
+                return Ok(None);
             }
 
+            Ok(Some((build_input, visited == Visit::Shallower)))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Only the children that get a status of their own are queued, the others would keep the
+    // progress from reaching its total
+    send_message(
+        &args.message_tx,
+        message::Message {
+            status: message::Status::Queued {
+                count: build_inputs - quietly_skipped.into_inner(),
+            },
+            id: rayon::current_thread_index().unwrap(),
+            path: description.attribute_path.clone(),
+        },
+    )?;
+
+    if let Some(batch_size) = args.describe_batch.filter(|&batch_size| batch_size > 1) {
+        if !args.from_store {
+            let build_inputs = pending.iter().map(|(build_input, _)| build_input);
+            describe_batches(&args, build_inputs, batch_size);
+        }
+    }
+
+    // use par_iter to call process on all children of this derivation
+    pending
+        .into_par_iter()
+        .map(|(build_input, already_sent)| -> Result<()> {
             // Call process with the build_input
             process(ProcessingArgs {
                 attribute_path: build_input.attribute_path,
+                output_path: build_input.output_path,
+                depth: args.depth + 1,
+                already_sent,
+                ancestors: ancestors.clone(),
                 tx: args.tx.clone(),
                 message_tx: args.message_tx.clone(),
                 ..args
@@ -156,153 +672,2095 @@ fn process(args: ProcessingArgs) -> Result<()> {
     Ok(())
 }
 
+/// The systems nixpkgs builds binary caches for, see `--target-system all`
+pub const SUPPORTED_SYSTEMS: [&str; 4] = [
+    "x86_64-linux",
+    "aarch64-linux",
+    "x86_64-darwin",
+    "aarch64-darwin",
+];
+
+/// Systems nix and nixpkgs know about, a target system outside of these is most likely a typo
+pub const KNOWN_SYSTEMS: [&str; 22] = [
+    "x86_64-linux",
+    "aarch64-linux",
+    "i686-linux",
+    "armv6l-linux",
+    "armv7l-linux",
+    "powerpc64le-linux",
+    "riscv64-linux",
+    "s390x-linux",
+    "loongarch64-linux",
+    "mips64el-linux",
+    "x86_64-darwin",
+    "aarch64-darwin",
+    "x86_64-freebsd",
+    "aarch64-freebsd",
+    "i686-freebsd",
+    "x86_64-netbsd",
+    "x86_64-openbsd",
+    "x86_64-windows",
+    "i686-windows",
+    "x86_64-cygwin",
+    "wasm32-wasi",
+    "wasm64-wasi",
+];
+
+/// Fail with [`error::Error::InvalidSystem`] on a target system that is neither known nor the
+/// system of this machine, before nix fails on it with a less helpful error
+fn validate_systems(systems: &[String], nix_binary: Option<&std::path::Path>) -> Result<()> {
+    let mut current_system = None;
+    for system in systems {
+        if KNOWN_SYSTEMS.contains(&system.as_str()) {
+            continue;
+        }
+        // The machine itself may run on a system missing from the list
+        let current_system =
+            current_system.get_or_insert_with(|| nix::command::current_system(nix_binary).ok());
+        if current_system.as_deref() != Some(system.as_str()) {
+            return Err(error::Error::InvalidSystem(system.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// State kept separately for every extracted system: the same attribute path has different
+/// output paths on each system, so deduplication and caching happen per system
+struct SystemState {
+    system: Option<String>,
+    collected_paths: CollectedPaths,
+    described: DescriptionCache,
+}
+
+impl SystemState {
+    fn new(system: Option<String>) -> Self {
+        SystemState {
+            system,
+            collected_paths: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            described: Arc::new(Mutex::new(std::collections::HashMap::new())),
+        }
+    }
+}
+
+/// Only emit the derivations whose nixpkgs metadata marks them as broken, unfree or insecure, to
+/// audit a flake for them. The dependencies of the other derivations are still explored.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MetaFilter {
+    pub broken: bool,
+    pub unfree: bool,
+    pub insecure: bool,
+}
+
+impl MetaFilter {
+    /// Whether a derivation with this metadata is emitted: it has any of the requested flags, or
+    /// none is requested
+    pub fn matches(&self, metadata: &NixpkgsMetadata) -> bool {
+        if !(self.broken || self.unfree || self.insecure) {
+            return true;
+        }
+        (self.broken && metadata.broken)
+            || (self.unfree && metadata.unfree)
+            || (self.insecure && metadata.insecure)
+    }
+}
+
+/// Decides whether the dependencies of a described derivation are explored, see
+/// [`NixtractBuilder::filter`]
+#[derive(Clone)]
+pub struct DescendFilter(Arc<dyn Fn(&DerivationDescription) -> bool + Send + Sync>);
+
+impl DescendFilter {
+    pub fn new(filter: impl Fn(&DerivationDescription) -> bool + Send + Sync + 'static) -> Self {
+        DescendFilter(Arc::new(filter))
+    }
+
+    /// Whether the dependencies of `description` should be explored
+    pub fn descends(&self, description: &DerivationDescription) -> bool {
+        (self.0)(description)
+    }
+}
+
+impl std::fmt::Debug for DescendFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DescendFilter")
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct NixtractConfig {
     pub offline: bool,
+    /// Resolve the flake ref to its locked URL with `nix flake metadata` before extracting, and
+    /// evaluate everything against it. Without this, a ref such as `nixpkgs` that moves during a
+    /// long run can be evaluated at different revisions by the discovery and by the workers.
+    pub lock_flake_ref: bool,
+    /// Run this nix executable, e.g. a wrapper or another implementation such as Lix, instead of
+    /// the `nix` on the PATH. `nix-store` is run from the same directory. The narinfo of the
+    /// local store is read with [`narinfo::FetchConfig::nix_binary`].
+    pub nix_binary: Option<std::path::PathBuf>,
+    /// Extra arguments passed to every `nix eval` as is, e.g. `--override-input`. They are not
+    /// validated and can change what nix evaluates or where it fetches from, only pass trusted
+    /// values.
+    pub nix_args: Vec<String>,
+    /// Which unfree, insecure and broken packages nixpkgs evaluates, all of them by default
+    pub nixpkgs_allow: NixpkgsAllow,
     pub include_nar_info: bool,
+    /// Attach the contents of the `.drv` file to every description, costs an extra nix call each
+    pub include_drv: bool,
+    /// Record the `file:line` every derivation is defined at, from `meta.position`
+    pub include_meta_position: bool,
+    /// Record the size realised output paths and their closures take in the local store, costs
+    /// an extra nix call for each of them
+    pub include_store_size: bool,
     pub runtime_only: bool,
+    /// Flake output categories to discover derivations in, only `packages` if empty
+    pub flake_outputs: Vec<FlakeOutput>,
+    /// Stop the whole run at the first derivation that cannot be described, the error is
+    /// available from [`Descriptions::error`]. By default such derivations are skipped.
+    pub fail_fast: bool,
+    /// Describe the requested attribute paths directly instead of discovering the derivations
+    /// below them, each attribute path must point to a derivation
+    pub skip_discovery: bool,
+    /// Where runtime dependencies are taken from when `runtime_only` is set
+    pub runtime_source: RuntimeSource,
+    /// Maximum depth of dependencies to explore, 0 only describes the top-level derivations. The
+    /// depth of a derivation is the shortest path to it, a derivation reached at the maximum
+    /// depth first is explored again once it is reached shallower.
+    pub max_depth: Option<usize>,
+    /// Regular expressions matched against attribute paths, matching derivations are not described
+    pub exclude: Vec<String>,
+    /// Output paths described by a previous, interrupted run. They are evaluated again to explore
+    /// their dependencies, but not described again.
+    pub completed_paths: Vec<String>,
+    /// Start from the derivations that produced these store paths instead of the attribute paths
+    /// of the flake. They and their dependencies are described from their `.drv` files, so
+    /// without the `meta` of the derivations.
+    pub store_paths: Vec<String>,
+    /// Only emit broken, unfree or insecure derivations, all of them by default
+    pub only: MetaFilter,
+    /// Drop or flag derivations whose licenses are not allowed, all licenses are allowed if `None`
+    pub license_policy: Option<license::LicensePolicy>,
+    /// SPDX ids overriding the ones nixpkgs gives licenses, applied to every description
+    pub license_map: Option<license::LicenseMap>,
+    /// Only explore the dependencies of the derivations it accepts, all of them if `None`. The
+    /// rejected derivations themselves are still in the output.
+    pub descend_filter: Option<DescendFilter>,
+    /// Kill the evaluation of a single derivation if it takes longer than this
+    pub eval_timeout: Option<std::time::Duration>,
+    /// Limit the address space of every `nix eval` to this many bytes, so a single runaway
+    /// evaluation fails with [`error::Error::EvalOutOfMemory`] instead of exhausting the memory of
+    /// the machine. Only supported on Linux, ignored elsewhere.
+    pub max_eval_memory: Option<u64>,
     pub binary_caches: Option<Vec<String>>,
+    /// Retry behaviour when fetching narinfo from the binary caches
+    pub narinfo_fetch: narinfo::FetchConfig,
+    /// Fetch narinfo on a separate pool of this many threads, so the workers evaluating
+    /// derivations do not wait on the network. By default the workers fetch it themselves.
+    pub narinfo_concurrency: Option<usize>,
+    /// Number of workers describing derivations, on a thread pool of the run itself so that
+    /// concurrent runs do not share one. Defaults to the number of CPUs.
+    pub n_workers: Option<usize>,
     pub message_tx: Option<mpsc::Sender<message::Message>>,
+    /// Do not log or send a [`message::Status::Skipped`] message for every dependency that was
+    /// already described, they are only counted in the stats. On large closures these are the
+    /// vast majority of messages.
+    pub quiet_skipped: bool,
+    /// Skip build inputs without an output path, with a [`message::Status::Skipped`], instead of
+    /// warning about them and describing them. They cannot be told apart from one another, so
+    /// they are described again for every derivation depending on them.
+    pub skip_null_output_paths: bool,
+    /// Describe the dependencies of every derivation in batches of this many, with a single nix
+    /// evaluation per batch instead of one per dependency, sparing the cost of starting nix and
+    /// evaluating the flake for each of them. `None`, or a size below 2, describes them one by one.
+    pub describe_batch: Option<usize>,
+    /// Filled with counts of what happened during the run, read it once the iterator is exhausted
+    pub stats: Option<stats::SharedStats>,
+    /// Stops the run once cancelled, see [`CancellationToken`]
+    pub cancellation: Option<CancellationToken>,
+    /// Stop the whole run if it takes longer than this, including the discovery of the
+    /// derivations. No new derivations are described after it, the iterator ends like when the
+    /// run is cancelled and [`Descriptions::error`] is [`error::Error::TimeoutTotal`].
+    pub timeout_total: Option<std::time::Duration>,
+    /// Fail instead of skipping what is normally only warned about: derivations that cannot be
+    /// described like with `fail_fast`, narinfo that cannot be fetched, found derivations that
+    /// cannot be parsed, store paths without a deriver and narinfo with unknown keys, see
+    /// [`narinfo::FetchConfig::strict`]. A run then either describes everything or ends with
+    /// [`Descriptions::error`], even when some derivations were already described.
+    pub strict: bool,
 }
 
-pub fn nixtract(
-    flake_ref: impl Into<String>,
-    system: Option<impl Into<String>>,
-    attribute_path: Option<impl Into<String>>,
+/// Builder for a nixtract run, the ergonomic alternative to calling [`nixtract`] directly.
+///
+/// All options default to the same values as [`NixtractConfig::default`], and the flake
+/// reference defaults to `nixpkgs`.
+///
+/// ```no_run
+/// let derivations = nixtract::nixtract_builder()
+///     .flake_ref("nixpkgs")
+///     .system("x86_64-linux")
+///     .attribute_path("haskellPackages.hello")
+///     .runtime_only(true)
+///     .run()?;
+/// # Ok::<(), nixtract::error::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct NixtractBuilder {
+    flake_refs: Vec<String>,
+    systems: Vec<String>,
+    attribute_paths: Vec<String>,
     config: NixtractConfig,
-) -> Result<impl Iterator<Item = DerivationDescription>> {
-    // Convert the arguments to the expected types
-    let flake_ref = flake_ref.into();
-    let system = system.map(Into::into);
-    let attribute_path = attribute_path.map(Into::into);
+}
 
-    let binary_caches = match config.binary_caches {
-        None => nix::substituters::get_substituters(flake_ref.clone())?,
-        Some(caches) => caches,
-    };
+impl Default for NixtractBuilder {
+    fn default() -> Self {
+        NixtractBuilder {
+            flake_refs: vec!["nixpkgs".to_owned()],
+            systems: Vec::new(),
+            attribute_paths: Vec::new(),
+            config: NixtractConfig::default(),
+        }
+    }
+}
 
-    // Writes the `lib.nix` file to the tempdir and stores its path
-    let lib = nix::lib::Lib::new()?;
+/// Create a [`NixtractBuilder`] with the default configuration
+pub fn nixtract_builder() -> NixtractBuilder {
+    NixtractBuilder::default()
+}
 
-    // Create a channel to communicate DerivationDescription to the main thread
-    let (tx, rx) = mpsc::channel();
-
-    log::info!(
-        "Starting nixtract with flake_ref: {}, system: {}, attribute_path: {:?}",
-        flake_ref,
-        system
-            .clone()
-            .unwrap_or("builtins.currentSystem".to_owned()),
-        attribute_path.clone().unwrap_or_default()
-    );
-
-    let collected_paths: Arc<Mutex<std::collections::HashSet<String>>> =
-        Arc::new(Mutex::new(std::collections::HashSet::new()));
-
-    // call find_attribute_paths to get the initial set of derivations
-    let attribute_paths =
-        nix::find_attribute_paths(&flake_ref, &system, &attribute_path, &config.offline, &lib)?;
-
-    // Combine all AttributePaths into a single Vec
-    let mut derivations: Vec<FoundDrv> = Vec::new();
-    for attribute_path in attribute_paths {
-        derivations.extend(attribute_path.found_drvs);
-    }
-
-    for found_drv in derivations.clone() {
-        match found_drv.output_path {
-            None => log::warn!("Found a derivation without an output_path: {:?}", found_drv),
-            Some(output_path) => {
-                let mut collected_paths = collected_paths.lock().unwrap();
-                collected_paths.insert(output_path);
-            }
-        }
+impl NixtractBuilder {
+    /// The flake URI to extract, e.g. "github:tweag/nixtract". Replaces the flakes set before.
+    pub fn flake_ref(mut self, flake_ref: impl Into<String>) -> Self {
+        self.flake_refs = vec![flake_ref.into()];
+        self
     }
 
-    // Spawn a new rayon thread to call process on every foundDrv
-    rayon::spawn(move || {
-        derivations.into_par_iter().for_each(|found_drv| {
-            let processing_args = ProcessingArgs {
-                collected_paths: &collected_paths,
-                flake_ref: &flake_ref,
-                system: &system,
-                attribute_path: found_drv.attribute_path,
-                offline: config.offline,
-                runtime_only: config.runtime_only,
-                include_nar_info: config.include_nar_info,
-                binary_caches: &binary_caches,
-                lib: &lib,
-                tx: tx.clone(),
-                message_tx: config.message_tx.clone(),
-            };
-            match process(processing_args) {
-                Ok(_) => {}
-                Err(e) => log::warn!("Error processing derivation: {}", e),
-            }
-        });
-    });
+    /// Extract several flakes in a single run, e.g. an overlay and nixpkgs. Every derivation is
+    /// described in the flake it was found in, and derivations are deduplicated on their output
+    /// path across flakes, so the dependencies they share are only described once. Replaces the
+    /// flakes set before.
+    pub fn flake_refs(mut self, flake_refs: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.flake_refs = flake_refs.into_iter().map(Into::into).collect();
+        self
+    }
 
-    Ok(rx.into_iter())
-}
+    /// The system to extract, e.g. "x86_64-linux", defaults to the host system.
+    /// Can be called multiple times to extract several systems in a single run.
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.systems.push(system.into());
+        self
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use core::panic;
-    use std::fs;
+    /// Extract several systems in a single run, e.g. [`SUPPORTED_SYSTEMS`]
+    pub fn systems(mut self, systems: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.systems.extend(systems.into_iter().map(Into::into));
+        self
+    }
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+    /// An attribute path to extract, e.g. "haskellPackages.hello", defaults to all derivations in the flake.
+    /// Can be called multiple times to extract several attribute paths in a single run.
+    pub fn attribute_path(mut self, attribute_path: impl Into<String>) -> Self {
+        self.attribute_paths.push(attribute_path.into());
+        self
     }
 
-    #[test]
-    fn test_main_fixtures() -> Result<()> {
-        init();
+    /// Extract several attribute paths in a single run, dependencies shared between them are only described once
+    pub fn attribute_paths(
+        mut self,
+        attribute_paths: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.attribute_paths
+            .extend(attribute_paths.into_iter().map(Into::into));
+        self
+    }
 
-        // For every subdirectory in the tests/fixtures directory
-        for entry in fs::read_dir("tests/fixtures").unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path().canonicalize().unwrap();
-            if path.is_dir() {
-                let config = NixtractConfig {
-                    runtime_only: false,
-                    binary_caches: None,
-                    offline: false,
-                    include_nar_info: false,
-                    message_tx: None,
-                };
+    /// Run nix evaluation in offline mode, narinfo is then only read from local sources
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.config.narinfo_fetch.offline = offline;
+        self.config.offline = offline;
+        self
+    }
 
-                log::info!("Running test for {:?}", path);
+    /// Run this nix executable instead of the `nix` on the PATH, for every nix command
+    pub fn nix_binary(mut self, nix_binary: impl Into<std::path::PathBuf>) -> Self {
+        let nix_binary = nix_binary.into();
+        self.config.narinfo_fetch.nix_binary = Some(nix_binary.clone());
+        self.config.nix_binary = Some(nix_binary);
+        self
+    }
 
-                let test_name = path
-                    .components()
-                    .last()
-                    .unwrap()
-                    .as_os_str()
-                    .to_str()
-                    .unwrap();
-                let flake_ref = path.to_str().unwrap();
-                let system: Option<String> = None;
-                let attribute_path: Option<String> = None;
+    /// Evaluate everything against the revision the flake ref resolves to when the run starts
+    pub fn lock_flake_ref(mut self, lock_flake_ref: bool) -> Self {
+        self.config.lock_flake_ref = lock_flake_ref;
+        self
+    }
 
-                let mut descriptions = nixtract(flake_ref, system, attribute_path, config).unwrap();
+    /// Pass extra arguments to every `nix eval`, inserted before the expression. They are not
+    /// validated, only pass trusted values.
+    pub fn nix_args(mut self, nix_args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config
+            .nix_args
+            .extend(nix_args.into_iter().map(Into::into));
+        self
+    }
 
-                match test_name {
-                    "flake-direct-buildInput" => {}
-                    "flake-direct-nativeBuildInput" => {}
-                    "flake-three-levels" => {}
-                    "flake-trivial-rust" => {
-                        assert!(descriptions.any(|d| {
-                            d.src.is_some_and(|s| {
-                                s.git_repo_url == "https://github.com/hello-lang/Rust.git"
-                            })
-                        }));
-                    }
-                    "flake-trivial" => {}
-                    s => panic!("Unknown test: {}", s),
-                }
-            }
-        }
-        Ok(())
+    /// Let nixpkgs evaluate packages with a license that is not free, `true` by default
+    pub fn allow_unfree(mut self, allow_unfree: bool) -> Self {
+        self.config.nixpkgs_allow.unfree = allow_unfree;
+        self
+    }
+
+    /// Let nixpkgs evaluate packages with known vulnerabilities, `true` by default
+    pub fn allow_insecure(mut self, allow_insecure: bool) -> Self {
+        self.config.nixpkgs_allow.insecure = allow_insecure;
+        self
+    }
+
+    /// Let nixpkgs evaluate packages marked as broken, `true` by default
+    pub fn allow_broken(mut self, allow_broken: bool) -> Self {
+        self.config.nixpkgs_allow.broken = allow_broken;
+        self
+    }
+
+    /// Attempt to fetch nar info from the binary cache
+    pub fn include_nar_info(mut self, include_nar_info: bool) -> Self {
+        self.config.include_nar_info = include_nar_info;
+        self
+    }
+
+    /// Attach the builder, arguments, environment and input derivations from the `.drv` file
+    pub fn include_drv(mut self, include_drv: bool) -> Self {
+        self.config.include_drv = include_drv;
+        self
+    }
+
+    /// Record the `file:line` every derivation is defined at, from `meta.position`
+    pub fn include_meta_position(mut self, include_meta_position: bool) -> Self {
+        self.config.include_meta_position = include_meta_position;
+        self
+    }
+
+    /// Record the size realised output paths and their closures take in the local store
+    pub fn include_store_size(mut self, include_store_size: bool) -> Self {
+        self.config.include_store_size = include_store_size;
+        self
+    }
+
+    /// Only extract runtime dependencies
+    pub fn runtime_only(mut self, runtime_only: bool) -> Self {
+        self.config.runtime_only = runtime_only;
+        self
+    }
+
+    /// Also discover derivations in these flake output categories, e.g. checks and devShells.
+    /// Only `packages` are discovered if this is never called.
+    pub fn flake_outputs(mut self, flake_outputs: impl IntoIterator<Item = FlakeOutput>) -> Self {
+        self.config.flake_outputs.extend(flake_outputs);
+        self
+    }
+
+    /// Stop at the first derivation that cannot be described, see [`Descriptions::error`]
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.config.fail_fast = fail_fast;
+        self
+    }
+
+    /// Fail on everything that is normally only warned about, see [`NixtractConfig::strict`]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.narinfo_fetch.strict = strict;
+        self.config.strict = strict;
+        self
+    }
+
+    /// Describe the attribute paths directly, skipping the discovery of the derivations below them
+    pub fn skip_discovery(mut self, skip_discovery: bool) -> Self {
+        self.config.skip_discovery = skip_discovery;
+        self
+    }
+
+    /// Where runtime dependencies are taken from when only runtime dependencies are requested
+    pub fn runtime_source(mut self, runtime_source: RuntimeSource) -> Self {
+        self.config.runtime_source = runtime_source;
+        self
+    }
+
+    /// Stop exploring dependencies after this many levels, 0 only describes the top-level derivations
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.config.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Do not describe derivations whose attribute path matches this regular expression, can be called multiple times
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude.push(pattern.into());
+        self
+    }
+
+    /// Only count the dependencies that were already described, without logging or sending a
+    /// status message for each of them
+    pub fn quiet_skipped(mut self, quiet_skipped: bool) -> Self {
+        self.config.quiet_skipped = quiet_skipped;
+        self
+    }
+
+    /// Describe the dependencies of every derivation with one nix evaluation per `batch_size` of them
+    pub fn describe_batch(mut self, batch_size: usize) -> Self {
+        self.config.describe_batch = Some(batch_size);
+        self
+    }
+
+    /// Skip build inputs without an output path instead of warning about them and describing them
+    pub fn skip_null_output_paths(mut self, skip_null_output_paths: bool) -> Self {
+        self.config.skip_null_output_paths = skip_null_output_paths;
+        self
+    }
+
+    /// Start from the derivation that produced this store path, bypassing the discovery of
+    /// derivations in the flake, can be called multiple times. See [`NixtractConfig::store_paths`].
+    pub fn store_path(mut self, store_path: impl Into<String>) -> Self {
+        self.config.store_paths.push(store_path.into());
+        self
+    }
+
+    /// Do not describe these output paths again, described by a previous run, e.g. to resume an
+    /// interrupted one. See [`NixtractConfig::completed_paths`].
+    pub fn completed_paths(
+        mut self,
+        completed_paths: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config
+            .completed_paths
+            .extend(completed_paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Drop or flag derivations whose licenses this policy does not allow
+    pub fn license_policy(mut self, license_policy: license::LicensePolicy) -> Self {
+        self.config.license_policy = Some(license_policy);
+        self
+    }
+
+    /// Only emit derivations marked as broken in nixpkgs, their dependencies are still explored
+    pub fn only_broken(mut self, only_broken: bool) -> Self {
+        self.config.only.broken = only_broken;
+        self
+    }
+
+    /// Only emit derivations with a license that is not free, their dependencies are still
+    /// explored
+    pub fn only_unfree(mut self, only_unfree: bool) -> Self {
+        self.config.only.unfree = only_unfree;
+        self
+    }
+
+    /// Only emit derivations with known vulnerabilities, their dependencies are still explored
+    pub fn only_insecure(mut self, only_insecure: bool) -> Self {
+        self.config.only.insecure = only_insecure;
+        self
+    }
+
+    /// Override the SPDX ids of licenses, keyed by their name in nixpkgs' `lib.licenses` or by
+    /// their full name, see [`license::LicenseMap`]
+    pub fn license_map(mut self, license_map: license::LicenseMap) -> Self {
+        self.config.license_map = Some(license_map);
+        self
+    }
+
+    /// Only explore the dependencies of derivations for which `filter` returns true, e.g. to
+    /// prune by name or by the size in the narinfo. The derivations it rejects are still
+    /// described, only their `build_inputs` are not. It is called from the worker threads.
+    ///
+    /// ```no_run
+    /// let derivations = nixtract::nixtract_builder()
+    ///     .attribute_path("hello")
+    ///     .filter(|description| !description.parsed_name.name.starts_with("python3"))
+    ///     .run()?;
+    /// # Ok::<(), nixtract::error::Error>(())
+    /// ```
+    pub fn filter(
+        mut self,
+        filter: impl Fn(&DerivationDescription) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.descend_filter = Some(DescendFilter::new(filter));
+        self
+    }
+
+    /// Give up on describing a derivation if its evaluation takes longer than this
+    pub fn eval_timeout(mut self, eval_timeout: std::time::Duration) -> Self {
+        self.config.eval_timeout = Some(eval_timeout);
+        self
+    }
+
+    /// Stop the run if it takes longer than this overall, see [`NixtractConfig::timeout_total`]
+    pub fn timeout_total(mut self, timeout_total: std::time::Duration) -> Self {
+        self.config.timeout_total = Some(timeout_total);
+        self
+    }
+
+    /// Limit the memory of every nix evaluation to this many bytes, a derivation whose evaluation
+    /// exceeds it fails to be described. Only supported on Linux.
+    pub fn max_eval_memory(mut self, max_eval_memory: u64) -> Self {
+        self.config.max_eval_memory = Some(max_eval_memory);
+        self
+    }
+
+    /// Caches to fetch narinfo from, defaults to the substituters from nix.conf and the flake
+    pub fn binary_caches(
+        mut self,
+        binary_caches: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.binary_caches = Some(binary_caches.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// How often a narinfo fetch is retried on a transient failure before trying the next cache
+    pub fn narinfo_retries(mut self, retries: usize) -> Self {
+        self.config.narinfo_fetch.retries = retries;
+        self
+    }
+
+    /// Authenticate to the binary cache at this host when fetching narinfo, can be called multiple times
+    pub fn cache_credentials(
+        mut self,
+        host: impl Into<String>,
+        credentials: netrc::Credentials,
+    ) -> Self {
+        self.config
+            .narinfo_fetch
+            .credentials
+            .insert(host.into(), credentials);
+        self
+    }
+
+    /// Where narinfo is read from, the local store can be asked before the binary caches
+    pub fn narinfo_source(mut self, source: narinfo::NarInfoSource) -> Self {
+        self.config.narinfo_fetch.source = source;
+        self
+    }
+
+    /// Give up on a narinfo request, and try the next cache, if it takes longer than this
+    pub fn narinfo_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.config.narinfo_fetch.timeout = timeout;
+        self
+    }
+
+    /// Give up on connecting to a cache for narinfo, and try the next one, after this long
+    pub fn narinfo_connect_timeout(mut self, connect_timeout: std::time::Duration) -> Self {
+        self.config.narinfo_fetch.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Keep fetched narinfo in this directory and read it from there before asking the caches,
+    /// see [`narinfo::default_cache_dir`] for the usual location
+    pub fn narinfo_cache_dir(mut self, cache_dir: impl Into<std::path::PathBuf>) -> Self {
+        self.config.narinfo_fetch.cache_dir = Some(cache_dir.into());
+        self
+    }
+
+    /// Send at most this many concurrent narinfo requests to each binary cache, whatever the number
+    /// of threads fetching narinfo
+    pub fn narinfo_per_host(mut self, limit: usize) -> Self {
+        self.config.narinfo_fetch.per_host = narinfo::HostLimit::new(Some(limit));
+        self
+    }
+
+    /// Fetch narinfo on a separate pool of this many threads instead of on the evaluation workers
+    pub fn narinfo_concurrency(mut self, narinfo_concurrency: usize) -> Self {
+        self.config.narinfo_concurrency = Some(narinfo_concurrency);
+        self
+    }
+
+    /// Describe derivations on this many workers, the default is the number of CPUs
+    pub fn n_workers(mut self, n_workers: usize) -> Self {
+        self.config.n_workers = Some(n_workers);
+        self
+    }
+
+    /// Channel used to send status updates back to the caller, see [`message::Message`]
+    pub fn message_tx(mut self, message_tx: mpsc::Sender<message::Message>) -> Self {
+        self.config.message_tx = Some(message_tx);
+        self
+    }
+
+    /// Statistics of the run are accumulated here, read them once the iterator is exhausted
+    pub fn stats(mut self, stats: stats::SharedStats) -> Self {
+        self.config.stats = Some(stats);
+        self
+    }
+
+    /// Stop the run once `cancellation` is cancelled
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.config.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Replace the whole configuration at once
+    pub fn config(mut self, config: NixtractConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Only discover the top-level derivations that [`run`](Self::run) would describe, without
+    /// describing anything. A strict discovery that fails after finding derivations ends with
+    /// its error.
+    pub fn discover(self) -> Result<impl Iterator<Item = Result<FoundDrv>>> {
+        let NixtractBuilder {
+            flake_refs,
+            systems,
+            attribute_paths,
+            config,
+        } = self;
+
+        nix::command::check_nix_installed(config.nix_binary.as_deref())?;
+        validate_systems(&systems, config.nix_binary.as_deref())?;
+        let flake_refs = lock_flake_refs(flake_refs, &config)?;
+
+        let lib = Arc::new(nix::lib::Lib::new()?);
+        // A token of its own, so that a strict failure does not cancel the caller's
+        let failure = Arc::new(Failure::default());
+        let cancellation = config.cancellation.clone().unwrap_or_default();
+        let found_drvs = spawn_discoveries(
+            flake_refs,
+            none_if_empty(systems),
+            none_if_empty(attribute_paths),
+            &config,
+            lib,
+            Arc::clone(&failure),
+        )?;
+        // The error is set before the discovery stops, so it is there once the found ones end
+        let error = std::iter::from_fn(move || failure.error.lock().unwrap().take().map(Err));
+
+        // Derivations found through several of the requested attribute paths are listed once
+        let mut seen = std::collections::HashSet::new();
+        Ok(found_drvs
+            .map(|(_, found_drv)| found_drv)
+            .filter(move |found_drv| match &found_drv.output_path {
+                None => true,
+                Some(output_path) => seen.insert((found_drv.system.clone(), output_path.clone())),
+            })
+            .take_while(move |_| !cancellation.is_cancelled())
+            .map(Ok)
+            .chain(error))
+    }
+
+    /// Start extracting, returns an iterator over the described derivations
+    pub fn run(self) -> Result<Descriptions> {
+        let NixtractBuilder {
+            flake_refs,
+            systems,
+            attribute_paths,
+            config,
+        } = self;
+
+        // Fail right away if nix is missing or a system is mistyped, rather than in every worker
+        nix::command::check_nix_installed(config.nix_binary.as_deref())?;
+        validate_systems(&systems, config.nix_binary.as_deref())?;
+        if config.max_eval_memory.is_some() && !cfg!(target_os = "linux") {
+            log::warn!("Limiting the evaluation memory is only supported on Linux, ignoring it");
+        }
+        let flake_refs = lock_flake_refs(flake_refs, &config)?;
+
+        let binary_caches = match config.binary_caches.clone() {
+            None => nix::substituters::get_flakes_substituters(
+                &flake_refs,
+                config.nix_binary.as_deref(),
+            )?,
+            Some(caches) => caches,
+        };
+
+        // Compile the exclude patterns once for all workers
+        let exclude = regex::RegexSet::new(&config.exclude)?;
+
+        // A single HTTP client for all narinfo fetches, keeping connections to the caches alive
+        let http_client = config.narinfo_fetch.client()?;
+
+        let narinfo_pool = config
+            .narinfo_concurrency
+            .map(|narinfo_concurrency| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(narinfo_concurrency)
+                    .thread_name(|index| format!("nixtract-narinfo-{}", index))
+                    .build()
+            })
+            .transpose()?;
+
+        // A pool of our own rather than the global one, which the caller may use or size
+        // differently. 0 threads means rayon's default.
+        let worker_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.n_workers.unwrap_or(0))
+            .thread_name(|index| format!("nixtract-worker-{}", index))
+            .build()
+            .map_err(error::Error::WorkerPool)?;
+
+        // Writes the `lib.nix` file to the tempdir and stores its path
+        let lib = nix::lib::Lib::new()?;
+
+        // Create a channel to communicate DerivationDescription to the main thread
+        let (tx, rx) = mpsc::channel();
+        let failure = Arc::new(Failure {
+            cancelled: config.cancellation.clone().unwrap_or_default(),
+            ..Default::default()
+        });
+        if let Some(timeout_total) = config.timeout_total {
+            spawn_watchdog(Arc::downgrade(&failure), timeout_total);
+        }
+
+        log::info!(
+            "Starting nixtract with flake_refs: {:?}, systems: {:?}, attribute_paths: {:?}",
+            flake_refs,
+            systems,
+            attribute_paths
+        );
+
+        let systems: Vec<SystemState> = none_if_empty(systems)
+            .into_iter()
+            .map(SystemState::new)
+            .collect();
+        let completed_paths: std::collections::HashSet<String> =
+            config.completed_paths.iter().cloned().collect();
+
+        // Describing can start while the flake is still being evaluated
+        let lib = Arc::new(lib);
+        let found_drvs = spawn_discoveries(
+            flake_refs,
+            systems.iter().map(|state| state.system.clone()).collect(),
+            none_if_empty(attribute_paths),
+            &config,
+            Arc::clone(&lib),
+            Arc::clone(&failure),
+        )?;
+
+        // Spawn a new rayon thread to call process on every foundDrv
+        let descriptions = Descriptions {
+            rx,
+            failure: Arc::clone(&failure),
+        };
+        worker_pool.spawn(move || {
+            found_drvs.par_bridge().for_each(|(flake_ref, found_drv)| {
+                let SystemState {
+                    system,
+                    collected_paths,
+                    described,
+                } = systems
+                    .iter()
+                    .find(|system_state| system_state.system == found_drv.system)
+                    .expect("derivations are only found for the requested systems");
+
+                // Skip derivations that were found through several of the requested attribute
+                // paths, or already reached as the dependency of another
+                let visited = match &found_drv.output_path {
+                    // Expected when the discovery was skipped
+                    None if config.skip_discovery => Visit::New,
+                    None => {
+                        log::warn!("Found a derivation without an output_path: {:?}", found_drv);
+                        Visit::New
+                    }
+                    Some(output_path) => visit(collected_paths, output_path, 0, config.max_depth),
+                };
+                if visited == Visit::Done {
+                    record_stats(&config.stats, |stats| stats.skipped += 1);
+                    return;
+                }
+
+                let processing_args = ProcessingArgs {
+                    collected_paths,
+                    completed_paths: &completed_paths,
+                    described,
+                    flake_ref: &flake_ref,
+                    system,
+                    attribute_path: found_drv.attribute_path,
+                    flake_output: found_drv.flake_output,
+                    output_path: found_drv.output_path,
+                    depth: 0,
+                    already_sent: visited == Visit::Shallower,
+                    ancestors: Vec::new(),
+                    max_depth: config.max_depth,
+                    exclude: &exclude,
+                    from_store: !config.store_paths.is_empty(),
+                    only: config.only,
+                    license_policy: config.license_policy.as_ref(),
+                    license_map: config.license_map.as_ref(),
+                    descend_filter: config.descend_filter.as_ref(),
+                    offline: config.offline,
+                    nix_binary: config.nix_binary.as_deref(),
+                    nix_args: &config.nix_args,
+                    nixpkgs_allow: config.nixpkgs_allow,
+                    runtime_only: config.runtime_only,
+                    runtime_source: config.runtime_source,
+                    include_nar_info: config.include_nar_info,
+                    include_drv: config.include_drv,
+                    include_meta_position: config.include_meta_position,
+                    include_store_size: config.include_store_size,
+                    eval_timeout: config.eval_timeout,
+                    max_eval_memory: config.max_eval_memory,
+                    binary_caches: &binary_caches,
+                    http_client: &http_client,
+                    narinfo_fetch: &config.narinfo_fetch,
+                    narinfo_pool: narinfo_pool.as_ref(),
+                    lib: &lib,
+                    tx: tx.clone(),
+                    message_tx: config.message_tx.clone(),
+                    quiet_skipped: config.quiet_skipped,
+                    skip_null_output_paths: config.skip_null_output_paths,
+                    describe_batch: config.describe_batch,
+                    stats: &config.stats,
+                    strict: config.strict,
+                    failure: &failure,
+                };
+                let queued = send_message(
+                    &processing_args.message_tx,
+                    message::Message {
+                        status: message::Status::Queued { count: 1 },
+                        id: rayon::current_thread_index().unwrap(),
+                        path: processing_args.attribute_path.clone(),
+                    },
+                );
+                if let Err(e) = queued {
+                    log::warn!("Error processing derivation: {}", e);
+                    return;
+                }
+                match is_excluded(&processing_args, &processing_args.attribute_path) {
+                    Ok(false) => {}
+                    Ok(true) => return,
+                    Err(e) => {
+                        log::warn!("Error processing derivation: {}", e);
+                        return;
+                    }
+                }
+                match process(processing_args) {
+                    Ok(_) => {}
+                    Err(e) if config.fail_fast || config.strict => failure.fail(e),
+                    Err(e) => log::warn!("Error processing derivation: {}", e),
+                }
+            });
+        });
+
+        Ok(descriptions)
+    }
+}
+
+/// Cancels a run from another thread, e.g. when the request it serves is abandoned. Derivations
+/// that are being described are finished, but no new ones are started and the iterator of the
+/// run ends right away. Clones share their state, a token is meant for a single run.
+///
+/// ```no_run
+/// let cancellation = nixtract::CancellationToken::default();
+/// let descriptions = nixtract::nixtract_builder()
+///     .cancellation(cancellation.clone())
+///     .run()?;
+///
+/// let timeout = cancellation.clone();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     timeout.cancel();
+/// });
+/// for description in descriptions {
+///     println!("{}", description);
+/// }
+/// # Ok::<(), nixtract::error::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The first error of a run with `fail_fast` or a `timeout_total`, which cancels the rest of the
+/// run
+#[derive(Debug, Default)]
+struct Failure {
+    cancelled: CancellationToken,
+    error: Mutex<Option<error::Error>>,
+    /// Set once every description was received, a deadline passing after that is not a failure
+    finished: AtomicBool,
+}
+
+impl Failure {
+    fn fail(&self, e: error::Error) {
+        log::error!("Error processing derivation, stopping: {}", e);
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e);
+        }
+        self.cancelled.cancel();
+    }
+}
+
+/// Fail the run of `failure` once `timeout_total` has passed, unless it finished or was cancelled
+/// before. The watchdog wakes up regularly so it does not outlive the run by much.
+fn spawn_watchdog(failure: std::sync::Weak<Failure>, timeout_total: std::time::Duration) {
+    let deadline = std::time::Instant::now() + timeout_total;
+    std::thread::spawn(move || loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        std::thread::sleep(remaining.min(std::time::Duration::from_secs(1)));
+
+        let Some(failure) = failure.upgrade() else {
+            return;
+        };
+        if failure.finished.load(Ordering::Relaxed) || failure.cancelled.is_cancelled() {
+            return;
+        }
+        if std::time::Instant::now() >= deadline {
+            log::error!(
+                "The run did not finish within {:?}, stopping",
+                timeout_total
+            );
+            failure
+                .error
+                .lock()
+                .unwrap()
+                .get_or_insert(error::Error::TimeoutTotal(timeout_total));
+            failure.cancelled.cancel();
+            return;
+        }
+    });
+}
+
+/// Iterator over the described derivations, returned by [`NixtractBuilder::run`]
+#[derive(Debug)]
+pub struct Descriptions {
+    rx: mpsc::Receiver<DerivationDescription>,
+    failure: Arc<Failure>,
+}
+
+impl Descriptions {
+    /// The error that stopped a run with `fail_fast`, `strict` or `timeout_total`, check it once
+    /// the iterator is exhausted. A run stopped with its [`CancellationToken`] has no error.
+    ///
+    /// The error is moved out, so only the first call returns it.
+    pub fn error(&self) -> Option<error::Error> {
+        self.failure.error.lock().unwrap().take()
+    }
+}
+
+impl Iterator for Descriptions {
+    type Item = DerivationDescription;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Poll, so a failure ends the iteration without waiting for evaluations still running
+        loop {
+            if self.failure.cancelled.is_cancelled() {
+                return None;
+            }
+            match self.rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                Ok(description) => return Some(description),
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.failure.finished.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// No systems means the host system, and no attribute paths the whole flake
+fn none_if_empty(values: Vec<String>) -> Vec<Option<String>> {
+    if values.is_empty() {
+        vec![None]
+    } else {
+        values.into_iter().map(Some).collect()
+    }
+}
+
+/// Only packages are discovered unless asked otherwise
+fn flake_outputs_or_default(flake_outputs: &[FlakeOutput]) -> Vec<FlakeOutput> {
+    if flake_outputs.is_empty() {
+        vec![FlakeOutput::Packages]
+    } else {
+        flake_outputs.to_vec()
+    }
+}
+
+/// [`lock_flake_ref`] for every flake of the run
+fn lock_flake_refs(flake_refs: Vec<String>, config: &NixtractConfig) -> Result<Vec<String>> {
+    flake_refs
+        .into_iter()
+        .map(|flake_ref| lock_flake_ref(flake_ref, config))
+        .collect()
+}
+
+/// The locked URL of `flake_ref` if the configuration asks for it, `flake_ref` itself otherwise
+fn lock_flake_ref(flake_ref: String, config: &NixtractConfig) -> Result<String> {
+    if !config.lock_flake_ref {
+        return Ok(flake_ref);
+    }
+
+    let metadata = nix::flake_metadata::FlakeMetadata::get(
+        &flake_ref,
+        config.offline,
+        config.nix_binary.as_deref(),
+    )?;
+    match metadata.url {
+        Some(url) => {
+            log::info!("Locked {} to {}", flake_ref, url);
+            Ok(url)
+        }
+        None => {
+            log::warn!(
+                "nix did not report a locked URL for {}, using it as is",
+                flake_ref
+            );
+            Ok(flake_ref)
+        }
+    }
+}
+
+/// [`spawn_discovery`] for every flake, with the flake each derivation was found in. Every flake
+/// is evaluated on a thread of its own, their derivations come one flake after the other.
+fn spawn_discoveries(
+    flake_refs: Vec<String>,
+    systems: Vec<Option<String>>,
+    attribute_paths: Vec<Option<String>>,
+    config: &NixtractConfig,
+    lib: Arc<nix::lib::Lib>,
+    failure: Arc<Failure>,
+) -> Result<impl Iterator<Item = (String, FoundDrv)> + Send> {
+    // Store paths do not depend on the flake, they are only seeded once
+    let flake_refs = if config.store_paths.is_empty() {
+        flake_refs
+    } else {
+        flake_refs.into_iter().take(1).collect()
+    };
+
+    let mut found_drvs: Box<dyn Iterator<Item = (String, FoundDrv)> + Send> =
+        Box::new(std::iter::empty());
+    for flake_ref in flake_refs {
+        let found = spawn_discovery(
+            flake_ref.clone(),
+            systems.clone(),
+            attribute_paths.clone(),
+            config,
+            Arc::clone(&lib),
+            Arc::clone(&failure),
+        )?;
+        found_drvs =
+            Box::new(found_drvs.chain(found.map(move |found_drv| (flake_ref.clone(), found_drv))));
+    }
+    Ok(found_drvs)
+}
+
+/// Discover the top-level derivations on a separate thread, returning them as soon as nix reports
+/// them. Fails if nothing at all could be discovered because of an error.
+fn spawn_discovery(
+    flake_ref: String,
+    systems: Vec<Option<String>>,
+    attribute_paths: Vec<Option<String>>,
+    config: &NixtractConfig,
+    lib: Arc<nix::lib::Lib>,
+    failure: Arc<Failure>,
+) -> Result<impl Iterator<Item = FoundDrv> + Send> {
+    let flake_outputs = flake_outputs_or_default(&config.flake_outputs);
+    let offline = config.offline;
+    let nix_binary = config.nix_binary.clone();
+    let skip_discovery = config.skip_discovery;
+    let nix_args = config.nix_args.clone();
+    let nixpkgs_allow = config.nixpkgs_allow;
+    let max_eval_memory = config.max_eval_memory;
+    let store_paths = config.store_paths.clone();
+    let eval_timeout = config.eval_timeout;
+    let strict = config.strict;
+    let cancelled = failure.cancelled.clone();
+    let (found_tx, found_rx) = mpsc::channel::<FoundDrv>();
+    let discovery = std::thread::spawn(move || -> Result<()> {
+        // Store paths are seeded with their derivation, the flake is not evaluated
+        if !store_paths.is_empty() {
+            let mut first_error = None;
+            let mut found = 0;
+            for store_path in &store_paths {
+                if failure.cancelled.is_cancelled() {
+                    return Ok(());
+                }
+                match nix::deriver(store_path, eval_timeout, nix_binary.as_deref()) {
+                    Ok(Some(derivation_path)) => {
+                        let found_drv = FoundDrv {
+                            attribute_path: derivation_path.clone(),
+                            derivation_path: Some(derivation_path),
+                            output_path: Some(store_path.clone()),
+                            system: systems[0].clone(),
+                            ..Default::default()
+                        };
+                        if found_tx.send(found_drv).is_err() {
+                            return Ok(());
+                        }
+                        found += 1;
+                    }
+                    Ok(None) => {
+                        log::warn!("Skipping {}, it has no known deriver", store_path);
+                        first_error.get_or_insert(error::Error::NoDeriver(store_path.clone()));
+                    }
+                    Err(e) => {
+                        log::error!("Error finding the deriver of {}: {}", store_path, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            return discovery_result(first_error, found, strict, &failure);
+        }
+
+        // The attribute paths are known to be derivations, seed them as they are
+        if skip_discovery {
+            for system in &systems {
+                for &flake_output in &flake_outputs {
+                    for attribute_path in attribute_paths.iter().flatten() {
+                        let found_drv = FoundDrv {
+                            attribute_path: attribute_path.clone(),
+                            system: system.clone(),
+                            flake_output,
+                            ..Default::default()
+                        };
+                        if found_tx.send(found_drv).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        // Every combination is a separate nix evaluation, run them in parallel so a slow one
+        // does not hold back the others. Derivations found through several of them are
+        // deduplicated on their output path by the consumers.
+        let mut searches = Vec::new();
+        for system in &systems {
+            for &flake_output in &flake_outputs {
+                for attribute_path in &attribute_paths {
+                    searches.push((system, flake_output, attribute_path));
+                }
+            }
+        }
+        let results: Vec<_> = searches
+            .into_par_iter()
+            .map(|(system, flake_output, attribute_path)| {
+                let result = nix::find_attribute_paths(
+                    &nix::FindAttributePathsArgs {
+                        flake_ref: &flake_ref,
+                        system,
+                        attribute_path,
+                        flake_output,
+                        offline,
+                        nix_binary: nix_binary.as_deref(),
+                        nix_args: &nix_args,
+                        nixpkgs_allow,
+                        max_eval_memory,
+                        strict,
+                        lib: &lib,
+                    },
+                    found_tx.clone(),
+                );
+                (system, flake_output, attribute_path, result)
+            })
+            .collect();
+
+        let found: usize = results
+            .iter()
+            .filter_map(|(_, _, _, result)| result.as_ref().ok())
+            .sum();
+        let mut first_error = None;
+        for (system, flake_output, attribute_path, result) in results {
+            if let Err(e) = result {
+                log::error!(
+                    "Error finding derivations in {} of {} for {}: {}",
+                    attribute_path.as_deref().unwrap_or(&flake_ref),
+                    flake_output.as_str(),
+                    system.as_deref().unwrap_or("the host system"),
+                    e
+                );
+                first_error.get_or_insert(e);
+            }
+        }
+
+        discovery_result(first_error, found, strict, &failure)
+    });
+
+    // Wait for the first derivation, so that failing to evaluate the flake at all is reported
+    // to the caller instead of resulting in an empty iterator. A cancelled run stops waiting.
+    let first = loop {
+        match found_rx.recv_timeout(std::time::Duration::from_millis(100)) {
+            Ok(found_drv) => break Some(found_drv),
+            Err(mpsc::RecvTimeoutError::Timeout) if cancelled.is_cancelled() => break None,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                discovery
+                    .join()
+                    .expect("the discovery thread should not panic")?;
+                break None;
+            }
+        }
+    };
+
+    Ok(first
+        .into_iter()
+        .chain(found_rx)
+        .take_while(move |_| !cancelled.is_cancelled()))
+}
+
+/// The result of a discovery that found `found` derivations and ran into `first_error`. When
+/// nothing was sent the error can still be returned to the caller, otherwise it is only logged,
+/// unless the run is strict and fails as a whole. The derivations found before a strict failure
+/// may not be counted in `found`, so its errors always go through `failure`.
+fn discovery_result(
+    first_error: Option<error::Error>,
+    found: usize,
+    strict: bool,
+    failure: &Failure,
+) -> Result<()> {
+    match first_error {
+        Some(e) if strict => {
+            failure.fail(e);
+            Ok(())
+        }
+        Some(e) if found == 0 => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Extract the derivations of a flake, see [`NixtractBuilder`] for a more ergonomic interface
+pub fn nixtract(
+    flake_ref: impl Into<String>,
+    system: Option<impl Into<String>>,
+    attribute_path: Option<impl Into<String>>,
+    config: NixtractConfig,
+) -> Result<Descriptions> {
+    // Convert the arguments to the expected types
+    NixtractBuilder {
+        flake_refs: vec![flake_ref.into()],
+        systems: system.map(Into::into).into_iter().collect(),
+        attribute_paths: attribute_path.map(Into::into).into_iter().collect(),
+        config,
+    }
+    .run()
+}
+
+/// Describe a single derivation, without exploring its dependencies: its `build_inputs` are listed
+/// but not described. Cheaper than [`nixtract`] when only the metadata of a specific package is
+/// needed.
+///
+/// Of the `config`, only the options that change how a derivation is described are used, and the
+/// first of the `flake_outputs` is the one `attribute_path` is looked up in.
+/// ```no_run
+/// let hello = nixtract::describe_one(
+///     "nixpkgs",
+///     Some("x86_64-linux"),
+///     "hello",
+///     &nixtract::NixtractConfig::default(),
+/// )?;
+/// println!("{} {:?}", hello.name, hello.nixpkgs_metadata.licenses);
+/// # Ok::<(), nixtract::error::Error>(())
+/// ```
+pub fn describe_one(
+    flake_ref: impl Into<String>,
+    system: Option<impl Into<String>>,
+    attribute_path: impl Into<String>,
+    config: &NixtractConfig,
+) -> Result<DerivationDescription> {
+    let flake_ref = flake_ref.into();
+    let system = system.map(Into::into);
+
+    // Only look the substituters up when they are needed, it costs nix calls of its own
+    let needs_binary_caches = config.include_nar_info
+        || (config.runtime_only && config.runtime_source == RuntimeSource::Store);
+    let binary_caches = match &config.binary_caches {
+        Some(caches) => caches.clone(),
+        None if needs_binary_caches => {
+            nix::substituters::get_substituters(flake_ref.clone(), config.nix_binary.as_deref())?
+        }
+        None => Vec::new(),
+    };
+
+    let http_client = config.narinfo_fetch.client()?;
+    let lib = nix::lib::Lib::new()?;
+
+    nix::describe_derivation(&nix::DescribeDerivationArgs {
+        flake_ref: &flake_ref,
+        system: &system,
+        attribute_path: attribute_path.into(),
+        flake_output: flake_outputs_or_default(&config.flake_outputs)[0],
+        offline: config.offline,
+        nix_binary: config.nix_binary.as_deref(),
+        nix_args: &config.nix_args,
+        nixpkgs_allow: config.nixpkgs_allow,
+        runtime_only: config.runtime_only,
+        runtime_source: config.runtime_source,
+        include_nar_info: config.include_nar_info,
+        include_drv: config.include_drv,
+        include_meta_position: config.include_meta_position,
+        include_store_size: config.include_store_size,
+        eval_timeout: config.eval_timeout,
+        max_eval_memory: config.max_eval_memory,
+        license_map: config.license_map.as_ref(),
+        binary_caches: &binary_caches,
+        http_client: &http_client,
+        narinfo_fetch: &config.narinfo_fetch,
+        lib: &lib,
+    })
+}
+
+/// Fetch the narinfo of many store paths at once, without describing any derivation, e.g. for
+/// tools that only deal with store paths. The paths are fetched concurrently on the current rayon
+/// pool with a single HTTP client, within the `per_host` limit of `config`. The results are in the
+/// order of `paths`, a path that none of the `servers` has is `Ok(None)`.
+/// ```no_run
+/// let paths = vec!["/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_owned()];
+/// let servers = vec!["https://cache.nixos.org".to_owned()];
+/// let config = nixtract::narinfo::FetchConfig::default();
+/// for (path, narinfo) in nixtract::fetch_narinfo_batch(&paths, &servers, &config)? {
+///     if let Ok(Some(narinfo)) = narinfo {
+///         println!("{}: {} bytes", path, narinfo.nar_size);
+///     }
+/// }
+/// # Ok::<(), nixtract::error::Error>(())
+/// ```
+pub fn fetch_narinfo_batch(
+    paths: &[String],
+    servers: &[String],
+    config: &narinfo::FetchConfig,
+) -> Result<Vec<(String, Result<Option<narinfo::NarInfo>>)>> {
+    let http_client = config.client()?;
+    Ok(paths
+        .par_iter()
+        .map(|path| {
+            let narinfo = narinfo::NarInfo::fetch(&http_client, config, path, servers);
+            (path.clone(), narinfo)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::panic;
+    use std::fs;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_main_fixtures() -> Result<()> {
+        init();
+
+        // For every subdirectory in the tests/fixtures directory
+        for entry in fs::read_dir("tests/fixtures").unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path().canonicalize().unwrap();
+            if path.is_dir() {
+                let config = NixtractConfig {
+                    runtime_only: false,
+                    flake_outputs: Vec::new(),
+                    fail_fast: false,
+                    skip_discovery: false,
+                    runtime_source: RuntimeSource::default(),
+                    max_depth: None,
+                    exclude: Vec::new(),
+                    completed_paths: Vec::new(),
+                    store_paths: Vec::new(),
+                    only: MetaFilter::default(),
+                    license_policy: None,
+                    license_map: None,
+                    descend_filter: None,
+                    eval_timeout: None,
+                    max_eval_memory: None,
+                    binary_caches: None,
+                    narinfo_fetch: narinfo::FetchConfig::default(),
+                    narinfo_concurrency: None,
+                    n_workers: None,
+                    offline: false,
+                    lock_flake_ref: false,
+                    nix_binary: None,
+                    nix_args: Vec::new(),
+                    nixpkgs_allow: NixpkgsAllow::default(),
+                    include_nar_info: false,
+                    include_drv: false,
+                    include_meta_position: false,
+                    include_store_size: false,
+                    message_tx: None,
+                    quiet_skipped: false,
+                    skip_null_output_paths: false,
+                    describe_batch: None,
+                    stats: None,
+                    cancellation: None,
+                    timeout_total: None,
+                    strict: false,
+                };
+
+                log::info!("Running test for {:?}", path);
+
+                let test_name = path
+                    .components()
+                    .next_back()
+                    .unwrap()
+                    .as_os_str()
+                    .to_str()
+                    .unwrap();
+                let flake_ref = path.to_str().unwrap();
+                let system: Option<String> = None;
+                let attribute_path: Option<String> = None;
+
+                let mut descriptions = nixtract(flake_ref, system, attribute_path, config).unwrap();
+
+                match test_name {
+                    "flake-direct-buildInput" => {}
+                    "flake-direct-nativeBuildInput" => {}
+                    "flake-three-levels" => {}
+                    "flake-trivial-rust" => {
+                        assert!(descriptions.any(|d| {
+                            d.sources.iter().any(|s| {
+                                matches!(s, Source::Git { url, .. } if url == "https://github.com/hello-lang/Rust.git")
+                            })
+                        }));
+                    }
+                    "flake-trivial" => {}
+                    s => panic!("Unknown test: {}", s),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_descriptions_stop_on_failure() {
+        let (tx, rx) = mpsc::channel();
+        let failure: Arc<Failure> = Arc::default();
+        let mut descriptions = Descriptions {
+            rx,
+            failure: Arc::clone(&failure),
+        };
+
+        tx.send(format::tests::description("hello", "1.0", &[]))
+            .unwrap();
+        assert!(descriptions.next().is_some());
+
+        // Ends without waiting for the sender to hang up
+        failure.fail(error::Error::NixTimeout("broken".to_owned()));
+        assert!(descriptions.next().is_none());
+        assert!(matches!(
+            descriptions.error(),
+            Some(error::Error::NixTimeout(_))
+        ));
+        drop(tx);
+    }
+
+    #[test]
+    fn test_descriptions_cancelled() {
+        let (tx, rx) = mpsc::channel();
+        let cancellation = CancellationToken::default();
+        let mut descriptions = Descriptions {
+            rx,
+            failure: Arc::new(Failure {
+                cancelled: cancellation.clone(),
+                ..Default::default()
+            }),
+        };
+
+        tx.send(format::tests::description("hello", "1.0", &[]))
+            .unwrap();
+        assert!(descriptions.next().is_some());
+
+        cancellation.cancel();
+        tx.send(format::tests::description("zlib", "1.3", &[]))
+            .unwrap();
+        assert!(descriptions.next().is_none());
+        assert!(descriptions.error().is_none());
+    }
+
+    #[test]
+    fn test_fetch_narinfo_batch() {
+        let server = tempfile::tempdir().unwrap();
+        std::fs::write(
+            server
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+        let servers = vec![format!("file://{}", server.path().display())];
+        let paths = vec![
+            "/nix/store/00000000000000000000000000000000-missing".to_owned(),
+            "not-a-store-path".to_owned(),
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_owned(),
+        ];
+
+        let results =
+            fetch_narinfo_batch(&paths, &servers, &narinfo::FetchConfig::default()).unwrap();
+
+        let result_paths: Vec<&String> = results.iter().map(|(path, _)| path).collect();
+        assert_eq!(result_paths, paths.iter().collect::<Vec<_>>());
+        assert!(matches!(results[0].1, Ok(None)));
+        assert!(matches!(
+            results[1].1,
+            Err(error::Error::NarInfoInvalidPath(_))
+        ));
+        assert_eq!(
+            results[2].1.as_ref().unwrap().as_ref().unwrap().nar_size,
+            226552
+        );
+    }
+
+    #[test]
+    fn test_strict_narinfo() {
+        let hello = format::tests::description("hello", "1.0", &[]);
+        let output_path = hello.output_path.clone().unwrap();
+        let hash = output_path
+            .strip_prefix("/nix/store/")
+            .and_then(|base_name| base_name.split('-').next())
+            .unwrap();
+        // A key nixtract does not know
+        let server = tempfile::tempdir().unwrap();
+        std::fs::write(
+            server.path().join(format!("{}.narinfo", hash)),
+            format!(
+                "StorePath: {}
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+Signature: cache.nixos.org-1:future
+",
+                output_path
+            ),
+        )
+        .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let failure = Arc::new(Failure::default());
+        let narinfo_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let args = ProcessingArgs {
+            collected_paths: &Default::default(),
+            completed_paths: &Default::default(),
+            described: &Default::default(),
+            flake_ref: &"nixpkgs".to_owned(),
+            system: &None,
+            attribute_path: "hello".to_owned(),
+            flake_output: FlakeOutput::default(),
+            output_path: Some(output_path),
+            depth: 0,
+            already_sent: false,
+            ancestors: Vec::new(),
+            max_depth: None,
+            exclude: &regex::RegexSet::empty(),
+            from_store: false,
+            only: MetaFilter::default(),
+            license_policy: None,
+            license_map: None,
+            descend_filter: None,
+            offline: false,
+            nix_binary: None,
+            nix_args: &[],
+            nixpkgs_allow: NixpkgsAllow::default(),
+            include_nar_info: true,
+            include_drv: false,
+            include_meta_position: false,
+            include_store_size: false,
+            runtime_only: false,
+            runtime_source: RuntimeSource::default(),
+            eval_timeout: None,
+            max_eval_memory: None,
+            binary_caches: &vec![format!("file://{}", server.path().display())],
+            http_client: &reqwest::blocking::Client::new(),
+            narinfo_fetch: &narinfo::FetchConfig {
+                strict: true,
+                ..Default::default()
+            },
+            narinfo_pool: Some(&narinfo_pool),
+            lib: &nix::lib::Lib::new().unwrap(),
+            tx,
+            message_tx: None,
+            quiet_skipped: false,
+            skip_null_output_paths: false,
+            describe_batch: None,
+            stats: &None,
+            strict: true,
+            failure: &failure,
+        };
+        send_with_narinfo(&args, &narinfo_pool, hello);
+        drop(args);
+
+        // The description is not sent without its narinfo, the run fails instead
+        let mut descriptions = Descriptions { rx, failure };
+        assert!(descriptions.next().is_none());
+        match descriptions.error() {
+            Some(error::Error::NarInfoInvalid(_, e)) => assert!(matches!(
+                *e,
+                error::Error::NarInfoUnknownField(key) if key == "Signature"
+            )),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    /// Descriptions of `name-1.0` for every node of the graph, depending on its edges
+    fn graph(edges: &[(&str, &[&str])]) -> Vec<DerivationDescription> {
+        let output_path = |name: &str| format::tests::description(name, "1.0", &[]).output_path;
+        edges
+            .iter()
+            .map(|(name, dependencies)| {
+                let mut description = format::tests::description(name, "1.0", &[]);
+                description.build_inputs = dependencies
+                    .iter()
+                    .map(|dependency| BuiltInput {
+                        attribute_path: dependency.to_string(),
+                        build_input_type: BuildInputType::BuildInput,
+                        output_path: output_path(dependency),
+                        all_output_paths: Vec::new(),
+                    })
+                    .collect();
+                description
+            })
+            .collect()
+    }
+
+    /// A `nix` that describes these derivations by attribute path, and appends every attribute
+    /// path, or batch of them, it evaluates to `evaluations` in `dir`. A flake discovers the
+    /// derivations traced in `{flake_ref}.found` in `dir`, and the `nix-store` next to it finds
+    /// the references of a store path in `{base name}.references`.
+    fn fake_nix(
+        dir: &std::path::Path,
+        descriptions: &[DerivationDescription],
+    ) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        for description in descriptions {
+            fs::write(
+                dir.join(format!("{}.json", description.attribute_path)),
+                serde_json::to_string(description).unwrap(),
+            )
+            .unwrap();
+        }
+        let nix = dir.join("nix");
+        fs::write(
+            &nix,
+            format!(
+                r#"#!/bin/sh
+if [ -z "$RUNTIME_ONLY" ]; then exec cat "{0}/$TARGET_FLAKE_REF.found" >&2; fi
+if [ -n "$TARGET_ATTRIBUTE_PATHS" ]; then
+  echo "$TARGET_ATTRIBUTE_PATHS" >> {0}/evaluations
+  separator=[
+  for attribute_path in $(echo "$TARGET_ATTRIBUTE_PATHS" | tr -d '[]"' | tr , ' '); do
+    printf %s "$separator"
+    cat "{0}/$attribute_path.json"
+    separator=,
+  done
+  echo ]
+  exit
+fi
+echo "$TARGET_ATTRIBUTE_PATH" >> {0}/evaluations
+exec cat "{0}/$TARGET_ATTRIBUTE_PATH.json"
+"#,
+                dir.display()
+            ),
+        )
+        .unwrap();
+        let nix_store = dir.join("nix-store");
+        fs::write(
+            &nix_store,
+            format!(
+                "#!/bin/sh\nexec cat \"{}/$(basename \"$3\").references\"\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        for script in [&nix, &nix_store] {
+            fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        nix
+    }
+
+    /// The trace of `fake_nix` discovering the derivations of `graph` with these names
+    fn found(names: &[&str]) -> String {
+        let found_drvs: Vec<_> = names
+            .iter()
+            .map(|name| {
+                serde_json::json!({
+                    "attributePath": name,
+                    "derivationPath": null,
+                    "outputPath": format::tests::description(name, "1.0", &[]).output_path,
+                })
+            })
+            .collect();
+        format!(
+            "trace: {}\n",
+            serde_json::json!({ "foundDrvs": found_drvs })
+        )
+    }
+
+    /// Process `root` on a single thread, so dependencies are processed in order, and return the
+    /// attribute paths of the descriptions that were sent
+    fn process_graph(
+        nix: &std::path::Path,
+        root: &str,
+        completed_paths: &std::collections::HashSet<String>,
+        customize: impl FnOnce(&mut ProcessingArgs),
+    ) -> Vec<String> {
+        let (tx, rx) = mpsc::channel();
+        let failure = Arc::new(Failure::default());
+        let mut args = ProcessingArgs {
+            collected_paths: &Default::default(),
+            completed_paths,
+            described: &Default::default(),
+            flake_ref: &"nixpkgs".to_owned(),
+            system: &None,
+            attribute_path: root.to_owned(),
+            flake_output: FlakeOutput::default(),
+            output_path: None,
+            depth: 0,
+            already_sent: false,
+            ancestors: Vec::new(),
+            max_depth: None,
+            exclude: &regex::RegexSet::empty(),
+            from_store: false,
+            only: MetaFilter::default(),
+            license_policy: None,
+            license_map: None,
+            descend_filter: None,
+            offline: false,
+            nix_binary: Some(nix),
+            nix_args: &[],
+            nixpkgs_allow: NixpkgsAllow::default(),
+            include_nar_info: false,
+            include_drv: false,
+            include_meta_position: false,
+            include_store_size: false,
+            runtime_only: false,
+            runtime_source: RuntimeSource::default(),
+            eval_timeout: None,
+            max_eval_memory: None,
+            binary_caches: &Vec::new(),
+            http_client: &reqwest::blocking::Client::new(),
+            narinfo_fetch: &narinfo::FetchConfig::default(),
+            narinfo_pool: None,
+            lib: &nix::lib::Lib::new().unwrap(),
+            tx,
+            message_tx: None,
+            quiet_skipped: false,
+            skip_null_output_paths: false,
+            describe_batch: None,
+            stats: &None,
+            strict: false,
+            failure: &failure,
+        };
+        customize(&mut args);
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| process(args))
+            .unwrap();
+
+        rx.into_iter()
+            .map(|description| description.attribute_path)
+            .collect()
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            &graph(&[("hello", &["glibc"]), ("glibc", &["gcc"]), ("gcc", &[])]),
+        );
+        assert_eq!(
+            process_graph(&nix, "hello", &Default::default(), |_| {}),
+            ["hello", "glibc", "gcc"]
+        );
+
+        // Interrupted before gcc was described
+        let completed_paths = ["hello", "glibc"]
+            .iter()
+            .filter_map(|name| format::tests::description(name, "1.0", &[]).output_path)
+            .collect();
+        assert_eq!(
+            process_graph(&nix, "hello", &completed_paths, |_| {}),
+            ["gcc"]
+        );
+    }
+
+    #[test]
+    fn test_flake_refs_dedupe() {
+        let dir = tempfile::tempdir().unwrap();
+        let descriptions = graph(&[("hello", &["glibc"]), ("glibc", &[])]);
+        let nix = fake_nix(dir.path(), &descriptions);
+        // The overlay builds the hello of nixpkgs, which depends on glibc
+        fs::write(dir.path().join("overlay.found"), found(&["hello"])).unwrap();
+        fs::write(dir.path().join("nixpkgs.found"), found(&["hello", "glibc"])).unwrap();
+
+        let mut described: Vec<String> = nixtract_builder()
+            .flake_refs(["overlay", "nixpkgs"])
+            .nix_binary(&nix)
+            .binary_caches(Vec::<String>::new())
+            .n_workers(1)
+            .run()
+            .unwrap()
+            .map(|description| description.attribute_path)
+            .collect();
+        described.sort();
+        assert_eq!(described, ["glibc", "hello"]);
+        // Not even evaluated twice
+        let evaluations = fs::read_to_string(dir.path().join("evaluations")).unwrap();
+        assert_eq!(evaluations.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_runtime_source_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut descriptions = graph(&[
+            ("curl", &["openssl", "perl"]),
+            ("openssl", &[]),
+            ("perl", &[]),
+        ]);
+        // curl lists the default output of openssl, but its binary links to the libraries in `out`
+        let openssl_out = format!(
+            "/nix/store/{}-openssl-1.0",
+            format::tests::hash("openssl-out")
+        );
+        descriptions[0].build_inputs[0].all_output_paths = vec![
+            descriptions[1].output_path.clone().unwrap(),
+            openssl_out.clone(),
+        ];
+        let nix = fake_nix(dir.path(), &descriptions);
+        let curl_output_path = descriptions[0].output_path.clone().unwrap();
+        fs::write(
+            dir.path().join(format!(
+                "{}.references",
+                curl_output_path.strip_prefix("/nix/store/").unwrap()
+            )),
+            format!("{}\n{}\n", curl_output_path, openssl_out),
+        )
+        .unwrap();
+
+        let described = process_graph(&nix, "curl", &Default::default(), |args| {
+            args.runtime_only = true;
+            args.runtime_source = RuntimeSource::Store;
+            args.offline = true;
+        });
+        assert_eq!(described, ["curl", "openssl"]);
+    }
+
+    #[test]
+    fn test_describe_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            &graph(&[("hello", &["glibc", "gcc"]), ("glibc", &[]), ("gcc", &[])]),
+        );
+
+        let described = process_graph(&nix, "hello", &Default::default(), |args| {
+            args.describe_batch = Some(2);
+        });
+        assert_eq!(described, ["hello", "glibc", "gcc"]);
+        // The dependencies were described by their batch and not evaluated again
+        let evaluations = fs::read_to_string(dir.path().join("evaluations")).unwrap();
+        assert_eq!(
+            evaluations.lines().collect::<Vec<_>>(),
+            ["hello", r#"["glibc","gcc"]"#]
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            &graph(&[
+                ("hello", &["glibc"]),
+                ("glibc", &["zlib"]),
+                ("zlib", &["xz"]),
+                ("xz", &[]),
+            ]),
+        );
+        fs::write(dir.path().join("nixpkgs.found"), found(&["hello", "zlib"])).unwrap();
+
+        // zlib is both at the maximum depth below hello and top-level, whichever comes first
+        let mut described: Vec<String> = nixtract_builder()
+            .nix_binary(&nix)
+            .binary_caches(Vec::<String>::new())
+            .max_depth(2)
+            .n_workers(1)
+            .run()
+            .unwrap()
+            .map(|description| description.attribute_path)
+            .collect();
+        described.sort();
+        assert_eq!(described, ["glibc", "hello", "xz", "zlib"]);
+    }
+
+    #[test]
+    fn test_visit() {
+        let collected_paths = CollectedPaths::default();
+        assert_eq!(visit(&collected_paths, "a", 2, Some(2)), Visit::New);
+        assert_eq!(visit(&collected_paths, "a", 2, Some(2)), Visit::Done);
+        assert_eq!(visit(&collected_paths, "a", 0, Some(2)), Visit::Shallower);
+        assert_eq!(visit(&collected_paths, "a", 1, Some(2)), Visit::Done);
+        // Without a maximum depth, the first visit explores everything
+        assert_eq!(visit(&collected_paths, "b", 2, None), Visit::New);
+        assert_eq!(visit(&collected_paths, "b", 0, None), Visit::Done);
+    }
+
+    #[test]
+    fn test_cycle() {
+        // Without paths, the inputs are not deduplicated and their attribute paths keep growing
+        let node = |name: &str, attribute_path: &str, input: &str| {
+            let mut description = format::tests::description(name, "1.0", &[]);
+            description.attribute_path = attribute_path.to_owned();
+            description.derivation_path = None;
+            description.output_path = None;
+            description.build_inputs = vec![BuiltInput {
+                attribute_path: input.to_owned(),
+                build_input_type: BuildInputType::BuildInput,
+                output_path: None,
+                all_output_paths: Vec::new(),
+            }];
+            description
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            &[
+                node("a", "a", "a.b"),
+                node("b", "a.b", "a.b.a"),
+                node("a", "a.b.a", "a.b.a.b"),
+            ],
+        );
+
+        let (message_tx, message_rx) = mpsc::channel();
+        let sent = process_graph(&nix, "a", &Default::default(), |args| {
+            args.message_tx = Some(message_tx);
+        });
+        assert_eq!(sent, ["a", "a.b"]);
+        let cycles: Vec<_> = message_rx
+            .into_iter()
+            .filter(|message| matches!(message.status, message::Status::Cycle))
+            .map(|message| message.path)
+            .collect();
+        assert_eq!(cycles, ["a.b.a"]);
+        let evaluations = fs::read_to_string(dir.path().join("evaluations")).unwrap();
+        assert_eq!(
+            evaluations.lines().collect::<Vec<_>>(),
+            ["a", "a.b", "a.b.a"]
+        );
+    }
+
+    #[test]
+    fn test_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            &graph(&[
+                ("hello", &["glibc", "gcc"]),
+                ("glibc", &["gcc"]),
+                ("gcc", &[]),
+            ]),
+        );
+
+        for quiet_skipped in [false, true] {
+            let (message_tx, message_rx) = mpsc::channel();
+            process_graph(&nix, "hello", &Default::default(), |args| {
+                args.message_tx = Some(message_tx);
+                args.quiet_skipped = quiet_skipped;
+            });
+
+            // Every derivation queued gets a final status, like the top-level one which is not
+            let (mut queued, mut finished) = (1, 0);
+            for message in message_rx {
+                match message.status {
+                    message::Status::Queued { count } => queued += count,
+                    message::Status::Started | message::Status::MaxDepthReached => {}
+                    _ => finished += 1,
+                }
+            }
+            assert_eq!(queued, finished, "quiet_skipped: {}", quiet_skipped);
+        }
+    }
+
+    #[test]
+    fn test_discovery_result() {
+        let no_deriver = || Some(error::Error::NoDeriver("/nix/store/a-hello".to_owned()));
+        let failure = Failure::default();
+
+        assert!(discovery_result(no_deriver(), 0, false, &failure).is_err());
+        assert!(discovery_result(no_deriver(), 3, false, &failure).is_ok());
+        assert!(!failure.cancelled.is_cancelled());
+
+        // A strict run fails even when derivations were already found
+        assert!(discovery_result(no_deriver(), 3, true, &failure).is_ok());
+        assert!(failure.cancelled.is_cancelled());
+        assert!(matches!(
+            failure.error.lock().unwrap().take(),
+            Some(error::Error::NoDeriver(_))
+        ));
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let failure = Arc::new(Failure::default());
+        spawn_watchdog(
+            Arc::downgrade(&failure),
+            std::time::Duration::from_millis(10),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(failure.cancelled.is_cancelled());
+        assert!(matches!(
+            failure.error.lock().unwrap().take(),
+            Some(error::Error::TimeoutTotal(_))
+        ));
+
+        // A run that already finished is left alone
+        let failure = Arc::new(Failure::default());
+        failure.finished.store(true, Ordering::Relaxed);
+        spawn_watchdog(
+            Arc::downgrade(&failure),
+            std::time::Duration::from_millis(10),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        assert!(!failure.cancelled.is_cancelled());
+        assert!(failure.error.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_meta_filter() {
+        let mut metadata = format::tests::description("hello", "1.0", &[]).nixpkgs_metadata;
+        let only_broken = MetaFilter {
+            broken: true,
+            ..Default::default()
+        };
+        let broken_or_unfree = MetaFilter {
+            unfree: true,
+            ..only_broken
+        };
+
+        assert!(MetaFilter::default().matches(&metadata));
+        assert!(!only_broken.matches(&metadata));
+        metadata.unfree = true;
+        assert!(!only_broken.matches(&metadata));
+        assert!(broken_or_unfree.matches(&metadata));
+        metadata.broken = true;
+        assert!(only_broken.matches(&metadata));
+    }
+
+    #[test]
+    fn test_validate_systems() {
+        assert!(validate_systems(
+            &["x86_64-linux".to_owned(), "aarch64-darwin".to_owned()],
+            None
+        )
+        .is_ok());
+
+        let err = validate_systems(
+            &["x86_64-linux".to_owned(), "x86-64-linux".to_owned()],
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(&err, error::Error::InvalidSystem(system) if system == "x86-64-linux"));
+        assert!(err.to_string().contains("aarch64-darwin"));
     }
 }
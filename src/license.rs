@@ -0,0 +1,207 @@
+//! Filtering of derivations by license, to use nixtract as a license-compliance gate.
+//!
+//! Licenses are matched on their SPDX id, after normalizing the ids nixpkgs uses that are
+//! deprecated or slightly off. Like nixpkgs' own `allowlistedLicenses` and
+//! `blocklistedLicenses`, a derivation with several licenses is allowed if any of them is
+//! allowed, and denied if any of them is denied.
+
+use std::collections::HashMap;
+
+use crate::License;
+
+/// Licenses of the GNU family whose bare SPDX ids are deprecated in favour of `-only` and
+/// `-or-later`
+const GNU_LICENSES: [&str; 11] = [
+    "AGPL-1.0", "AGPL-3.0", "GFDL-1.1", "GFDL-1.2", "GFDL-1.3", "GPL-1.0", "GPL-2.0", "GPL-3.0",
+    "LGPL-2.0", "LGPL-2.1", "LGPL-3.0",
+];
+
+/// What to do with derivations none of whose licenses has an SPDX id
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnknownLicense {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// What to do with derivations whose licenses are not allowed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LicenseAction {
+    /// Leave them out of the output, their dependencies are still explored
+    #[default]
+    Drop,
+    /// Keep them in the output and log a warning, they are counted in
+    /// [`Stats::license_violations`](crate::stats::Stats::license_violations)
+    Flag,
+}
+
+/// Which licenses derivations may have
+#[derive(Debug, Default, Clone)]
+pub struct LicensePolicy {
+    /// SPDX ids of the allowed licenses, all licenses are allowed if empty
+    pub allow: Vec<String>,
+    /// SPDX ids of the denied licenses, they take precedence over `allow`
+    pub deny: Vec<String>,
+    pub unknown: UnknownLicense,
+    pub action: LicenseAction,
+}
+
+/// SPDX ids to use instead of the ones from nixpkgs, keyed by the name of the license in
+/// `lib.licenses` (e.g. `gpl2Plus`) or by its full name. Read from a JSON object with
+/// `--license-map`, it also fills in the licenses nixpkgs has no SPDX id for.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct LicenseMap(pub HashMap<String, String>);
+
+impl LicenseMap {
+    /// Replace the SPDX id of every license that is in the map, matching on the short name first
+    pub fn apply(&self, licenses: &mut [License]) {
+        for license in licenses {
+            let mapped = license
+                .short_name
+                .as_ref()
+                .and_then(|short_name| self.0.get(short_name))
+                .or_else(|| self.0.get(&license.full_name));
+            if let Some(spdx_id) = mapped {
+                license.spdx_id = Some(spdx_id.clone());
+            }
+        }
+    }
+}
+
+/// Fix the SPDX ids nixpkgs uses that are deprecated, e.g. `GPL-2.0+` becomes `GPL-2.0-or-later`
+/// and `LGPL-2.1` becomes `LGPL-2.1-only`
+pub fn normalize_spdx_id(spdx_id: &str) -> String {
+    let spdx_id = spdx_id.trim();
+    let (base, or_later) = match spdx_id.strip_suffix('+') {
+        Some(base) => (base, true),
+        None => (spdx_id, false),
+    };
+
+    match GNU_LICENSES
+        .iter()
+        .find(|gnu_license| gnu_license.eq_ignore_ascii_case(base))
+    {
+        Some(gnu_license) if or_later => format!("{}-or-later", gnu_license),
+        Some(gnu_license) => format!("{}-only", gnu_license),
+        None => spdx_id.to_owned(),
+    }
+}
+
+/// SPDX ids are case insensitive
+fn contains(spdx_ids: &[String], spdx_id: &str) -> bool {
+    spdx_ids
+        .iter()
+        .any(|listed| normalize_spdx_id(listed).eq_ignore_ascii_case(spdx_id))
+}
+
+impl LicensePolicy {
+    /// Whether a derivation with these licenses, see `NixpkgsMetadata::licenses`, is allowed
+    pub fn allows(&self, licenses: Option<&[License]>) -> bool {
+        let spdx_ids: Vec<String> = licenses
+            .into_iter()
+            .flatten()
+            .filter_map(|license| license.spdx_id.as_deref())
+            .map(normalize_spdx_id)
+            .collect();
+
+        if spdx_ids.is_empty() {
+            return self.unknown == UnknownLicense::Allow;
+        }
+        if spdx_ids.iter().any(|spdx_id| contains(&self.deny, spdx_id)) {
+            return false;
+        }
+        self.allow.is_empty()
+            || spdx_ids
+                .iter()
+                .any(|spdx_id| contains(&self.allow, spdx_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn license(spdx_id: Option<&str>) -> License {
+        License {
+            spdx_id: spdx_id.map(str::to_owned),
+            full_name: "Some license".to_owned(),
+            short_name: None,
+            free: None,
+        }
+    }
+
+    #[test]
+    fn test_license_map() {
+        let license_map: LicenseMap = serde_json::from_str(
+            r#"{"gpl2Plus": "GPL-2.0-or-later", "Unfree": "LicenseRef-Unfree"}"#,
+        )
+        .unwrap();
+        let mut licenses = [
+            License {
+                spdx_id: Some("GPL-2.0+".to_owned()),
+                full_name: "GNU General Public License v2.0 or later".to_owned(),
+                short_name: Some("gpl2Plus".to_owned()),
+                free: None,
+            },
+            License {
+                spdx_id: None,
+                full_name: "Unfree".to_owned(),
+                short_name: Some("unfree".to_owned()),
+                free: None,
+            },
+            license(Some("MIT")),
+        ];
+        license_map.apply(&mut licenses);
+
+        let spdx_ids: Vec<_> = licenses
+            .iter()
+            .map(|license| license.spdx_id.as_deref())
+            .collect();
+        assert_eq!(
+            spdx_ids,
+            [
+                Some("GPL-2.0-or-later"),
+                Some("LicenseRef-Unfree"),
+                Some("MIT")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_spdx_id() {
+        assert_eq!(normalize_spdx_id("GPL-2.0+"), "GPL-2.0-or-later");
+        assert_eq!(normalize_spdx_id("lgpl-2.1"), "LGPL-2.1-only");
+        assert_eq!(normalize_spdx_id("GPL-3.0-only"), "GPL-3.0-only");
+        assert_eq!(normalize_spdx_id(" MIT "), "MIT");
+    }
+
+    #[test]
+    fn test_allows() {
+        let policy = LicensePolicy {
+            allow: vec!["mit".to_owned(), "GPL-2.0-or-later".to_owned()],
+            deny: vec!["AGPL-3.0".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(policy.allows(Some(&[license(Some("MIT"))])));
+        assert!(policy.allows(Some(&[license(Some("GPL-2.0+"))])));
+        // Any allowed license is enough, but any denied one is too many
+        assert!(policy.allows(Some(&[license(Some("MIT")), license(Some("Zlib"))])));
+        assert!(!policy.allows(Some(&[
+            license(Some("MIT")),
+            license(Some("AGPL-3.0-only"))
+        ])));
+        assert!(!policy.allows(Some(&[license(Some("Zlib"))])));
+
+        // Licenses without an SPDX id are unknown
+        assert!(policy.allows(None));
+        assert!(policy.allows(Some(&[license(None)])));
+        let policy = LicensePolicy {
+            unknown: UnknownLicense::Deny,
+            ..policy
+        };
+        assert!(!policy.allows(None));
+        assert!(!policy.allows(Some(&[license(None)])));
+    }
+}
@@ -14,13 +14,60 @@
 //!
 //! Glossary:
 //! - output path: full path of the realization of the derivation in the Nix store.
-//!     e.g. /nix/store/py9jjqsgsya5b9cpps64gchaj8lq2h5i-python3.10-versioneer-0.28
+//!   e.g. /nix/store/py9jjqsgsya5b9cpps64gchaj8lq2h5i-python3.10-versioneer-0.28
 //! - attribute path: path from the root attribute set to get the desired value.
-//!     e.g. python3Derivations.versioneer
-use std::{error::Error, io::Write};
+//!   e.g. python3Derivations.versioneer
+use std::{
+    error::Error,
+    io::{BufRead, Write},
+    process::ExitCode,
+};
 
-use clap::Parser;
-use nixtract::{message::Message, nixtract, NixtractConfig};
+use clap::{Parser, ValueEnum};
+use nixtract::{
+    format::{self, sink::OutputSink},
+    license::{LicenseAction, LicensePolicy, UnknownLicense},
+    message::Message,
+    narinfo::NarInfoSource,
+    nixtract_builder, FlakeOutput, MetaFilter, NixpkgsAllow, NixtractConfig, RuntimeSource,
+};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// One JSON description per line, written as soon as it is available
+    Jsonl,
+    /// CycloneDX 1.5 JSON SBOM, written once all derivations are described
+    Cyclonedx,
+    /// SPDX 2.3 JSON document, written once all derivations are described
+    Spdx,
+    /// Mermaid flowchart of the dependency graph, written once all derivations are described
+    Mermaid,
+    /// Indented tree of the dependency graph to read in a terminal, written once all derivations are described
+    Tree,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    /// When stderr is a terminal, unless the output is written to it as well
+    Auto,
+    /// Whenever stderr is a terminal, even when the output is written to that terminal
+    Always,
+    Never,
+}
+
+impl Progress {
+    fn show(self, to_file: bool) -> bool {
+        use std::io::IsTerminal;
+        match self {
+            // Both would end up mangled on the same terminal
+            Progress::Auto => {
+                std::io::stderr().is_terminal() && (to_file || !std::io::stdout().is_terminal())
+            }
+            Progress::Always => true,
+            Progress::Never => false,
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -29,47 +76,186 @@ struct Args {
         short,
         long = "target-flake-ref",
         default_value = "nixpkgs",
-        help = "The flake URI to extract",
-        long_help = "The flake URI to extract, e.g. \"github:tweag/nixtract\""
+        help = "The flake URI to extract, can be repeated",
+        long_help = "The flake URI to extract, e.g. \"github:tweag/nixtract\". Can be repeated to extract several flakes, e.g. an overlay and nixpkgs, into a single output where the derivations they share are described once"
     )]
-    flake_ref: String,
+    flake_ref: Vec<String>,
 
     #[arg(
         short,
         long = "target-attribute-path",
-        help = "The attribute path to extract",
-        long_help = "The attribute path to extract, e.g. \"haskellPackages.hello\", defaults to all derivations in the flake"
+        help = "The attribute path to extract, can be repeated",
+        long_help = "The attribute path to extract, e.g. \"haskellPackages.hello\", defaults to all derivations in the flake. Can be repeated to extract several attribute paths at once"
     )]
-    attribute_path: Option<String>,
+    attribute_path: Vec<String>,
+
+    /// Flake output categories to discover derivations in, comma separated
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "packages")]
+    outputs: Vec<FlakeOutput>,
+
+    /// Stop with a non-zero exit code at the first derivation that cannot be described, instead of skipping it
+    #[arg(long, default_value_t = false)]
+    fail_fast: bool,
+
+    /// Fail the run instead of skipping what is normally only warned about: derivations that cannot be described, like with --fail-fast, narinfo that cannot be fetched, found derivations that cannot be parsed, store paths without a deriver and narinfo with unknown keys. The run then either fully succeeds or exits with an error
+    #[arg(long)]
+    strict: bool,
+
+    /// Read the attribute paths to describe from this file (`-` for stdin), one per line, skipping the discovery of derivations. Blank lines and lines starting with `#` are ignored
+    #[arg(long, conflicts_with = "attribute_path")]
+    attribute_paths_file: Option<String>,
+
+    /// Start from the derivation that produced this store path instead of the flake, can be repeated. The derivations are described from their .drv files, without their meta attributes
+    #[arg(long, value_name = "STORE_PATH", visible_alias = "seed-output-path", conflicts_with_all = ["attribute_path", "attribute_paths_file"])]
+    from_store_path: Vec<String>,
 
     #[arg(
         short,
         long = "target-system",
-        help = "The system to extract",
-        long_help = "The system to extract, e.g. \"x86_64-linux\", defaults to the host system"
+        visible_alias = "target-systems",
+        value_delimiter = ',',
+        help = "The system to extract, can be repeated, a comma separated list or \"all\"",
+        long_help = "The system to extract, e.g. \"x86_64-linux\", defaults to the host system. Can be repeated or given as a comma separated list to extract several systems at once, \"all\" extracts x86_64-linux, aarch64-linux, x86_64-darwin and aarch64-darwin"
     )]
-    system: Option<String>,
+    system: Vec<String>,
 
     /// Run nix evaluation in offline mode
     #[arg(long, default_value_t = false)]
     offline: bool,
 
+    /// Run this nix executable instead of the `nix` on the PATH, e.g. a wrapper or another implementation such as Lix. `nix-store` is run from the same directory
+    #[arg(long, value_name = "PATH")]
+    nix_binary: Option<std::path::PathBuf>,
+
+    /// Resolve the flake ref to its locked URL before extracting and evaluate everything against it, so a ref like `nixpkgs` cannot move to another revision during the run
+    #[arg(long, default_value_t = false)]
+    lock_flake_ref: bool,
+
+    /// Extra argument passed to every `nix eval` before the expression, can be repeated, e.g. `--nix-arg --override-input --nix-arg nixpkgs --nix-arg github:NixOS/nixpkgs/abc123`. Passed as is without validation, only use trusted values
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    nix_arg: Vec<String>,
+
+    /// Let nixpkgs evaluate packages with a license that is not free, pass `--allow-unfree=false` to leave them out
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    allow_unfree: bool,
+
+    /// Let nixpkgs evaluate packages with known vulnerabilities, pass `--allow-insecure=false` to leave them out
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    allow_insecure: bool,
+
+    /// Let nixpkgs evaluate packages marked as broken, pass `--allow-broken=false` to leave them out
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, value_name = "BOOL")]
+    allow_broken: bool,
+
     /// Attempt to fetch nar info from the binary cache
     #[arg(short = 'n', long, default_value_t = false)]
     include_nar_info: bool,
 
-    /// List of caches to attempt to fetch narinfo from, defaults to the substituters from nix.conf and the `extra-substituters` from provided flake.
+    /// Include the builder, arguments, environment and input derivations of the .drv file, runs `nix derivation show` for every derivation
+    #[arg(long, default_value_t = false)]
+    include_drv: bool,
+
+    /// Include the file and line every derivation is defined at, from `meta.position`
+    #[arg(long, default_value_t = false)]
+    include_meta_position: bool,
+
+    /// Include the size of realised output paths and of their closure in the local store, from `nix path-info --closure-size`. Paths that are not realised have no size
+    #[arg(long, default_value_t = false)]
+    include_store_size: bool,
+
+    /// List of caches to attempt to fetch narinfo from, defaults to the caches in the `NIXTRACT_BINARY_CACHES` environment variable, or else the substituters from nix.conf and the `extra-substituters` from provided flake.
     #[arg(short, long)]
     binary_caches: Option<Vec<String>>,
 
+    /// How often to retry fetching a narinfo from a cache after a transient failure (5xx, timeout, ...)
+    #[arg(long, default_value_t = 3)]
+    narinfo_retries: usize,
+
+    /// Send this bearer token to the binary cache at this host, as `host=token`, can be repeated
+    #[arg(long, value_parser = parse_cache_auth)]
+    cache_auth: Vec<(String, String)>,
+
+    /// Read credentials for binary caches from this netrc file, like the `netrc-file` nix setting
+    #[arg(long)]
+    netrc_file: Option<String>,
+
+    /// Give up on fetching a narinfo from a cache after this many seconds, and try the next cache.
+    /// Also bounds the query of the local store with `--narinfo-source local`
+    #[arg(long, default_value_t = 30)]
+    narinfo_timeout: u64,
+
+    /// Give up on connecting to a cache for narinfo after this many seconds, and try the next cache
+    #[arg(long, default_value_t = 10)]
+    narinfo_connect_timeout: u64,
+
+    /// Where to read narinfo from, `local` asks the local store with `nix path-info` before falling back to the binary caches
+    #[arg(long, value_enum, default_value_t = NarInfoSource::Remote)]
+    narinfo_source: NarInfoSource,
+
+    /// Send at most this many concurrent narinfo requests to each binary cache, so busy caches do not throttle or reset connections
+    #[arg(long, value_name = "N")]
+    narinfo_per_host: Option<usize>,
+
+    /// Keep fetched narinfo in this directory and read it from there on later runs, defaults to `$XDG_CACHE_HOME/nixtract/narinfo`
+    #[arg(long, value_name = "DIR")]
+    narinfo_cache_dir: Option<std::path::PathBuf>,
+
+    /// Always fetch narinfo from the binary caches, without reading or writing the narinfo cache directory
+    #[arg(long, default_value_t = false, conflicts_with = "narinfo_cache_dir")]
+    no_narinfo_cache: bool,
+
+    /// Fetch narinfo on a separate pool of this many threads, independently of --n-workers, instead of on the evaluation workers
+    #[arg(long)]
+    narinfo_concurrency: Option<usize>,
+
+    /// Describe the dependencies of every derivation with one nix evaluation per this many of them, instead of one per dependency, sparing the start up of nix and the evaluation of the flake
+    #[arg(long, value_name = "N")]
+    describe_batch: Option<usize>,
+
     /// Count of workers to spawn to describe derivations
     #[arg(long)]
     n_workers: Option<usize>,
 
-    /// Pretty print the output
+    /// Format of the output
+    #[arg(long, value_enum, default_value_t = OutputFormat::Jsonl)]
+    format: OutputFormat,
+
+    /// When to display the progress bars on stderr. With `auto`, they are shown when writing to a file or piping the output to another program
+    #[arg(long, value_enum, default_value_t = Progress::Auto)]
+    progress: Progress,
+
+    /// Flush the output after every description, the default when writing to stdout. Output files are buffered otherwise
+    #[arg(long, default_value_t = false)]
+    flush_per_record: bool,
+
+    /// Compress the output with gzip, the default when the output path ends in `.gz`
+    #[arg(long, default_value_t = false)]
+    compress: bool,
+
+    /// Append to the output file instead of truncating it, so several runs can accumulate into one file. Compressed output is appended as another gzip member, which `gzip -d` reads as a whole
+    #[arg(long, default_value_t = false)]
+    append: bool,
+
+    /// Indent the JSON output of every format, the manifest and the dry run. JSONL records then span several lines, which `--validate` and tools reading line by line cannot read
     #[arg(long, default_value_t = false)]
     pretty: bool,
 
+    /// Sort the descriptions by attribute path, then output path, so the outputs of two runs can be diffed. Nothing is written until all derivations are described
+    #[arg(long, default_value_t = false)]
+    sort: bool,
+
+    /// Only count the dependencies that were already described instead of logging each of them
+    #[arg(long, default_value_t = false)]
+    quiet_skipped: bool,
+
+    /// Skip dependencies without an output path instead of warning about them and describing them, they cannot be deduplicated
+    #[arg(long, default_value_t = false)]
+    skip_null_output_paths: bool,
+
+    /// Write the inputs and environment of the run to this JSON file once it finishes, including the resolved revision of the flake
+    #[arg(long)]
+    manifest: Option<String>,
+
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 
@@ -77,10 +263,94 @@ struct Args {
     #[arg(long, default_value_t = false)]
     output_schema: bool,
 
+    /// Check an existing JSONL output instead of extracting: every line should be a description and every build input with an output path should be described. Exits with an error listing the dangling references otherwise
+    #[arg(long, value_name = "FILE", conflicts_with = "output_schema")]
+    validate: Option<String>,
+
+    /// Extract this flake ref as well and only output the derivations that differ from it, as JSONL records of the attribute paths that were added, removed or whose output path changed. Nothing is written until both are extracted. Requires a single --target-flake-ref
+    #[arg(
+        long,
+        value_name = "FLAKE_REF",
+        conflicts_with_all = ["resume_from", "checkpoint", "dry_run", "sort"]
+    )]
+    diff_against: Option<String>,
+
+    /// Only list the top-level derivations that would be described, as JSONL, without describing them
+    #[arg(long, visible_alias = "list-only", default_value_t = false)]
+    dry_run: bool,
+
     /// Ouput only runtime dependencies
     #[arg(long, short, default_value_t = false)]
     runtime_only: bool,
 
+    /// Where to take runtime dependencies from with --runtime-only, `store` requires the outputs to be realised locally or in a binary cache
+    #[arg(long, value_enum, default_value_t = RuntimeSource::Eval)]
+    runtime_source: RuntimeSource,
+
+    /// Only explore dependencies up to this depth, 0 only describes the top-level derivations
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Only describe the top-level derivations, with their direct build inputs listed but not described. Same as `--max-depth 0`
+    #[arg(long, default_value_t = false, conflicts_with = "max_depth")]
+    root_only: bool,
+
+    /// Do not describe derivations whose attribute path matches this regular expression, can be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+
+    /// Only keep derivations with at least one of these licenses, as SPDX ids, can be repeated
+    #[arg(long)]
+    license_allow: Vec<String>,
+
+    /// Drop derivations with any of these licenses, as SPDX ids, can be repeated
+    #[arg(long)]
+    license_deny: Vec<String>,
+
+    /// Whether derivations without any license with an SPDX id pass --license-allow and --license-deny
+    #[arg(long, value_enum, default_value_t = UnknownLicense::Allow)]
+    license_unknown: UnknownLicense,
+
+    /// What to do with derivations whose licenses are not allowed, `flag` keeps them but exits with an error
+    #[arg(long, value_enum, default_value_t = LicenseAction::Drop)]
+    license_action: LicenseAction,
+
+    /// Only output derivations marked as broken, their dependencies are still explored. Combined with --only-unfree or --only-insecure, derivations matching any of them are output
+    #[arg(long, default_value_t = false)]
+    only_broken: bool,
+
+    /// Only output derivations with a license that is not free, their dependencies are still explored
+    #[arg(long, default_value_t = false)]
+    only_unfree: bool,
+
+    /// Only output derivations with known vulnerabilities, listed in `known_vulnerabilities`, their dependencies are still explored
+    #[arg(long, visible_alias = "only-vulnerable", default_value_t = false)]
+    only_insecure: bool,
+
+    /// JSON object mapping nixpkgs license names (e.g. `gpl2Plus`) or full names to the SPDX id to use instead, applied before --license-allow and --license-deny
+    #[arg(long, value_name = "FILE")]
+    license_map: Option<String>,
+
+    /// Append the output path of every description once it is written to the output to this file, see --resume-from
+    #[arg(long)]
+    checkpoint: Option<String>,
+
+    /// Do not write the output paths listed in this checkpoint file, written by an interrupted run, again and keep appending to it. Their dependencies are still explored. The output file is appended to instead of truncated
+    #[arg(long)]
+    resume_from: Option<String>,
+
+    /// Give up on describing a derivation if its evaluation takes longer than this many seconds
+    #[arg(long)]
+    eval_timeout: Option<u64>,
+
+    /// Stop the whole run, including finding the derivations, after this many seconds. The derivations described so far are still written and nixtract exits with code 124
+    #[arg(long, value_name = "SECONDS")]
+    timeout_total: Option<u64>,
+
+    /// Limit the memory of every `nix eval` to this size, e.g. `4G`, so a runaway evaluation fails on its own instead of exhausting the memory of the machine. Accepts K, M and G suffixes (powers of 1024), Linux only
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_size)]
+    max_eval_memory: Option<u64>,
+
     /// Write the output to a file instead of stdout or explicitly use `-` for stdout
     #[arg()]
     output_path: Option<String>,
@@ -90,87 +360,399 @@ impl From<&Args> for NixtractConfig {
     fn from(args: &Args) -> Self {
         NixtractConfig {
             offline: args.offline,
+            nix_binary: args.nix_binary.clone(),
+            lock_flake_ref: args.lock_flake_ref,
+            nix_args: args.nix_arg.clone(),
+            nixpkgs_allow: NixpkgsAllow {
+                unfree: args.allow_unfree,
+                insecure: args.allow_insecure,
+                broken: args.allow_broken,
+            },
             include_nar_info: args.include_nar_info,
+            include_drv: args.include_drv,
+            include_meta_position: args.include_meta_position,
+            include_store_size: args.include_store_size,
             runtime_only: args.runtime_only,
+            flake_outputs: args.outputs.clone(),
+            fail_fast: args.fail_fast,
+            strict: args.strict,
+            skip_discovery: args.attribute_paths_file.is_some(),
+            runtime_source: args.runtime_source,
+            max_depth: if args.root_only {
+                Some(0)
+            } else {
+                args.max_depth
+            },
+            exclude: args.exclude.clone(),
+            completed_paths: Vec::new(),
+            store_paths: args.from_store_path.clone(),
+            license_policy: (!args.license_allow.is_empty()
+                || !args.license_deny.is_empty()
+                || args.license_unknown == UnknownLicense::Deny)
+                .then(|| LicensePolicy {
+                    allow: args.license_allow.clone(),
+                    deny: args.license_deny.clone(),
+                    unknown: args.license_unknown,
+                    action: args.license_action,
+                }),
+            only: MetaFilter {
+                broken: args.only_broken,
+                unfree: args.only_unfree,
+                insecure: args.only_insecure,
+            },
+            // Read in main_with_args, reading the file can fail
+            license_map: None,
+            descend_filter: None,
+            eval_timeout: args.eval_timeout.map(std::time::Duration::from_secs),
+            max_eval_memory: args.max_eval_memory,
             binary_caches: args.binary_caches.clone(),
+            narinfo_fetch: nixtract::narinfo::FetchConfig {
+                source: args.narinfo_source,
+                retries: args.narinfo_retries,
+                timeout: std::time::Duration::from_secs(args.narinfo_timeout),
+                connect_timeout: std::time::Duration::from_secs(args.narinfo_connect_timeout),
+                credentials: args
+                    .cache_auth
+                    .iter()
+                    .map(|(host, token)| {
+                        (
+                            host.clone(),
+                            nixtract::netrc::Credentials::Bearer(token.clone()),
+                        )
+                    })
+                    .collect(),
+                per_host: nixtract::narinfo::HostLimit::new(args.narinfo_per_host),
+                nix_binary: args.nix_binary.clone(),
+                offline: args.offline,
+                strict: args.strict,
+                cache_dir: if args.no_narinfo_cache {
+                    None
+                } else {
+                    args.narinfo_cache_dir
+                        .clone()
+                        .or_else(nixtract::narinfo::default_cache_dir)
+                },
+                ..Default::default()
+            },
+            narinfo_concurrency: args.narinfo_concurrency,
+            n_workers: args.n_workers,
             message_tx: None,
+            quiet_skipped: args.quiet_skipped,
+            skip_null_output_paths: args.skip_null_output_paths,
+            describe_batch: args.describe_batch,
+            stats: None,
+            cancellation: None,
+            timeout_total: args.timeout_total.map(std::time::Duration::from_secs),
         }
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+/// Errors of nixtract exit with the code of their category, see [`nixtract::error::Error::exit_code`]
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            match e.downcast_ref::<nixtract::error::Error>() {
+                Some(e) => ExitCode::from(e.exit_code()),
+                None => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn Error>> {
     let opts: Args = Args::parse();
 
+    // Validating only reads, nothing is extracted or written
+    if let Some(path) = opts.validate.as_deref() {
+        return validate(path);
+    }
+
+    // The other formats are a single document, a second one after it makes the file invalid
+    if opts.append && opts.format != OutputFormat::Jsonl {
+        return Err("--append requires the jsonl format".into());
+    }
+    if opts.diff_against.is_some() && opts.format != OutputFormat::Jsonl {
+        return Err("--diff-against requires the jsonl format".into());
+    }
+    // The flake it is compared to replaces all of them, which one it stands for is ambiguous
+    if opts.diff_against.is_some() && opts.flake_ref.len() > 1 {
+        return Err("--diff-against requires a single --target-flake-ref".into());
+    }
+
     // Create the out writer
-    let (mut out_writer, to_file) = match opts.output_path.as_deref() {
+    let (writer, to_file) = match opts.output_path.as_deref() {
         None | Some("-") => (
             Box::new(std::io::stdout()) as Box<dyn std::io::Write>,
             false,
         ),
         Some(path) => {
-            let file = std::fs::File::create(path)?;
-            (Box::new(file) as Box<dyn std::io::Write>, true)
+            // Descriptions of the interrupted run are kept, only the missing ones are added
+            let file = if opts.append || opts.resume_from.is_some() {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?
+            } else {
+                std::fs::File::create(path)?
+            };
+            (
+                Box::new(std::io::BufWriter::new(file)) as Box<dyn std::io::Write>,
+                true,
+            )
         }
     };
 
+    let compress = opts.compress
+        || opts
+            .output_path
+            .as_deref()
+            .is_some_and(|path| path.ends_with(".gz"));
+    let mut out_writer = if compress {
+        OutWriter::Gzip(flate2::write::GzEncoder::new(
+            writer,
+            flate2::Compression::default(),
+        ))
+    } else {
+        OutWriter::Plain(writer)
+    };
+
     // If schema is requested, print the schema and return
     if opts.output_schema {
         let schema = schemars::schema_for!(nixtract::DerivationDescription);
         let schema_string = serde_json::to_string_pretty(&schema)?;
         out_writer.write_all(schema_string.as_bytes())?;
         out_writer.write_all(b"\n")?;
-        Ok(())
+    } else if let Err(e) = main_with_args(opts, &mut out_writer, to_file) {
+        // What was described before the deadline is kept
+        if let Some(nixtract::error::Error::TimeoutTotal(_)) = e.downcast_ref() {
+            out_writer.finish()?;
+        }
+        return Err(e);
+    }
+
+    out_writer.finish()?;
+    Ok(())
+}
+
+/// Extract the flake of `builder` and `diff_against` with the same configuration, and write the
+/// derivations that differ between them as JSONL, `diff_against` being the old side
+fn write_diff(
+    builder: nixtract::NixtractBuilder,
+    diff_against: String,
+    mut out_writer: impl Write,
+    pretty: bool,
+) -> Result<(), Box<dyn Error>> {
+    let collect = |builder: nixtract::NixtractBuilder| -> Result<Vec<_>, Box<dyn Error>> {
+        let mut results = builder.run()?;
+        let descriptions = results.by_ref().collect();
+        // A partial extraction would report everything it missed as removed or added
+        if let Some(e) = results.error() {
+            return Err(e.into());
+        }
+        Ok(descriptions)
+    };
+    let old = collect(builder.clone().flake_ref(diff_against))?;
+    let new = collect(builder)?;
+
+    for change in nixtract::diff::diff(old, new) {
+        format::sink::write_json(&mut out_writer, &change, pretty)?;
+    }
+    out_writer.flush()?;
+    Ok(())
+}
+
+/// Check the output at `path`, decompressing it if it ends in `.gz` like the output is compressed.
+/// Appended runs are separate gzip members, all of them are read
+fn validate(path: &str) -> Result<(), Box<dyn Error>> {
+    let file = std::fs::File::open(path)?;
+    let reader: Box<dyn BufRead> = if path.ends_with(".gz") {
+        Box::new(std::io::BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        )))
     } else {
-        main_with_args(opts, out_writer, to_file)
+        Box::new(std::io::BufReader::new(file))
+    };
+
+    let validation = nixtract::validate::validate(reader)?;
+    println!("{}", validation);
+    if !validation.is_valid() {
+        return Err(format!("{} is incomplete", path).into());
+    }
+    Ok(())
+}
+
+/// Where the output is written, compressed with gzip if requested
+enum OutWriter {
+    Plain(Box<dyn Write>),
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+}
+
+impl OutWriter {
+    /// Flush the output, writing the gzip trailer without which the compressed output is truncated
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            OutWriter::Plain(mut writer) => writer.flush(),
+            OutWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutWriter::Plain(writer) => writer.write(buf),
+            OutWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutWriter::Plain(writer) => writer.flush(),
+            OutWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Parse a size of `--max-eval-memory`, in bytes unless suffixed with K, M or G
+fn parse_memory_size(value: &str) -> Result<u64, String> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&value[..index], 1024),
+        Some((index, 'M' | 'm')) => (&value[..index], 1024 * 1024),
+        Some((index, 'G' | 'g')) => (&value[..index], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("expected a size such as 4G or 512M, got `{}`", value))
+}
+
+/// Parse a `host=token` pair of `--cache-auth`
+fn parse_cache_auth(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((host, token)) if !host.is_empty() && !token.is_empty() => {
+            Ok((host.to_owned(), token.to_owned()))
+        }
+        _ => Err("expected `host=token`".to_owned()),
     }
 }
 
+/// The inputs and environment of a run, written with --manifest so the output can be tied to the
+/// exact flake and settings it was produced from
+#[derive(serde::Serialize)]
+struct RunManifest {
+    nixtract_version: &'static str,
+    /// The first flake that was extracted
+    #[serde(flatten)]
+    flake: ManifestFlake,
+    /// The other flakes of a run extracting several of them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_flakes: Vec<ManifestFlake>,
+    /// The systems that were extracted, empty for the host system
+    systems: Vec<String>,
+    attribute_paths: Vec<String>,
+    substituters: Vec<String>,
+    n_workers: usize,
+    started_at: String,
+    finished_at: String,
+}
+
+#[derive(serde::Serialize)]
+struct ManifestFlake {
+    flake_ref: String,
+    /// What `flake_ref` resolved to, e.g. the locked revision of the flake
+    flake: nixtract::flake_metadata::FlakeMetadata,
+}
+
+fn timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Write the manifest with the current time as the end of the run
+fn write_manifest(
+    path: &str,
+    mut manifest: RunManifest,
+    pretty: bool,
+) -> Result<(), Box<dyn Error>> {
+    manifest.finished_at = timestamp(chrono::Utc::now());
+    let file = std::fs::File::create(path)?;
+    format::sink::write_json(file, &manifest, pretty)?;
+    Ok(())
+}
+
+/// Read newline separated attribute paths, ignoring blank lines and `#` comments
+fn read_attribute_paths(reader: impl BufRead) -> std::io::Result<Vec<String>> {
+    let mut attribute_paths = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            attribute_paths.push(line.to_owned());
+        }
+    }
+    Ok(attribute_paths)
+}
+
 fn main_with_args(
     opts: Args,
     mut out_writer: impl Write,
     to_file: bool,
 ) -> Result<(), Box<dyn Error>> {
-    // Initialize the rayon thread pool with the provided number of workers
-    // or use the default number of workers if none is provided
-    if let Some(n_workers) = opts.n_workers {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(n_workers)
-            .build_global()?;
-    }
+    let started_at = chrono::Utc::now();
+
+    // The workers run on a pool of nixtract, of this size
+    let n_workers = opts.n_workers.unwrap_or_else(rayon::current_num_threads);
 
     let (status_tx, status_rx): (
         std::sync::mpsc::Sender<Message>,
         std::sync::mpsc::Receiver<Message>,
     ) = std::sync::mpsc::channel();
 
-    // Initialize the logger if not writing to a file, otherwise we defer it to after we created the MultiProcess
+    // Initialize the logger if not displaying progress, otherwise we defer it to after we created the MultiProcess
+    let show_progress = opts.progress.show(to_file);
     let mut log_builder = env_logger::Builder::new();
     log_builder.filter_level(opts.verbose.log_level_filter());
-    if !to_file {
+    if !show_progress {
         // Initialize the logger with the provided verbosity
         let _ = log_builder.try_init();
     }
 
-    // If we are outputing to a file and not stdout, start a gui thread that uses indicatif to display progress
-    // If we would always start a MultiProgress progress bar, the output would be mangled by the output we write to stdout ourselves.
-    let handle = if to_file {
+    // Start a gui thread that uses indicatif to display progress on stderr, by default only if it
+    // does not share a terminal with the output, which would mangle both
+    let handle = if show_progress {
         let spinner_style =
             indicatif::ProgressStyle::with_template("{prefix:.bold.dim} {spinner} {wide_msg}")?
                 .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+        let failure_style = indicatif::ProgressStyle::with_template("{wide_msg:.red}")?;
+        let total_style = indicatif::ProgressStyle::with_template(
+            "{pos}/{len} [{elapsed_precise}, eta {eta}] {wide_bar}",
+        )?;
 
-        let multi = indicatif::MultiProgress::new();
+        let multi =
+            indicatif::MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::stderr());
         let logger = log_builder.build();
         let _ = indicatif_log_bridge::LogWrapper::new(multi.clone(), logger).try_init();
 
         Some(std::thread::spawn(move || {
-            // Create a progress bar for rayon thread in the global thread pool
+            // Create a progress bar for every worker
             let mut progress_bars = Vec::new();
-            for _ in 0..rayon::current_num_threads() {
+            for _ in 0..n_workers {
                 let pb = multi.add(indicatif::ProgressBar::new(0));
                 pb.set_style(spinner_style.clone());
                 progress_bars.push(pb);
             }
 
+            // Overall progress, the total grows as dependencies are discovered
+            let total = multi.add(indicatif::ProgressBar::new(0));
+            total.set_style(total_style);
+
+            // Counts the derivations that could not be described, only shown once one fails
+            let failures = multi.add(indicatif::ProgressBar::new(0));
+            failures.set_style(failure_style);
+
             for message in status_rx {
                 match message.status {
                     nixtract::message::Status::Started => {
@@ -181,9 +763,37 @@ fn main_with_args(
                         progress_bars[message.id]
                             .set_message(format!("Processed {}", message.path));
                         progress_bars[message.id].inc(1);
+                        total.inc(1);
                     }
                     nixtract::message::Status::Skipped => {
                         progress_bars[message.id].set_message(format!("Skipped {}", message.path));
+                        total.inc(1);
+                    }
+                    nixtract::message::Status::Excluded => {
+                        progress_bars[message.id].set_message(format!("Excluded {}", message.path));
+                        total.inc(1);
+                    }
+                    nixtract::message::Status::MaxDepthReached => {
+                        progress_bars[message.id]
+                            .set_message(format!("Reached maximum depth at {}", message.path));
+                    }
+                    nixtract::message::Status::Failed { error } => {
+                        progress_bars[message.id]
+                            .set_message(format!("Failed {}: {}", message.path, error));
+                        total.inc(1);
+                        failures.inc(1);
+                        failures.set_message(format!(
+                            "{} derivation(s) could not be described",
+                            failures.position()
+                        ));
+                    }
+                    nixtract::message::Status::Cycle => {
+                        progress_bars[message.id]
+                            .set_message(format!("Dependency cycle at {}", message.path));
+                        total.inc(1);
+                    }
+                    nixtract::message::Status::Queued { count } => {
+                        total.inc_length(count as u64);
                     }
                 }
             }
@@ -192,35 +802,226 @@ fn main_with_args(
                 pb.finish();
             }
 
+            if failures.position() > 0 {
+                log::warn!(
+                    "{} derivation(s) could not be described",
+                    failures.position()
+                );
+            }
+            failures.finish();
+            total.finish();
+
             multi.clear().expect("Failed to clear the progress bar");
         }))
     } else {
         None
     };
 
-    let config = NixtractConfig {
+    let stats = nixtract::stats::SharedStats::default();
+    let mut config = NixtractConfig {
         message_tx: Some(status_tx),
+        stats: Some(stats.clone()),
         ..(&opts).into()
     };
 
-    let results = nixtract(opts.flake_ref, opts.system, opts.attribute_path, config)?;
+    if let Some(license_map) = &opts.license_map {
+        config.license_map = Some(serde_json::from_str(&std::fs::read_to_string(
+            license_map,
+        )?)?);
+    }
 
-    // Print the results to the provided output, and pretty print if specified
-    for result in results {
-        let output = if opts.pretty {
-            serde_json::to_string_pretty(&result)?
-        } else {
-            serde_json::to_string(&result)?
+    // Tokens given with --cache-auth take precedence over the netrc file
+    if let Some(netrc_file) = &opts.netrc_file {
+        for (host, credentials) in nixtract::netrc::parse(&std::fs::read_to_string(netrc_file)?) {
+            config
+                .narinfo_fetch
+                .credentials
+                .entry(host)
+                .or_insert(credentials);
+        }
+    }
+
+    let attribute_paths = match opts.attribute_paths_file.as_deref() {
+        None => opts.attribute_path,
+        Some("-") => read_attribute_paths(std::io::stdin().lock())?,
+        Some(path) => read_attribute_paths(std::io::BufReader::new(std::fs::File::open(path)?))?,
+    };
+
+    // A missing checkpoint file means nothing was described yet, so the same command can be
+    // used for the first run and to resume it
+    let completed_paths = match opts.resume_from.as_deref() {
+        None => Vec::new(),
+        Some(path) => match std::fs::File::open(path) {
+            Ok(file) => read_attribute_paths(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    let checkpoint = match opts.checkpoint.as_deref().or(opts.resume_from.as_deref()) {
+        None => None,
+        // Records are only complete once all derivations are described in the other formats
+        Some(_) if opts.format != OutputFormat::Jsonl => {
+            return Err("--checkpoint and --resume-from require the jsonl format".into())
+        }
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+    };
+
+    let systems: Vec<String> = opts
+        .system
+        .into_iter()
+        .flat_map(|system| {
+            if system == "all" {
+                nixtract::SUPPORTED_SYSTEMS.map(str::to_owned).to_vec()
+            } else {
+                vec![system]
+            }
+        })
+        .collect();
+
+    // Resolve the flake and the substituters up front, so the manifest records exactly what the
+    // run used
+    let manifest = match &opts.manifest {
+        None => None,
+        Some(_) => {
+            let substituters = match &config.binary_caches {
+                Some(caches) => caches.clone(),
+                None => nixtract::substituters::get_flakes_substituters(
+                    &opts.flake_ref,
+                    config.nix_binary.as_deref(),
+                )?,
+            };
+            config.binary_caches = Some(substituters.clone());
+            let mut flakes = Vec::new();
+            for flake_ref in &opts.flake_ref {
+                flakes.push(ManifestFlake {
+                    flake_ref: flake_ref.clone(),
+                    flake: nixtract::flake_metadata::FlakeMetadata::get(
+                        flake_ref,
+                        config.offline,
+                        config.nix_binary.as_deref(),
+                    )?,
+                });
+            }
+            // There is always a flake, clap defaults to nixpkgs
+            let flake = flakes.remove(0);
+            Some(RunManifest {
+                nixtract_version: env!("CARGO_PKG_VERSION"),
+                flake,
+                extra_flakes: flakes,
+                systems: systems.clone(),
+                attribute_paths: attribute_paths.clone(),
+                substituters,
+                n_workers,
+                started_at: timestamp(started_at),
+                finished_at: String::new(),
+            })
+        }
+    };
+
+    // The SBOM of several flakes is named after all of them
+    let flake_ref = opts.flake_ref.join(" ");
+    let builder = nixtract_builder()
+        .flake_refs(opts.flake_ref)
+        .systems(systems)
+        .attribute_paths(attribute_paths)
+        .config(config)
+        .completed_paths(completed_paths);
+
+    // Only list what would be described, one JSON object per line
+    if opts.dry_run {
+        for found_drv in builder.discover()? {
+            let found_drv = found_drv?;
+            format::sink::write_json(&mut out_writer, &found_drv, opts.pretty)?;
+        }
+        out_writer.flush()?;
+
+        if let Some(handle) = handle {
+            handle.join().expect("Failed to join the gui thread");
+        }
+        if let (Some(path), Some(manifest)) = (&opts.manifest, manifest) {
+            write_manifest(path, manifest, opts.pretty)?;
+        }
+        return Ok(());
+    }
+
+    // Both extractions are needed before anything can be compared
+    if let Some(diff_against) = opts.diff_against {
+        write_diff(builder, diff_against, &mut out_writer, opts.pretty)?;
+    } else {
+        // Consumers reading from a pipe should see every description as soon as it is available
+        let flush_per_record = opts.flush_per_record || !to_file;
+
+        let mut results = builder.run()?;
+
+        let mut sink: Box<dyn OutputSink + '_> = match opts.format {
+            OutputFormat::Jsonl => {
+                let sink = format::sink::Jsonl::new(&mut out_writer, opts.pretty, flush_per_record);
+                match checkpoint {
+                    Some(checkpoint) => Box::new(sink.checkpoint(checkpoint)),
+                    None => Box::new(sink),
+                }
+            }
+            OutputFormat::Cyclonedx => format::sink::cyclonedx(&mut out_writer, opts.pretty),
+            OutputFormat::Spdx => format::sink::spdx(&mut out_writer, flake_ref, opts.pretty),
+            OutputFormat::Mermaid => format::sink::mermaid(&mut out_writer),
+            OutputFormat::Tree => format::sink::tree(&mut out_writer),
         };
 
-        out_writer.write_all(output.as_bytes())?;
-        out_writer.write_all(b"\n")?;
+        sink.begin()?;
+        if opts.sort {
+            // Workers finish in any order, only the whole set can be sorted
+            let mut descriptions: Vec<_> = results.by_ref().collect();
+            descriptions.sort_by(|a, b| {
+                (&a.attribute_path, &a.output_path).cmp(&(&b.attribute_path, &b.output_path))
+            });
+            for description in &descriptions {
+                sink.emit(description)?;
+            }
+        } else {
+            for result in results.by_ref() {
+                sink.emit(&result)?;
+            }
+        }
+        // An incomplete SBOM or graph is worse than none, but JSONL cut short is still valid
+        if let Some(e) = results.error() {
+            if matches!(e, nixtract::error::Error::TimeoutTotal(_))
+                && opts.format == OutputFormat::Jsonl
+            {
+                sink.finish()?;
+            }
+            return Err(e.into());
+        }
+        sink.finish()?;
+        drop(sink);
     }
 
     if let Some(handle) = handle {
         handle.join().expect("Failed to join the gui thread");
     }
 
+    // All workers are done once the results are exhausted, so the counts are final
+    let stats = stats.lock().unwrap();
+    eprintln!("Summary: {}", stats);
+
+    if let (Some(path), Some(manifest)) = (&opts.manifest, manifest) {
+        write_manifest(path, manifest, opts.pretty)?;
+    }
+
+    // Flagged derivations are in the output, but should still fail a CI run
+    if opts.license_action == LicenseAction::Flag && stats.license_violations > 0 {
+        return Err(format!(
+            "{} derivation(s) have a license that is not allowed",
+            stats.license_violations
+        )
+        .into());
+    }
+
     Ok(())
 }
 
@@ -244,19 +1045,73 @@ mod tests {
             if path.is_dir() {
                 // Create the Opts for the main_with_args function
                 let opts = Args {
-                    flake_ref: path.to_str().unwrap().to_string(),
-                    attribute_path: Option::default(),
-                    system: Option::default(),
+                    flake_ref: vec![path.to_str().unwrap().to_string()],
+                    attribute_path: Vec::default(),
+                    attribute_paths_file: None,
+                    from_store_path: Vec::new(),
+                    fail_fast: false,
+                    strict: false,
+                    outputs: vec![FlakeOutput::Packages],
+                    system: Vec::default(),
                     offline: bool::default(),
+                    nix_binary: None,
+                    lock_flake_ref: false,
+                    nix_arg: Vec::new(),
+                    allow_unfree: true,
+                    allow_insecure: true,
+                    allow_broken: true,
+                    describe_batch: None,
                     n_workers: Option::default(),
+                    format: OutputFormat::Jsonl,
+                    progress: Progress::Never,
+                    flush_per_record: false,
+                    compress: false,
+                    append: false,
                     pretty: bool::default(),
+                    sort: false,
+                    quiet_skipped: false,
+                    skip_null_output_paths: false,
                     verbose: clap_verbosity_flag::Verbosity::default(),
                     output_schema: bool::default(),
+                    validate: None,
+                    diff_against: None,
+                    dry_run: false,
+                    manifest: None,
                     // Write output to /dev/null to avoid cluttering the test output
                     output_path: Some("/dev/null".to_string()),
                     include_nar_info: false,
+                    include_drv: false,
+                    include_meta_position: false,
+                    include_store_size: false,
                     runtime_only: false,
+                    runtime_source: RuntimeSource::Eval,
+                    max_depth: None,
+                    root_only: false,
+                    exclude: Vec::new(),
+                    license_allow: Vec::new(),
+                    license_deny: Vec::new(),
+                    license_unknown: UnknownLicense::Allow,
+                    license_action: LicenseAction::Drop,
+                    only_broken: false,
+                    only_unfree: false,
+                    only_insecure: false,
+                    license_map: None,
+                    checkpoint: None,
+                    resume_from: None,
+                    eval_timeout: None,
+                    timeout_total: None,
+                    max_eval_memory: None,
                     binary_caches: None,
+                    narinfo_retries: 3,
+                    cache_auth: Vec::new(),
+                    netrc_file: None,
+                    narinfo_timeout: 30,
+                    narinfo_connect_timeout: 10,
+                    narinfo_per_host: None,
+                    narinfo_cache_dir: None,
+                    no_narinfo_cache: true,
+                    narinfo_source: NarInfoSource::Remote,
+                    narinfo_concurrency: None,
                 };
 
                 log::info!("Running test for {:?}", path);
@@ -276,4 +1131,46 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1024"), Ok(1024));
+        assert_eq!(parse_memory_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memory_size("4g"), Ok(4 * 1024 * 1024 * 1024));
+        assert!(parse_memory_size("G").is_err());
+        assert!(parse_memory_size("4GB").is_err());
+    }
+
+    #[test]
+    fn test_read_attribute_paths() {
+        let input = "hello\n\n# a comment\n  haskellPackages.hello  \n";
+        let attribute_paths = read_attribute_paths(input.as_bytes()).unwrap();
+        assert_eq!(attribute_paths, vec!["hello", "haskellPackages.hello"]);
+    }
+
+    #[test]
+    fn test_systems() {
+        let args = Args::try_parse_from([
+            "nixtract",
+            "--target-systems",
+            "x86_64-linux,aarch64-linux",
+            "-s",
+            "x86_64-darwin",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.system,
+            vec!["x86_64-linux", "aarch64-linux", "x86_64-darwin"]
+        );
+    }
+
+    #[test]
+    fn test_flake_refs() {
+        let args = Args::try_parse_from(["nixtract"]).unwrap();
+        assert_eq!(args.flake_ref, vec!["nixpkgs"]);
+
+        let args =
+            Args::try_parse_from(["nixtract", "-f", "github:me/overlay", "-f", "nixpkgs"]).unwrap();
+        assert_eq!(args.flake_ref, vec!["github:me/overlay", "nixpkgs"]);
+    }
 }
@@ -10,6 +10,25 @@ pub enum Status {
     Started,
     Completed,
     Skipped,
+    /// The derivation was described, but its dependencies were not explored
+    /// because the maximum depth was reached
+    MaxDepthReached,
+    /// The derivation matched one of the exclude patterns and was not described, or its
+    /// licenses are not allowed or it is not broken, unfree or insecure as requested and it was
+    /// dropped
+    Excluded,
+    /// The derivation could not be described
+    Failed {
+        error: String,
+    },
+    /// The derivation depends on itself through the derivations it was reached through, its
+    /// dependencies are not explored again
+    Cycle,
+    /// This many more derivations were found and will each get a `Completed`, `Skipped`,
+    /// `Excluded`, `Failed` or `Cycle` status, used to show the total amount of work
+    Queued {
+        count: usize,
+    },
 }
 
 /// Combine the struct and enum into a new Message struct
@@ -27,6 +46,11 @@ impl fmt::Display for Status {
             Status::Started => write!(f, "started"),
             Status::Completed => write!(f, "completed"),
             Status::Skipped => write!(f, "skipped"),
+            Status::MaxDepthReached => write!(f, "max depth reached"),
+            Status::Excluded => write!(f, "excluded"),
+            Status::Failed { error } => write!(f, "failed ({})", error),
+            Status::Cycle => write!(f, "dependency cycle"),
+            Status::Queued { count } => write!(f, "queued {} more", count),
         }
     }
 }
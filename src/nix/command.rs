@@ -0,0 +1,543 @@
+//! Helpers to run the nix subprocesses
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::time::Duration;
+
+use wait_timeout::ChildExt;
+
+use crate::error::{Error, Result};
+
+/// A line nix wrote to stderr
+#[derive(Debug, PartialEq, Eq)]
+pub enum StderrLine<'a> {
+    /// Output of `builtins.trace`, without the `trace: ` prefix
+    Trace(&'a str),
+    /// A warning printed by nix, without the `warning: ` or `evaluation warning: ` prefix
+    Warning(&'a str),
+    /// Anything else, such as error messages
+    Other(&'a str),
+}
+
+impl<'a> StderrLine<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        if let Some(trace) = line.strip_prefix("trace: ") {
+            StderrLine::Trace(trace)
+        } else if let Some(warning) = line
+            .strip_prefix("warning: ")
+            .or_else(|| line.strip_prefix("evaluation warning: "))
+        {
+            StderrLine::Warning(warning)
+        } else {
+            StderrLine::Other(line)
+        }
+    }
+}
+
+/// Messages of nix that mean the flake itself could not be found
+const FLAKE_NOT_FOUND: [&str; 2] = ["cannot find flake", "does not contain a 'flake.nix'"];
+
+/// Contexts nix prints while fetching a flake, as opposed to what the evaluation fetches
+const FLAKE_FETCH_CONTEXT: [&str; 2] = ["while fetching the input", "the 'getFlake' builtin"];
+
+/// Whether the download of the flake itself was not found. The innermost context nix printed
+/// before the 404 tells it apart from a missing `builtins.fetchurl` during the evaluation, nix
+/// prints no context at all when it fails before evaluating anything.
+fn flake_download_not_found(stderr: &str) -> bool {
+    let Some(position) = stderr.find("HTTP error 404") else {
+        return false;
+    };
+    match stderr[..position]
+        .lines()
+        .rfind(|line| line.trim_start().starts_with("… while"))
+    {
+        None => true,
+        Some(context) => FLAKE_FETCH_CONTEXT
+            .iter()
+            .any(|flake_fetch| context.contains(flake_fetch)),
+    }
+}
+
+/// Messages of nix, and of curl underneath it, that mean something could not be downloaded
+const FETCH_FAILED: [&str; 6] = [
+    "unable to download",
+    "offline mode",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "Failed to connect",
+    "Timeout was reached",
+];
+
+/// Messages of nix that mean the evaluation itself failed
+const EVALUATION_ERROR: [&str; 7] = [
+    "' missing",
+    "out of bounds",
+    "while evaluating",
+    "evaluation aborted",
+    "infinite recursion",
+    "undefined variable",
+    "assertion",
+];
+
+/// Source of the lookups of `getValueAtPath` in `lib.nix`, which nix shows below the error when
+/// it fails to follow the requested attribute path
+const ATTRIBUTE_PATH_LOOKUP: [&str; 2] = ["collection.${x}", "builtins.elemAt collection index"];
+
+/// Whether nix reports an attribute of the requested attribute path as missing, as opposed to an
+/// attribute missing somewhere inside the evaluation of the derivation. Nix stops at the first
+/// missing component of the path, so only the error itself, the last one it printed, is looked at.
+fn attribute_path_missing(stderr: &str, attribute_path: &str) -> bool {
+    let error = stderr.rsplit("error: ").next().unwrap_or_default();
+    if !ATTRIBUTE_PATH_LOOKUP
+        .iter()
+        .any(|lookup| error.contains(lookup))
+    {
+        return false;
+    }
+
+    let mut components = attribute_path.split('.');
+    components.any(|component| {
+        error.starts_with(&format!("attribute '{}' missing", component))
+            || (error.contains("out of bounds") && component.parse::<usize>().is_ok())
+    })
+}
+
+/// Turn the stderr of a failed nix command into the most specific error, falling back to
+/// [`Error::NixCommand`] for failures that are not recognized. `attribute_path` is the attribute
+/// path nix was asked to evaluate, only attributes of it being missing mean it does not exist.
+pub fn nix_failure(
+    code: Option<i32>,
+    stderr: String,
+    offline: bool,
+    attribute_path: Option<&str>,
+) -> Error {
+    let matches = |patterns: &[&str]| patterns.iter().any(|pattern| stderr.contains(pattern));
+
+    if matches(&FLAKE_NOT_FOUND) || flake_download_not_found(&stderr) {
+        Error::FlakeNotFound(stderr)
+    } else if matches(&FETCH_FAILED) {
+        if offline {
+            Error::OfflineFetchFailed(stderr)
+        } else {
+            Error::FetchFailed(stderr)
+        }
+    } else if attribute_path
+        .is_some_and(|attribute_path| attribute_path_missing(&stderr, attribute_path))
+    {
+        Error::AttributePathNotFound(stderr)
+    } else if matches(&EVALUATION_ERROR) {
+        Error::EvaluationError(stderr)
+    } else {
+        Error::NixCommand(code, stderr)
+    }
+}
+
+/// Messages of nix, its garbage collector and the C++ runtime when an allocation failed
+const OUT_OF_MEMORY: [&str; 4] = [
+    "out of memory",
+    "Out of Memory",
+    "std::bad_alloc",
+    "Cannot allocate memory",
+];
+
+/// Whether a nix command with a memory limit failed because it reached the limit. Failed
+/// allocations are either reported or crash nix, so being killed by a signal counts as well.
+pub fn out_of_memory(status: &ExitStatus, stderr: &str) -> bool {
+    !status.success()
+        && (status.code().is_none() || OUT_OF_MEMORY.iter().any(|pattern| stderr.contains(pattern)))
+}
+
+/// Limit the address space of the command to `max_memory` bytes, so a runaway evaluation fails
+/// instead of exhausting the memory of the machine. Only supported on Linux, elsewhere the
+/// command is left as is.
+#[cfg(target_os = "linux")]
+pub fn limit_memory(command: &mut Command, max_memory: Option<u64>) {
+    use std::os::unix::process::CommandExt;
+
+    let Some(max_memory) = max_memory else {
+        return;
+    };
+    let limit = libc::rlimit {
+        rlim_cur: max_memory as libc::rlim_t,
+        rlim_max: max_memory as libc::rlim_t,
+    };
+    // SAFETY: the hook runs in the forked child before exec, setrlimit is async-signal-safe and
+    // the hook does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn limit_memory(_command: &mut Command, _max_memory: Option<u64>) {}
+
+/// Turn a failure to run a nix command into an error, [`Error::NixNotInstalled`] if the program
+/// could not be found at all
+pub fn spawn_failure(command: &Command, err: std::io::Error) -> Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        Error::NixNotInstalled(command.get_program().to_string_lossy().into_owned())
+    } else {
+        Error::NixIO(err)
+    }
+}
+
+/// A `nix` command, running `nix_binary` instead of the `nix` on the PATH if it is given
+pub fn nix(nix_binary: Option<&Path>) -> Command {
+    Command::new(nix_binary.unwrap_or(Path::new("nix")))
+}
+
+/// A `nix-store` command, running the `nix-store` next to `nix_binary` if it is given as a path,
+/// as both are installed in the same directory
+pub fn nix_store(nix_binary: Option<&Path>) -> Command {
+    match nix_binary
+        .and_then(Path::parent)
+        .filter(|directory| !directory.as_os_str().is_empty())
+    {
+        Some(directory) => Command::new(directory.join("nix-store")),
+        None => Command::new("nix-store"),
+    }
+}
+
+/// Check that nix can be run at all, so a missing installation is reported before any work starts
+pub fn check_nix_installed(nix_binary: Option<&Path>) -> Result<()> {
+    let mut command = nix(nix_binary);
+    command.arg("--version");
+    command
+        .output()
+        .map_err(|err| spawn_failure(&command, err))?;
+    Ok(())
+}
+
+/// The system nix builds for on this machine, `builtins.currentSystem`
+pub fn current_system(nix_binary: Option<&Path>) -> Result<String> {
+    let mut command = nix(nix_binary);
+    command
+        .args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ])
+        .args(["--extra-experimental-features", "nix-command"]);
+    let output = command
+        .output()
+        .map_err(|err| spawn_failure(&command, err))?;
+    if !output.status.success() {
+        return Err(Error::NixCommand(
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Run the command to completion and collect its output, like `Command::output`, but kill it if
+/// it runs longer than the timeout. Returns `None` if the command was killed.
+pub fn output_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> Result<Option<Output>> {
+    let timeout = match timeout {
+        None => {
+            return command
+                .output()
+                .map(Some)
+                .map_err(|err| spawn_failure(command, err))
+        }
+        Some(timeout) => timeout,
+    };
+
+    // A process group of its own, so what nix starts is killed along with it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| spawn_failure(command, err))?;
+
+    // Read the pipes in the background, otherwise the child blocks once a pipe is full
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout_reader = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        stdout.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    });
+    let stderr_reader = std::thread::spawn(move || -> std::io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        stderr.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    });
+
+    let status = match child.wait_timeout(timeout)? {
+        Some(status) => status,
+        None => {
+            // Kill and reap the child so no nix process is left behind, the processes it started
+            // would otherwise keep the pipes open and the readers waiting
+            kill_process_group(&mut child)?;
+            child.wait()?;
+            let _ = stdout_reader.join();
+            let _ = stderr_reader.join();
+            return Ok(None);
+        }
+    };
+
+    let stdout = stdout_reader.join().expect("stdout reader panicked")?;
+    let stderr = stderr_reader.join().expect("stderr reader panicked")?;
+
+    Ok(Some(Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+/// Kill the child and every process of its group, see [`output_with_timeout`]
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) -> std::io::Result<()> {
+    // SAFETY: kill only sends a signal, to the group the child leads
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        let err = std::io::Error::last_os_error();
+        // The whole group already exited
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) -> std::io::Result<()> {
+    child.kill()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_with_timeout() {
+        let output = output_with_timeout(
+            Command::new("sh").args(["-c", "echo out; echo err >&2"]),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        let output = output_with_timeout(
+            Command::new("sleep").arg("10"),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap();
+        assert!(output.is_none());
+
+        // A process the command started keeps the pipes open until it is killed as well
+        let start = std::time::Instant::now();
+        let output = output_with_timeout(
+            Command::new("sh").args(["-c", "sleep 100 & sleep 100"]),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap();
+        assert!(output.is_none());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_spawn_failure() {
+        let mut command = Command::new("nixtract-does-not-exist");
+        let err = command.output().unwrap_err();
+        assert!(matches!(
+            spawn_failure(&command, err),
+            Error::NixNotInstalled(program) if program == "nixtract-does-not-exist"
+        ));
+
+        let err = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(matches!(spawn_failure(&command, err), Error::NixIO(_)));
+    }
+
+    #[test]
+    fn test_nix_store() {
+        let program = |nix_binary: Option<&str>| {
+            nix_store(nix_binary.map(Path::new))
+                .get_program()
+                .to_owned()
+        };
+        assert_eq!(program(None), "nix-store");
+        assert_eq!(program(Some("nix")), "nix-store");
+        assert_eq!(
+            program(Some("/opt/lix/bin/nix")),
+            Path::new("/opt/lix/bin/nix-store").as_os_str()
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_limit_memory() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "ulimit -v"]);
+        limit_memory(&mut command, Some(512 * 1024 * 1024));
+        let output = command.output().unwrap();
+        // ulimit reports KiB
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "524288");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_out_of_memory() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let failed = ExitStatus::from_raw(1 << 8);
+        assert!(out_of_memory(&failed, "error: out of memory"));
+        assert!(out_of_memory(
+            &failed,
+            "GC Warning: Out of Memory! Heap size: 1024 MiB. Returning NULL!"
+        ));
+        assert!(!out_of_memory(&failed, "error: attribute 'foo' missing"));
+        // Killed by SIGABRT after a failed allocation
+        assert!(out_of_memory(&ExitStatus::from_raw(6), ""));
+        assert!(!out_of_memory(&ExitStatus::from_raw(0), "out of memory"));
+    }
+
+    #[test]
+    fn test_nix_failure() {
+        let failure = |stderr: &str, offline| {
+            nix_failure(Some(1), stderr.to_owned(), offline, Some("hello.nope"))
+        };
+
+        assert!(matches!(
+            failure(
+                "error: cannot find flake 'flake:nope' in the flake registries",
+                false
+            ),
+            Error::FlakeNotFound(_)
+        ));
+        assert!(matches!(
+            failure(
+                "error: unable to download 'https://example.org/x': Couldn't resolve host name (6)",
+                true
+            ),
+            Error::OfflineFetchFailed(_)
+        ));
+        assert!(matches!(
+            failure(
+                "error: unable to download 'https://example.org/x': Couldn't resolve host name (6)",
+                false
+            ),
+            Error::FetchFailed(_)
+        ));
+        assert!(matches!(
+            failure(
+                "error:
+       … while fetching the input 'github:tweag/nope'
+
+       error: unable to download 'https://api.github.com/repos/tweag/nope/commits/HEAD': HTTP error 404
+
+       response body:
+
+       {\"message\":\"Not Found\",\"status\":\"404\"}",
+                false
+            ),
+            Error::FlakeNotFound(_)
+        ));
+        // Something the derivation fetches while it is evaluated is missing, not the flake
+        assert!(matches!(
+            failure(
+                "error:
+       … while evaluating the attribute 'drvPath'
+
+       … while calling the 'fetchTarball' builtin
+         at /nix/store/6xq1ca84lw6iq4ag4d5ijjhz2ricvb8v-source/default.nix:3:9:
+            2|   pname = \"hello\";
+            3|   src = builtins.fetchTarball \"https://example.org/gone.tar.gz\";
+             |         ^
+
+       error: unable to download 'https://example.org/gone.tar.gz': HTTP error 404",
+                false
+            ),
+            Error::FetchFailed(_)
+        ));
+        assert!(matches!(
+            failure(
+                "error:
+       … while evaluating the attribute 'attribute_path'
+
+       error: attribute 'nope' missing
+
+       at /tmp/.tmpKx2Fq1/lib.nix:96:18:
+
+           95|             if nixpkgs.lib.isAttrs collection
+           96|             then collection.${x}
+             |                  ^
+           97|             else
+       Did you mean one of hello or hello-go?",
+                false
+            ),
+            Error::AttributePathNotFound(_)
+        ));
+        // A missing attribute that is not part of the attribute path is a bug in the derivation,
+        // even if it has the name of one of its components
+        assert!(matches!(
+            failure(
+                "error:
+       … while evaluating the attribute 'drvPath'
+
+       … while evaluating attribute 'buildInputs' of derivation 'hello-2.12.1'
+
+       error: attribute 'hello' missing
+
+       at /nix/store/6xq1ca84lw6iq4ag4d5ijjhz2ricvb8v-source/pkgs/by-name/he/hello/package.nix:12:5:
+
+           11|   buildInputs = [
+           12|     pkgs.hello
+             |     ^
+           13|   ];",
+                false
+            ),
+            Error::EvaluationError(_)
+        ));
+        assert!(matches!(
+            failure(
+                "error:\n       … while evaluating the attribute 'drvPath'\n\n       error: boom",
+                false
+            ),
+            Error::EvaluationError(_)
+        ));
+        assert!(matches!(
+            failure("error: something else", false),
+            Error::NixCommand(Some(1), _)
+        ));
+    }
+
+    #[test]
+    fn test_stderr_line() {
+        assert_eq!(
+            StderrLine::parse("trace: {\"foundDrvs\": []}"),
+            StderrLine::Trace("{\"foundDrvs\": []}")
+        );
+        assert_eq!(
+            StderrLine::parse("warning: unknown setting 'foo'"),
+            StderrLine::Warning("unknown setting 'foo'")
+        );
+        assert_eq!(
+            StderrLine::parse("evaluation warning: The option `foo' has been renamed"),
+            StderrLine::Warning("The option `foo' has been renamed")
+        );
+        assert_eq!(
+            StderrLine::parse("error: attribute 'foo' missing"),
+            StderrLine::Other("error: attribute 'foo' missing")
+        );
+    }
+}
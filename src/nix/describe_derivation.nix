@@ -4,7 +4,12 @@
 #     TARGET_FLAKE_REF: flake reference to evaluate
 #     TARGET_SYSTEM: system to evaluate
 #     TARGET_ATTRIBUTE_PATH: attribute path to the derivation to evaluate
+#     TARGET_ATTRIBUTE_PATHS: JSON list of attribute paths to describe in one evaluation instead, the result is then a
+#       list of descriptions. A trace "nixtract-describing: <index>" precedes the evaluation of each of them.
+#     TARGET_FLAKE_OUTPUT: flake output category the attribute path is in, "packages" (default), "checks", "devShells",
+#       "nixosConfigurations" or "homeConfigurations"
 #     RUNTIME_ONLY: 1 if you only want to include "buildInputs" (only runtime dependencies), 0 if you want all dependencies
+#     INCLUDE_META_POSITION: 1 to include the file and line the derivation is defined at
 #
 # Example:
 # TARGET_FLAKE_REF="nixpkgs" TARGET_SYSTEM="x86_64-linux" TARGET_ATTRIBUTE_PATH="python3" nix eval --json --file describe-derivation.nix
@@ -16,102 +21,223 @@ let
   # Arguments have to be taken from environment when using `nix` command
   targetFlakeRef = builtins.getEnv "TARGET_FLAKE_REF";
   targetAttributePath = builtins.getEnv "TARGET_ATTRIBUTE_PATH";
+  targetAttributePaths = builtins.getEnv "TARGET_ATTRIBUTE_PATHS";
   targetSystem = let env = builtins.getEnv "TARGET_SYSTEM"; in if env == "" then builtins.currentSystem else env;
+  targetFlakeOutput = let env = builtins.getEnv "TARGET_FLAKE_OUTPUT"; in if env == "" then "packages" else env;
   # 0 is false, everything else is true
   runtimeOnly = if builtins.getEnv "RUNTIME_ONLY" == "0" then false else true;
+  includeMetaPosition = builtins.getEnv "INCLUDE_META_POSITION" == "1";
+
+  # Output paths of every output of a multi-output build input, the one listed is only its default output
+  allOutputPaths = input:
+    let
+      outputPaths = lib.safePlatformDrvEval targetSystem
+        (drv:
+          let value = if builtins.length (drv.outputs or [ "out" ]) > 1 then map (output: drv.${output}.outPath) drv.outputs else [ ];
+          in builtins.deepSeq value value)
+        input;
+    in
+    if outputPaths == null then [ ] else outputPaths;
 
   # Get pkgs
   targetFlake = builtins.getFlake targetFlakeRef;
-  targetFlakePkgs = lib.getFlakePkgs targetFlake targetSystem;
+  targetFlakePkgs = lib.getFlakeOutput targetFlake targetFlakeOutput targetSystem;
+  outputType = lib.flakeOutputAttribute targetFlake targetFlakeOutput targetSystem;
 
-  # Get target value
-  targetValue = lib.getValueAtPath targetFlakePkgs targetAttributePath;
-in
-{
-  name = targetValue.name;
-  parsed_name = (builtins.parseDrvName targetValue.name);
-  attribute_path = targetAttributePath;
+  describe = targetAttributePath:
+    let
+      # Get target value
+      targetValue = lib.getValueAtPath targetFlakePkgs targetAttributePath;
+    in
+    {
+      name = targetValue.name;
+      parsed_name = (builtins.parseDrvName targetValue.name);
+      attribute_path = targetAttributePath;
+      system = targetSystem;
+      flake_output = targetFlakeOutput;
+      output_type = outputType;
 
-  src =
-    if targetValue ? src.gitRepoUrl && targetValue ? src.rev
-    then
-      {
-        git_repo_url = targetValue.src.gitRepoUrl;
-        rev = targetValue.src.rev;
-      }
-    else
-      null;
+      # file:line the derivation is defined at, relative to the root of its flake when that is in the store
+      meta_position =
+        let
+          position = if includeMetaPosition then lib.safeEval (targetValue.meta.position or null) else null;
+          relative = builtins.match "${builtins.storeDir}/[^/]+/(.*)" position;
+        in
+        if position == null then null
+        else if relative == null then position
+        else builtins.head relative;
 
-  nixpkgs_metadata =
-    {
-      description = (builtins.tryEval (targetValue.meta.description or "")).value;
-      pname = (builtins.tryEval (targetValue.pname or "")).value or null;
-      version = (builtins.tryEval (targetValue.version or "")).value;
-      broken = (builtins.tryEval (targetValue.meta.broken or false)).value;
-      homepage = (builtins.tryEval (targetValue.meta.homepage or "")).value;
-      licenses = (builtins.tryEval (
-        if builtins.isAttrs (targetValue.meta.license or null)
-        # In case the license attribute is not a list, we produce a singleton list to be consistent
-        then [{
-          spdx_id = targetValue.meta.license.spdxId or null;
-          full_name = targetValue.meta.license.fullName or null;
-        }]
-        # In case the license attribute is a list
-        else if builtins.isList (targetValue.meta.license or null)
-        then
+      # where the sources come from, derivations with several sources list them in `srcs`
+      sources =
+        let
+          srcs = lib.safeEval (nixpkgs.lib.toList (targetValue.src or targetValue.srcs or [ ]));
+        in
+        if srcs == null then [ ] else
+        builtins.filter (source: source != null) (
           builtins.map
-            (l: {
-              spdx_id = l.spdxId or null;
-              full_name = l.fullName or null;
-            })
-            targetValue.meta.license
-        else null
-      )).value;
-    };
+            (src: lib.safeEval (let value = lib.describeSource src; in builtins.deepSeq value value))
+            srcs
+        );
 
-  # path to the evaluated derivation file
-  derivation_Path = lib.safePlatformDrvEval targetSystem (drv: drv.drvPath) targetValue;
+      nixpkgs_metadata =
+        {
+          description = (builtins.tryEval (targetValue.meta.description or "")).value;
+          pname = (builtins.tryEval (targetValue.pname or "")).value or null;
+          version = (builtins.tryEval (targetValue.version or "")).value;
+          broken = (builtins.tryEval (targetValue.meta.broken or false)).value;
+          # Set by nixpkgs' check-meta from the licenses and `knownVulnerabilities`
+          unfree = (builtins.tryEval (targetValue.meta.unfree or false)).value;
+          insecure = (builtins.tryEval (targetValue.meta.insecure or false)).value;
+          # Usually CVE identifiers, or a sentence about the vulnerability
+          known_vulnerabilities =
+            let
+              knownVulnerabilities = lib.safeEval (
+                let
+                  value = builtins.filter builtins.isString
+                    (nixpkgs.lib.toList (targetValue.meta.knownVulnerabilities or [ ]));
+                in
+                builtins.deepSeq value value
+              );
+            in
+            if knownVulnerabilities == null then [ ] else knownVulnerabilities;
+          homepage = (builtins.tryEval (targetValue.meta.homepage or "")).value;
+          licenses = (builtins.tryEval (
+            if builtins.isAttrs (targetValue.meta.license or null)
+            # In case the license attribute is not a list, we produce a singleton list to be consistent
+            then [{
+              spdx_id = targetValue.meta.license.spdxId or null;
+              full_name = targetValue.meta.license.fullName or null;
+              short_name = targetValue.meta.license.shortName or null;
+              free = targetValue.meta.license.free or null;
+            }]
+            # In case the license attribute is a list
+            else if builtins.isList (targetValue.meta.license or null)
+            then
+              builtins.map
+                (l: {
+                  spdx_id = l.spdxId or null;
+                  full_name = l.fullName or null;
+                  short_name = l.shortName or null;
+                  free = l.free or null;
+                })
+                targetValue.meta.license
+            else null
+          )).value;
+          # Maintainers are usually attribute sets from `lib.maintainers`, but some packages use bare strings
+          maintainers =
+            let
+              maintainers = lib.safeEval (
+                let
+                  value = builtins.map
+                    (m:
+                      if builtins.isString m
+                      then { name = m; email = null; github = null; }
+                      else {
+                        name = m.name or null;
+                        email = m.email or null;
+                        github = m.github or null;
+                      }
+                    )
+                    (nixpkgs.lib.toList (targetValue.meta.maintainers or [ ]));
+                in
+                builtins.deepSeq value value
+              );
+            in
+            if maintainers == null then [ ] else maintainers;
+        };
 
-  # path to the realized (=built) derivation
-  # note: we can't name it `outPath` because serialization would only output it instead of dict, see Nix `toString` docs
-  output_path =
-    # TODO meaningfully represent when it's not the right platform (instead of null)
-    lib.safePlatformDrvEval
-      targetSystem
-      (drv: drv.outPath)
-      targetValue;
-  outputs = map (name: { inherit name; output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) targetValue.${name}; }) (targetValue.outputs or [ ]);
-  build_inputs =
-    if targetValue ? outputHash then [ ] else
-    nixpkgs.lib.concatMap
-      ({ name, value }:
-        if nixpkgs.lib.isDerivation value then
-          [{
-            build_input_type = name;
-            attribute_path = "${targetAttributePath}.drvAttrs.${name}";
-            output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) value;
-          }]
-        else if nixpkgs.lib.isList value then
-          nixpkgs.lib.concatMap
-            ({ index, value }:
-              if nixpkgs.lib.isDerivation value then
-                [{
-                  build_input_type = name;
-                  attribute_path = "${targetAttributePath}.drvAttrs.${name}.${builtins.toString index}";
-                  output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) value;
-                }]
-              else [ ]
+      # systems the package declares to support, platform patterns (attribute sets) are not supported and skipped
+      platforms =
+        let
+          platforms = lib.safeEval (
+            let value = nixpkgs.lib.toList (targetValue.meta.platforms or [ ]);
+            in builtins.deepSeq value value
+          );
+        in
+        if platforms == null then [ ] else
+        builtins.filter
+          (platform:
+            builtins.isString platform
+            || builtins.trace "warning: skipping a non-string value in meta.platforms of ${targetAttributePath}" false
+          )
+          platforms;
+
+      # path to the evaluated derivation file
+      derivation_Path = lib.safePlatformDrvEval targetSystem (drv: drv.drvPath) targetValue;
+
+      # path to the realized (=built) derivation
+      # note: we can't name it `outPath` because serialization would only output it instead of dict, see Nix `toString` docs
+      output_path =
+        # TODO meaningfully represent when it's not the right platform (instead of null)
+        lib.safePlatformDrvEval
+          targetSystem
+          (drv: drv.outPath)
+          targetValue;
+      outputs =
+        let
+          # fixed-output derivations (fetchers) declare the hash of their output upfront
+          fixedOutput =
+            if targetValue ? outputHash
+            then {
+              hash = lib.safeEval targetValue.outputHash;
+              # null when the hash is an SRI hash, which includes the algorithm
+              hash_algo = lib.safeEval (targetValue.outputHashAlgo or null);
+              hash_mode = lib.safeEval (targetValue.outputHashMode or "flat");
+            }
+            else { };
+        in
+        map (name: { inherit name; output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) targetValue.${name}; } // fixedOutput) (targetValue.outputs or [ ]);
+      # the outputs installing the package gets by default, only the main one when meta does not say
+      outputs_to_install =
+        let
+          outputsToInstall = lib.safeEval (
+            let value = builtins.filter builtins.isString (nixpkgs.lib.toList (targetValue.meta.outputsToInstall or [ ]));
+            in builtins.deepSeq value value
+          );
+          mainOutput = lib.safeEval (targetValue.outputName or "out");
+        in
+        if outputsToInstall != null && outputsToInstall != [ ] then outputsToInstall
+        else if mainOutput == null then [ ]
+        else [ mainOutput ];
+      build_inputs =
+        if targetValue ? outputHash then [ ] else
+        nixpkgs.lib.concatMap
+          ({ name, value }:
+            if nixpkgs.lib.isDerivation value then
+              [{
+                build_input_type = name;
+                attribute_path = "${targetAttributePath}.drvAttrs.${name}";
+                output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) value;
+                all_output_paths = allOutputPaths value;
+              }]
+            else if nixpkgs.lib.isList value then
+              nixpkgs.lib.concatMap
+                ({ index, value }:
+                  if nixpkgs.lib.isDerivation value then
+                    [{
+                      build_input_type = name;
+                      attribute_path = "${targetAttributePath}.drvAttrs.${name}.${builtins.toString index}";
+                      output_path = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) value;
+                      all_output_paths = allOutputPaths value;
+                    }]
+                  else [ ]
+                )
+                (lib.enumerate value)
+            else [ ]
+          )
+          (if runtimeOnly
+          then
+            (
+              nixpkgs.lib.optional (targetValue ? buildInputs) { name = "buildInputs"; value = targetValue.buildInputs; }
+              ++ nixpkgs.lib.optional (targetValue ? propagatedBuildInputs) { name = "propagatedBuildInputs"; value = targetValue.propagatedBuildInputs; }
             )
-            (lib.enumerate value)
-        else [ ]
-      )
-      (if runtimeOnly
-      then
-        (
-          nixpkgs.lib.optional (targetValue ? buildInputs) targetValue.buildInputs
-          ++ nixpkgs.lib.optional (targetValue ? propagatedBuildInputs) targetValue.propagatedBuildInputs
-        )
-      else
-        nixpkgs.lib.attrsToList targetValue.drvAttrs
-      );
-}
+          else
+            nixpkgs.lib.attrsToList targetValue.drvAttrs
+          );
+    };
+in
+if targetAttributePaths == "" then describe targetAttributePath
+else
+  nixpkgs.lib.imap0
+    (index: attributePath: builtins.trace "nixtract-describing: ${builtins.toString index}" (describe attributePath))
+    (builtins.fromJSON targetAttributePaths)
@@ -3,30 +3,207 @@ use std::{collections::HashMap, process::Command};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use super::command::StderrLine;
 use super::lib::Lib;
 use crate::error::{Error, Result};
 
+/// Version of the shape of [`DerivationDescription`], emitted with every description.
+///
+/// Bump the major version when a field is removed, renamed or changes type, and the minor version
+/// when a field is added. Consumers can safely parse any description with the same major version.
+pub const SCHEMA_VERSION: &str = "2.15.0";
+
+/// Version of the descriptions written before they carried a `schema_version`
+const LEGACY_SCHEMA_VERSION: &str = "1.0.0";
+
+fn legacy_schema_version() -> String {
+    LEGACY_SCHEMA_VERSION.to_owned()
+}
+
+/// The schema of `schema_version` only allows the current version
+fn schema_version_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+    schemars::schema::SchemaObject {
+        instance_type: Some(schemars::schema::InstanceType::String.into()),
+        const_value: Some(SCHEMA_VERSION.into()),
+        ..Default::default()
+    }
+    .into()
+}
+
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
 /// All information of a derivation that is extracted directly from nix
 pub struct DerivationDescription {
+    /// Version of the shape of this description, see [`SCHEMA_VERSION`]. Descriptions written
+    /// before it existed are read as version 1.0.0.
+    #[serde(default = "legacy_schema_version")]
+    #[schemars(schema_with = "schema_version_schema")]
+    pub schema_version: String,
     pub attribute_path: String,
+    /// The system the derivation was evaluated for, e.g. "x86_64-linux". Without a target system
+    /// this is the host system, `builtins.currentSystem`.
+    pub system: String,
+    /// The flake output category the attribute path is relative to
+    pub flake_output: super::FlakeOutput,
+    /// The attribute of the flake outputs the attribute path is relative to, e.g.
+    /// "legacyPackages" when `flake_output` is `packages`. Empty for store paths.
+    #[serde(default)]
+    pub output_type: String,
     pub derivation_path: Option<String>,
     pub output_path: Option<String>,
     pub outputs: Vec<Output>,
+    /// Names of the outputs installing the package gets by default, from
+    /// `meta.outputsToInstall`, or only the main output when it is not set
+    #[serde(default)]
+    pub outputs_to_install: Vec<String>,
     pub name: String,
     pub parsed_name: ParsedName,
+    /// Package URL of the derivation, see [`purl`]
+    #[serde(default)]
+    pub purl: String,
     pub nixpkgs_metadata: NixpkgsMetadata,
-    pub src: Option<Source>,
+    /// `file:line` the derivation is defined at, from `meta.position`, relative to the root of
+    /// the flake (e.g. nixpkgs) when possible. Only included on request.
+    #[serde(default)]
+    pub meta_position: Option<String>,
+    /// The `src` of the derivation, or its `srcs` when it has several
+    pub sources: Vec<Source>,
+    /// Systems the package declares to support in `meta.platforms`
+    pub platforms: Vec<String>,
     pub build_inputs: Vec<BuiltInput>,
+    /// Traces and warnings nix printed while evaluating the derivation
+    #[serde(default)]
+    pub eval_warnings: Vec<String>,
 
+    /// Narinfo of `output_path`, the narinfo of every output is in `outputs`
     #[serde(skip_deserializing)]
     pub nar_info: Option<super::narinfo::NarInfo>,
+
+    /// Bytes `output_path` takes in the local store, only included on request and when it is
+    /// realised
+    #[serde(default)]
+    pub self_size: Option<u64>,
+    /// Bytes the closure of `output_path` takes in the local store, like `self_size`
+    #[serde(default)]
+    pub closure_size: Option<u64>,
+
+    /// Contents of the `.drv` file, only included on request
+    #[serde(skip_deserializing)]
+    pub drv: Option<super::drv::Drv>,
+}
+
+impl DerivationDescription {
+    /// Fetch the narinfo of every output that has an output path, and of `output_path`
+    pub fn fetch_nar_info(
+        &mut self,
+        http_client: &reqwest::blocking::Client,
+        config: &super::narinfo::FetchConfig,
+        binary_caches: &[String],
+    ) -> Result<()> {
+        for output in &mut self.outputs {
+            if let Some(output_path) = &output.output_path {
+                output.nar_info = super::narinfo::NarInfo::fetch(
+                    http_client,
+                    config,
+                    output_path,
+                    binary_caches,
+                )?;
+            }
+        }
+
+        // `output_path` is normally one of the outputs, no need to fetch it twice
+        if let Some(output_path) = &self.output_path {
+            self.nar_info = match self
+                .outputs
+                .iter()
+                .find(|output| output.output_path.as_ref() == Some(output_path))
+            {
+                Some(output) => output.nar_info.clone(),
+                None => {
+                    super::narinfo::NarInfo::fetch(http_client, config, output_path, binary_caches)?
+                }
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// A one line summary for logs, e.g. `hello 2.12.1 (hello) — 2 build inputs, license: GPL-3.0-or-later`.
+/// Licenses are shown by SPDX id, or by full name for those without one. The JSON output, or
+/// `Debug`, has the whole description.
+impl std::fmt::Display for DerivationDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.parsed_name.name)?;
+        if !self.parsed_name.version.is_empty() {
+            write!(f, " {}", self.parsed_name.version)?;
+        }
+
+        let licenses = match &self.nixpkgs_metadata.licenses {
+            Some(licenses) if !licenses.is_empty() => licenses
+                .iter()
+                .map(|license| license.spdx_id.as_deref().unwrap_or(&license.full_name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => "unknown".to_owned(),
+        };
+        write!(
+            f,
+            " ({}) — {} build input{}, license: {}",
+            self.attribute_path,
+            self.build_inputs.len(),
+            if self.build_inputs.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            licenses
+        )
+    }
+}
+
+/// Characters that are percent-encoded in the components of a package URL
+const PURL_ENCODE: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Package URL (<https://github.com/package-url/purl-spec>) of a derivation, e.g.
+/// `pkg:nix/hello@2.12.1?attribute_path=hello&flake_ref=nixpkgs`
+pub fn purl(flake_ref: &str, attribute_path: &str, parsed_name: &ParsedName) -> String {
+    let encode =
+        |value: &str| percent_encoding::utf8_percent_encode(value, PURL_ENCODE).to_string();
+    let version = if parsed_name.version.is_empty() {
+        String::new()
+    } else {
+        format!("@{}", encode(&parsed_name.version))
+    };
+    // Qualifiers are sorted by key
+    format!(
+        "pkg:nix/{}{}?attribute_path={}&flake_ref={}",
+        encode(&parsed_name.name),
+        version,
+        encode(attribute_path),
+        encode(flake_ref)
+    )
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
 pub struct Output {
     pub name: String,
     pub output_path: Option<String>,
+    /// Expected hash of the output, only set for fixed-output derivations
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Algorithm of `hash`, e.g. "sha256", `None` when `hash` is an SRI hash that includes it
+    #[serde(default)]
+    pub hash_algo: Option<String>,
+    /// "flat" when the hash covers the file itself, "recursive" when it covers its NAR serialisation
+    #[serde(default)]
+    pub hash_mode: Option<String>,
+    /// Narinfo of this output, only included on request
+    #[serde(skip_deserializing)]
+    pub nar_info: Option<super::narinfo::NarInfo>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
@@ -41,15 +218,45 @@ pub struct NixpkgsMetadata {
     pub pname: String,
     pub version: String,
     pub broken: bool,
+    /// Whether any of the licenses is not free, as computed by nixpkgs
+    #[serde(default)]
+    pub unfree: bool,
+    /// Whether the package has known vulnerabilities in `meta.knownVulnerabilities`
+    #[serde(default)]
+    pub insecure: bool,
+    /// `meta.knownVulnerabilities`, usually CVE identifiers, empty for packages without any
+    #[serde(default)]
+    pub known_vulnerabilities: Vec<String>,
     pub homepage: String,
     pub licenses: Option<Vec<License>>,
+    pub maintainers: Vec<Maintainer>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
-pub struct Source {
-    pub git_repo_url: String,
-    // Revision or tag of the git repo
-    pub rev: String,
+pub struct Maintainer {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub github: Option<String>,
+}
+
+/// Where a source of a derivation comes from
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// Fetched from a git repository, e.g. with `fetchFromGitHub` or `fetchgit`
+    Git {
+        url: String,
+        /// Revision or tag of the git repo
+        rev: String,
+    },
+    /// Downloaded, e.g. a tarball fetched with `fetchurl`
+    Url {
+        url: String,
+        /// Expected hash of the download, `None` if it is not a sha256 hash
+        sha256: Option<String>,
+    },
+    /// A path in the flake itself, or a source produced by a derivation, in the nix store
+    Path { path: String },
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
@@ -57,13 +264,222 @@ pub struct License {
     // Not all licenses in nixpkgs have an associated spdx id
     pub spdx_id: Option<String>,
     pub full_name: String,
+    /// Name of the license in nixpkgs' `lib.licenses`, e.g. `gpl2Plus`
+    #[serde(default)]
+    pub short_name: Option<String>,
+    /// Whether nixpkgs considers the license free, `None` for licenses that do not say, such as
+    /// bare strings
+    #[serde(default)]
+    pub free: Option<bool>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, JsonSchema)]
 pub struct BuiltInput {
     pub attribute_path: String,
-    pub build_input_type: String,
+    pub build_input_type: BuildInputType,
     pub output_path: Option<String>,
+    /// Output paths of all outputs of a multi-output input, `output_path` being only its default
+    /// output. Empty for inputs with a single output.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub all_output_paths: Vec<String>,
+}
+
+/// The attribute of the derivation a build input is listed in, serialized as the name of that
+/// attribute, e.g. "nativeBuildInputs". See the nixpkgs manual on specifying dependencies for
+/// the platforms each of them is used on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BuildInputType {
+    /// `buildInputs`, needed at run time
+    BuildInput,
+    /// `nativeBuildInputs`, tools run at build time
+    NativeBuildInput,
+    /// `propagatedBuildInputs`, needed at run time, by dependents as well
+    PropagatedBuildInput,
+    /// `propagatedNativeBuildInputs`, tools run at build time, by dependents as well
+    PropagatedNativeBuildInput,
+    /// `checkInputs`, needed by the tests
+    CheckInput,
+    /// `nativeCheckInputs`, tools run by the tests
+    NativeCheckInput,
+    /// `installCheckInputs`, needed by the tests after installation
+    InstallCheckInput,
+    /// `nativeInstallCheckInputs`, tools run by the tests after installation
+    NativeInstallCheckInput,
+    /// `depsBuildBuild`
+    DepsBuildBuild,
+    /// `depsBuildBuildPropagated`
+    DepsBuildBuildPropagated,
+    /// `depsBuildTarget`
+    DepsBuildTarget,
+    /// `depsBuildTargetPropagated`
+    DepsBuildTargetPropagated,
+    /// `depsHostHost`
+    DepsHostHost,
+    /// `depsHostHostPropagated`
+    DepsHostHostPropagated,
+    /// `depsTargetTarget`
+    DepsTargetTarget,
+    /// `depsTargetTargetPropagated`
+    DepsTargetTargetPropagated,
+    /// `src`, when the source is built by a derivation
+    Source,
+    /// `srcs`, when the sources are built by derivations
+    Sources,
+    /// `patches` that are built by a derivation
+    Patch,
+    /// `stdenv`
+    Stdenv,
+    /// Any other attribute of the derivation that holds a derivation
+    Other(String),
+}
+
+impl BuildInputType {
+    /// All types but [`BuildInputType::Other`]
+    pub(crate) const KNOWN: [BuildInputType; 20] = [
+        BuildInputType::BuildInput,
+        BuildInputType::NativeBuildInput,
+        BuildInputType::PropagatedBuildInput,
+        BuildInputType::PropagatedNativeBuildInput,
+        BuildInputType::CheckInput,
+        BuildInputType::NativeCheckInput,
+        BuildInputType::InstallCheckInput,
+        BuildInputType::NativeInstallCheckInput,
+        BuildInputType::DepsBuildBuild,
+        BuildInputType::DepsBuildBuildPropagated,
+        BuildInputType::DepsBuildTarget,
+        BuildInputType::DepsBuildTargetPropagated,
+        BuildInputType::DepsHostHost,
+        BuildInputType::DepsHostHostPropagated,
+        BuildInputType::DepsTargetTarget,
+        BuildInputType::DepsTargetTargetPropagated,
+        BuildInputType::Source,
+        BuildInputType::Sources,
+        BuildInputType::Patch,
+        BuildInputType::Stdenv,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            BuildInputType::BuildInput => "buildInputs",
+            BuildInputType::NativeBuildInput => "nativeBuildInputs",
+            BuildInputType::PropagatedBuildInput => "propagatedBuildInputs",
+            BuildInputType::PropagatedNativeBuildInput => "propagatedNativeBuildInputs",
+            BuildInputType::CheckInput => "checkInputs",
+            BuildInputType::NativeCheckInput => "nativeCheckInputs",
+            BuildInputType::InstallCheckInput => "installCheckInputs",
+            BuildInputType::NativeInstallCheckInput => "nativeInstallCheckInputs",
+            BuildInputType::DepsBuildBuild => "depsBuildBuild",
+            BuildInputType::DepsBuildBuildPropagated => "depsBuildBuildPropagated",
+            BuildInputType::DepsBuildTarget => "depsBuildTarget",
+            BuildInputType::DepsBuildTargetPropagated => "depsBuildTargetPropagated",
+            BuildInputType::DepsHostHost => "depsHostHost",
+            BuildInputType::DepsHostHostPropagated => "depsHostHostPropagated",
+            BuildInputType::DepsTargetTarget => "depsTargetTarget",
+            BuildInputType::DepsTargetTargetPropagated => "depsTargetTargetPropagated",
+            BuildInputType::Source => "src",
+            BuildInputType::Sources => "srcs",
+            BuildInputType::Patch => "patches",
+            BuildInputType::Stdenv => "stdenv",
+            BuildInputType::Other(other) => other,
+        }
+    }
+
+    /// Whether the input is a run time dependency of the derivation, as kept by `runtime_only`
+    pub fn is_runtime(&self) -> bool {
+        matches!(
+            self,
+            BuildInputType::BuildInput | BuildInputType::PropagatedBuildInput
+        )
+    }
+}
+
+impl From<&str> for BuildInputType {
+    fn from(value: &str) -> Self {
+        BuildInputType::KNOWN
+            .into_iter()
+            .find(|known| known.as_str() == value)
+            .unwrap_or_else(|| BuildInputType::Other(value.to_owned()))
+    }
+}
+
+impl std::fmt::Display for BuildInputType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for BuildInputType {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for BuildInputType {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(BuildInputType::from(value.as_str()))
+    }
+}
+
+/// One of the known attribute names, or any other string
+impl JsonSchema for BuildInputType {
+    fn schema_name() -> String {
+        "BuildInputType".to_owned()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let known = schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            enum_values: Some(
+                BuildInputType::KNOWN
+                    .iter()
+                    .map(|known| known.as_str().into())
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        let other = schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some(
+                    "Any other attribute of the derivation that holds a derivation".to_owned(),
+                ),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        schemars::schema::SchemaObject {
+            metadata: Some(Box::new(schemars::schema::Metadata {
+                description: Some(
+                    "The attribute of the derivation the build input is listed in".to_owned(),
+                ),
+                ..Default::default()
+            })),
+            subschemas: Some(Box::new(schemars::schema::SubschemaValidation {
+                any_of: Some(vec![known.into(), other.into()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// Where the runtime dependencies of a derivation are taken from when only runtime dependencies
+/// are requested
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RuntimeSource {
+    /// The `buildInputs` and `propagatedBuildInputs` of the evaluated derivation
+    #[default]
+    Eval,
+    /// The references of the output paths in the nix store, falling back to the narinfo in the
+    /// binary caches. Requires the outputs to be realised locally or available in a cache.
+    Store,
 }
 
 #[derive(Clone)]
@@ -71,10 +487,29 @@ pub struct DescribeDerivationArgs<'a> {
     pub flake_ref: &'a String,
     pub system: &'a Option<String>,
     pub attribute_path: String,
+    pub flake_output: super::FlakeOutput,
     pub offline: bool,
+    /// Run this nix executable instead of the one on the PATH
+    pub nix_binary: Option<&'a std::path::Path>,
+    /// Extra arguments passed to `nix eval` as is
+    pub nix_args: &'a [String],
+    pub nixpkgs_allow: super::NixpkgsAllow,
     pub runtime_only: bool,
+    pub runtime_source: RuntimeSource,
     pub include_nar_info: bool,
+    pub include_drv: bool,
+    pub include_meta_position: bool,
+    /// Query the size of realised output paths in the local store
+    pub include_store_size: bool,
+    /// Kill the nix evaluation if it takes longer than this
+    pub eval_timeout: Option<std::time::Duration>,
+    /// Limit the memory of the nix evaluation to this many bytes, on Linux
+    pub max_eval_memory: Option<u64>,
+    /// Corrects the SPDX ids of the licenses
+    pub license_map: Option<&'a crate::license::LicenseMap>,
     pub binary_caches: &'a [String],
+    pub http_client: &'a reqwest::blocking::Client,
+    pub narinfo_fetch: &'a super::narinfo::FetchConfig,
     pub lib: &'a Lib,
 }
 
@@ -84,47 +519,80 @@ impl<'a> From<crate::ProcessingArgs<'a>> for DescribeDerivationArgs<'a> {
             flake_ref: args.flake_ref,
             system: args.system,
             attribute_path: args.attribute_path,
+            flake_output: args.flake_output,
             offline: args.offline,
+            nix_binary: args.nix_binary,
+            nix_args: args.nix_args,
+            nixpkgs_allow: args.nixpkgs_allow,
             runtime_only: args.runtime_only,
-            include_nar_info: args.include_nar_info,
+            runtime_source: args.runtime_source,
+            // Fetched after describing when there is a separate pool for it
+            include_nar_info: args.include_nar_info && args.narinfo_pool.is_none(),
+            include_drv: args.include_drv,
+            include_meta_position: args.include_meta_position,
+            include_store_size: args.include_store_size,
+            eval_timeout: args.eval_timeout,
+            max_eval_memory: args.max_eval_memory,
+            license_map: args.license_map,
             binary_caches: args.binary_caches,
+            http_client: args.http_client,
+            narinfo_fetch: args.narinfo_fetch,
             lib: args.lib,
         }
     }
 }
 
-pub fn describe_derivation(args: &DescribeDerivationArgs) -> Result<DerivationDescription> {
+/// Marks the start of the evaluation of each derivation of a batch in the traces, see
+/// `describe_derivation.nix`
+const BATCH_MARKER: &str = "nixtract-describing: ";
+
+/// The `nix eval` of `describe_derivation.nix`, describing what the attribute path environment
+/// variable `target` is set to
+fn describe_command(args: &DescribeDerivationArgs, target: (&str, String)) -> Command {
     let expr = include_str!("describe_derivation.nix");
 
+    // With the store as source of the runtime dependencies, all inputs are evaluated and
+    // filtered on the store references afterwards
+    let store_runtime = args.runtime_only && args.runtime_source == RuntimeSource::Store;
+
     // Create a scope so env_vars isn't needlessly mutable
     let env_vars: HashMap<String, String> = {
         let mut res = HashMap::from([
             ("TARGET_FLAKE_REF".to_owned(), args.flake_ref.to_owned()),
+            (target.0.to_owned(), target.1),
             (
-                "TARGET_ATTRIBUTE_PATH".to_owned(),
-                args.attribute_path.to_owned(),
+                "TARGET_FLAKE_OUTPUT".to_owned(),
+                args.flake_output.as_str().to_owned(),
             ),
-            ("NIXPKGS_ALLOW_UNFREE".to_owned(), "1".to_owned()),
-            ("NIXPKGS_ALLOW_INSECURE".to_owned(), "1".to_owned()),
-            ("NIXPKGS_ALLOW_BROKEN".to_owned(), "1".to_owned()),
             (
                 "RUNTIME_ONLY".to_owned(),
-                if args.runtime_only { "1" } else { "0" }.to_owned(),
+                if args.runtime_only && !store_runtime {
+                    "1"
+                } else {
+                    "0"
+                }
+                .to_owned(),
             ),
         ]);
+        res.extend(args.nixpkgs_allow.env_vars());
         if let Some(system) = args.system {
             res.insert("TARGET_SYSTEM".to_owned(), system.to_owned());
         }
+        if args.include_meta_position {
+            res.insert("INCLUDE_META_POSITION".to_owned(), "1".to_owned());
+        }
         res
     };
 
     // Run the nix command, with the provided environment variables and expression
-    let mut command: Command = Command::new("nix");
+    let mut command: Command = super::command::nix(args.nix_binary);
     command
         .arg("eval")
         .arg("-I")
         .arg(format!("lib={}", args.lib.path().to_string_lossy()))
-        .args(["--json", "--expr", expr])
+        .arg("--json")
+        .args(args.nix_args)
+        .args(["--expr", expr])
         .arg("--impure")
         .args(["--extra-experimental-features", "flakes nix-command"])
         .envs(env_vars);
@@ -133,19 +601,127 @@ pub fn describe_derivation(args: &DescribeDerivationArgs) -> Result<DerivationDe
     if args.offline {
         command.arg("--offline");
     }
+    super::command::limit_memory(&mut command, args.max_eval_memory);
+
+    command
+}
 
-    let output = command.output()?;
+/// Run a `describe_command` with the given timeout, returning its stdout and stderr if it
+/// succeeded. `target` names what is described in errors.
+fn run_describe_command(
+    args: &DescribeDerivationArgs,
+    mut command: Command,
+    timeout: Option<std::time::Duration>,
+    target: &str,
+) -> Result<(String, String)> {
+    let output = super::command::output_with_timeout(&mut command, timeout)?
+        .ok_or_else(|| Error::NixTimeout(target.to_owned()))?;
 
     // Get stdout, stderr as a String
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
 
     log::debug!("stdout: {}", stdout);
 
     // Check if the nix command was successful
+    if args.max_eval_memory.is_some() && super::command::out_of_memory(&output.status, &stderr) {
+        return Err(Error::EvalOutOfMemory(target.to_owned()));
+    }
     if !output.status.success() {
-        return Err(Error::NixCommand(output.status.code(), stderr.to_string()));
+        return Err(super::command::nix_failure(
+            output.status.code(),
+            stderr,
+            args.offline,
+            Some(target),
+        ));
+    }
+
+    Ok((stdout, stderr))
+}
+
+/// Everything that is added to a description once nix evaluated it, `stderr` being what nix
+/// printed while evaluating it
+fn complete_description(
+    args: &DescribeDerivationArgs,
+    description: &mut DerivationDescription,
+    stderr: &[&str],
+) -> Result<()> {
+    // Nix does not output it, the default is for descriptions written by older versions
+    description.schema_version = SCHEMA_VERSION.to_owned();
+    description.purl = purl(
+        args.flake_ref,
+        &description.attribute_path,
+        &description.parsed_name,
+    );
+    if let (Some(license_map), Some(licenses)) =
+        (args.license_map, &mut description.nixpkgs_metadata.licenses)
+    {
+        license_map.apply(licenses);
+    }
+
+    // Nix can print useful traces and warnings, e.g. about deprecated options, even on success
+    description.eval_warnings = stderr
+        .iter()
+        .filter(|line| !matches!(StderrLine::parse(line), StderrLine::Other(_)))
+        .map(|line| line.to_string())
+        .collect();
+    for warning in &description.eval_warnings {
+        log::debug!("{}: {}", description.attribute_path, warning);
+    }
+
+    if args.include_nar_info {
+        description.fetch_nar_info(args.http_client, args.narinfo_fetch, args.binary_caches)?;
+    };
+
+    if args.include_drv {
+        if let Some(derivation_path) = &description.derivation_path {
+            description.drv = Some(super::drv::Drv::show(
+                derivation_path,
+                args.eval_timeout,
+                args.nix_binary,
+            )?);
+        }
+    }
+
+    if args.runtime_only && args.runtime_source == RuntimeSource::Store {
+        filter_runtime_inputs(args, description)?;
+    }
+
+    if args.include_store_size {
+        query_store_size(args, description)?;
+    }
+
+    Ok(())
+}
+
+/// Split what nix printed while describing a batch of `count` derivations by derivation: what it
+/// prints while evaluating one follows the marker of that derivation. Anything before the first
+/// marker comes from evaluating the flake itself and is only logged.
+fn split_batch_stderr(stderr: &str, count: usize) -> Vec<Vec<&str>> {
+    let mut stderr_per_description = vec![Vec::new(); count];
+    let mut current = None;
+    for line in stderr.lines() {
+        if let StderrLine::Trace(trace) = StderrLine::parse(line) {
+            if let Some(index) = trace.strip_prefix(BATCH_MARKER) {
+                current = index.parse::<usize>().ok();
+                continue;
+            }
+        }
+        match current.and_then(|index| stderr_per_description.get_mut(index)) {
+            Some(lines) => lines.push(line),
+            None => log::debug!("{}", line),
+        }
     }
+    stderr_per_description
+}
+
+pub fn describe_derivation(args: &DescribeDerivationArgs) -> Result<DerivationDescription> {
+    let command = describe_command(
+        args,
+        ("TARGET_ATTRIBUTE_PATH", args.attribute_path.to_owned()),
+    );
+    let (stdout, stderr) =
+        run_describe_command(args, command, args.eval_timeout, &args.attribute_path)?;
 
     // Parse the stdout as JSON
     let mut description: DerivationDescription = match serde_json::from_str(stdout.trim()) {
@@ -153,12 +729,286 @@ pub fn describe_derivation(args: &DescribeDerivationArgs) -> Result<DerivationDe
         Err(e) => return Err(Error::SerdeJSON(args.attribute_path.to_owned(), e)),
     };
 
-    if args.include_nar_info && description.output_path.is_some() {
-        let output_path = description.output_path.clone().unwrap();
-        let narinfo = super::narinfo::NarInfo::fetch(&output_path, args.binary_caches)?;
-
-        description.nar_info = narinfo;
-    };
+    complete_description(args, &mut description, &stderr.lines().collect::<Vec<_>>())?;
 
     Ok(description)
 }
+
+/// Describe several derivations of the flake with a single `nix eval`, sparing the cost of
+/// starting nix and evaluating the flake for each of them. `args.attribute_path` is ignored.
+///
+/// A single derivation failing to evaluate fails the whole batch, describe them one by one with
+/// [`describe_derivation`] then to know which ones fail. The timeout applies to each derivation,
+/// so the batch may take `attribute_paths.len()` times as long.
+pub fn describe_derivations(
+    args: &DescribeDerivationArgs,
+    attribute_paths: &[String],
+) -> Result<Vec<DerivationDescription>> {
+    let target = attribute_paths.join(" ");
+    let attribute_paths_json =
+        serde_json::to_string(attribute_paths).map_err(|e| Error::SerdeJSON(target.clone(), e))?;
+    let command = describe_command(args, ("TARGET_ATTRIBUTE_PATHS", attribute_paths_json));
+    let timeout = args.eval_timeout.map(|timeout| {
+        timeout.saturating_mul(attribute_paths.len().try_into().unwrap_or(u32::MAX))
+    });
+    let (stdout, stderr) = run_describe_command(args, command, timeout, &target)?;
+
+    let mut descriptions: Vec<DerivationDescription> =
+        serde_json::from_str(stdout.trim()).map_err(|e| Error::SerdeJSON(target.clone(), e))?;
+
+    let stderr_per_description = split_batch_stderr(&stderr, descriptions.len());
+    for (description, stderr) in descriptions.iter_mut().zip(&stderr_per_description) {
+        complete_description(args, description, stderr)?;
+    }
+
+    Ok(descriptions)
+}
+
+/// Fill in the sizes of the output path, when it is realised in the local store
+pub(super) fn query_store_size(
+    args: &DescribeDerivationArgs,
+    description: &mut DerivationDescription,
+) -> Result<()> {
+    if let Some(output_path) = &description.output_path {
+        if let Some(size) =
+            super::narinfo::StoreSize::query(output_path, args.eval_timeout, args.nix_binary)?
+        {
+            description.self_size = Some(size.self_size);
+            description.closure_size = Some(size.closure_size);
+        }
+    }
+    Ok(())
+}
+
+/// Only keep the build inputs that are referenced by one of the outputs of the derivation.
+/// Falls back to the inputs `RuntimeSource::Eval` would use if the references are unknown.
+pub(super) fn filter_runtime_inputs(
+    args: &DescribeDerivationArgs,
+    description: &mut DerivationDescription,
+) -> Result<()> {
+    let mut references = std::collections::HashSet::new();
+    for output_path in description
+        .outputs
+        .iter()
+        .filter_map(|output| output.output_path.as_ref())
+    {
+        match store_references(args, output_path)? {
+            Some(output_references) => references.extend(output_references),
+            None => {
+                log::warn!(
+                    "{} is not realised locally nor in a binary cache, using the evaluated runtime dependencies of {}",
+                    output_path,
+                    description.attribute_path
+                );
+                description
+                    .build_inputs
+                    .retain(|input| input.build_input_type.is_runtime());
+                return Ok(());
+            }
+        }
+    }
+
+    // The references can be to any output of an input, e.g. to the libraries in the `out` of
+    // openssl while `bin` is the output that is listed
+    description.build_inputs.retain(|input| {
+        input
+            .output_path
+            .iter()
+            .chain(&input.all_output_paths)
+            .any(|output_path| references.contains(output_path))
+    });
+
+    Ok(())
+}
+
+/// The store paths referenced by `output_path`, `None` if the path is neither valid in the local
+/// store nor available in one of the binary caches
+fn store_references(
+    args: &DescribeDerivationArgs,
+    output_path: &str,
+) -> Result<Option<Vec<String>>> {
+    let mut command = super::command::nix_store(args.nix_binary);
+    command.args(["--query", "--references", output_path]);
+
+    let output = super::command::output_with_timeout(&mut command, args.eval_timeout)?
+        .ok_or_else(|| Error::NixTimeout(output_path.to_owned()))?;
+
+    if output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        return Ok(Some(stdout.lines().map(str::to_owned).collect()));
+    }
+
+    log::debug!(
+        "nix-store could not query the references of {}: {}",
+        output_path,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if args.offline {
+        return Ok(None);
+    }
+
+    let narinfo = super::narinfo::NarInfo::fetch(
+        args.http_client,
+        args.narinfo_fetch,
+        output_path,
+        args.binary_caches,
+    )?;
+    Ok(narinfo.map(|narinfo| narinfo.reference_paths()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        // As emitted by `describeSource` in lib.nix
+        let sources: Vec<Source> = serde_json::from_str(
+            r#"[
+                {"type": "git", "url": "https://github.com/hello/hello.git", "rev": "v1.0"},
+                {"type": "url", "url": "mirror://gnu/hello/hello-2.12.1.tar.gz", "sha256": null},
+                {"type": "path", "path": "/nix/store/00000000000000000000000000000000-source"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            sources,
+            vec![
+                Source::Git {
+                    url: "https://github.com/hello/hello.git".to_owned(),
+                    rev: "v1.0".to_owned()
+                },
+                Source::Url {
+                    url: "mirror://gnu/hello/hello-2.12.1.tar.gz".to_owned(),
+                    sha256: None
+                },
+                Source::Path {
+                    path: "/nix/store/00000000000000000000000000000000-source".to_owned()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_input_type() {
+        let types: Vec<BuildInputType> = serde_json::from_str(
+            r#"["buildInputs", "nativeCheckInputs", "srcs", "depsHostHostPropagated", "doc"]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            types,
+            vec![
+                BuildInputType::BuildInput,
+                BuildInputType::NativeCheckInput,
+                BuildInputType::Sources,
+                BuildInputType::DepsHostHostPropagated,
+                BuildInputType::Other("doc".to_owned()),
+            ]
+        );
+        // Serialized back as the attribute they come from
+        assert_eq!(
+            serde_json::to_string(&types).unwrap(),
+            r#"["buildInputs","nativeCheckInputs","srcs","depsHostHostPropagated","doc"]"#
+        );
+
+        for known in BuildInputType::KNOWN {
+            assert_eq!(BuildInputType::from(known.as_str()), known);
+            let serialized = serde_json::to_string(&known).unwrap();
+            assert_eq!(serialized, format!("\"{}\"", known.as_str()));
+            assert_eq!(
+                serde_json::from_str::<BuildInputType>(&serialized).unwrap(),
+                known
+            );
+        }
+        assert_eq!(
+            serde_json::to_string(&BuildInputType::Other("doc".to_owned())).unwrap(),
+            r#""doc""#
+        );
+    }
+
+    #[test]
+    fn test_legacy_schema_version() {
+        let hello = crate::format::tests::description("hello", "2.12.1", &[]);
+        let mut legacy = serde_json::to_value(&hello).unwrap();
+        legacy.as_object_mut().unwrap().remove("schema_version");
+
+        let legacy: DerivationDescription = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.schema_version, "1.0.0");
+    }
+
+    #[test]
+    fn test_split_batch_stderr() {
+        let stderr = "warning: unknown setting 'foo'
+trace: nixtract-describing: 0
+trace: warning: python2 is deprecated
+trace: nixtract-describing: 1
+trace: nixtract-describing: 2
+evaluation warning: this option is obsolete
+";
+        assert_eq!(
+            split_batch_stderr(stderr, 3),
+            vec![
+                vec!["trace: warning: python2 is deprecated"],
+                vec![],
+                vec!["evaluation warning: this option is obsolete"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let mut hello = crate::format::tests::description(
+            "hello",
+            "2.12.1",
+            &["/nix/store/00000000000000000000000000000000-glibc-2.38"],
+        );
+        assert_eq!(
+            hello.to_string(),
+            "hello 2.12.1 (hello) — 1 build input, license: unknown"
+        );
+
+        hello.parsed_name.version = String::new();
+        hello.build_inputs.clear();
+        hello.nixpkgs_metadata.licenses = Some(vec![
+            License {
+                spdx_id: Some("GPL-3.0-or-later".to_owned()),
+                full_name: "GNU General Public License v3.0 or later".to_owned(),
+                short_name: None,
+                free: None,
+            },
+            License {
+                spdx_id: None,
+                full_name: "Unfree".to_owned(),
+                short_name: None,
+                free: None,
+            },
+        ]);
+        assert_eq!(
+            hello.to_string(),
+            "hello (hello) — 0 build inputs, license: GPL-3.0-or-later, Unfree"
+        );
+    }
+
+    #[test]
+    fn test_purl() {
+        let parsed_name = ParsedName {
+            name: "hello".to_owned(),
+            version: "2.12.1".to_owned(),
+        };
+        assert_eq!(
+            purl("github:NixOS/nixpkgs/nixos-23.11", "hello", &parsed_name),
+            "pkg:nix/hello@2.12.1?attribute_path=hello&flake_ref=github%3ANixOS%2Fnixpkgs%2Fnixos-23.11"
+        );
+
+        let parsed_name = ParsedName {
+            name: "source".to_owned(),
+            version: String::new(),
+        };
+        assert_eq!(
+            purl("nixpkgs", "hello.drvAttrs.src", &parsed_name),
+            "pkg:nix/source?attribute_path=hello.drvAttrs.src&flake_ref=nixpkgs"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Reading the contents of `.drv` files with `nix derivation show`
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// The parts of a `.drv` file needed to reproduce its build
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Drv {
+    /// The program that is run to build the derivation
+    pub builder: String,
+    /// The arguments passed to the builder
+    pub args: Vec<String>,
+    /// The environment of the builder
+    pub env: BTreeMap<String, String>,
+    /// The derivations this one depends on, with the names of the outputs that are used
+    pub input_drvs: BTreeMap<String, Vec<String>>,
+    /// The system the derivation is built on, e.g. "x86_64-linux"
+    #[serde(default)]
+    pub system: String,
+    /// The paths of the outputs by name, `None` when they are only known once built
+    #[serde(default)]
+    pub outputs: BTreeMap<String, Option<String>>,
+}
+
+/// The shape of a derivation in the output of `nix derivation show`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ShownDrv {
+    builder: String,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    input_drvs: BTreeMap<String, InputDrvOutputs>,
+    system: String,
+    outputs: BTreeMap<String, ShownOutput>,
+}
+
+#[derive(Deserialize)]
+struct ShownOutput {
+    /// Missing for content-addressed derivations
+    path: Option<String>,
+}
+
+/// Older versions of nix list the outputs directly, newer ones nest them next to the dynamic outputs
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputDrvOutputs {
+    List(Vec<String>),
+    Nested { outputs: Vec<String> },
+}
+
+impl Drv {
+    /// Read the derivation at `derivation_path`
+    pub fn show(
+        derivation_path: &str,
+        timeout: Option<std::time::Duration>,
+        nix_binary: Option<&std::path::Path>,
+    ) -> Result<Self> {
+        Self::show_many(&[derivation_path], timeout, nix_binary)?
+            .into_values()
+            .next()
+            .ok_or_else(|| {
+                Error::NixCommand(
+                    None,
+                    format!(
+                        "nix derivation show returned nothing for {}",
+                        derivation_path
+                    ),
+                )
+            })
+    }
+
+    /// Read several derivations with a single nix call, keyed by their path
+    pub fn show_many(
+        derivation_paths: &[&str],
+        timeout: Option<std::time::Duration>,
+        nix_binary: Option<&std::path::Path>,
+    ) -> Result<BTreeMap<String, Self>> {
+        if derivation_paths.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let mut command = super::command::nix(nix_binary);
+        command
+            .args(["derivation", "show"])
+            .args(derivation_paths)
+            .args(["--extra-experimental-features", "nix-command"]);
+
+        let output = super::command::output_with_timeout(&mut command, timeout)?
+            .ok_or_else(|| Error::NixTimeout(derivation_paths.join(" ")))?;
+
+        if !output.status.success() {
+            return Err(Error::NixCommand(
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Self::parse(
+            &derivation_paths.join(" "),
+            &String::from_utf8_lossy(&output.stdout),
+        )
+    }
+
+    /// Parse the output of `nix derivation show`, which maps the derivation paths to their contents
+    fn parse(derivation_paths: &str, output: &str) -> Result<BTreeMap<String, Self>> {
+        let shown: BTreeMap<String, ShownDrv> = serde_json::from_str(output)
+            .map_err(|e| Error::SerdeJSON(derivation_paths.to_owned(), e))?;
+
+        Ok(shown
+            .into_iter()
+            .map(|(derivation_path, shown)| {
+                let drv = Drv {
+                    builder: shown.builder,
+                    args: shown.args,
+                    env: shown.env,
+                    input_drvs: shown
+                        .input_drvs
+                        .into_iter()
+                        .map(|(path, outputs)| match outputs {
+                            InputDrvOutputs::List(outputs)
+                            | InputDrvOutputs::Nested { outputs } => (path, outputs),
+                        })
+                        .collect(),
+                    system: shown.system,
+                    outputs: shown
+                        .outputs
+                        .into_iter()
+                        .map(|(name, output)| (name, output.path))
+                        .collect(),
+                };
+                (derivation_path, drv)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let output = r#"{
+  "/nix/store/57677sld6ja212hkv1gh8bdm0amnk1hz-hello-2.12.1.drv": {
+    "args": ["-e", "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"],
+    "builder": "/nix/store/8fv91097mbh5049i9rglc73dx6kjg3qk-bash-5.2-p15/bin/bash",
+    "env": { "name": "hello-2.12.1", "out": "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1" },
+    "inputDrvs": {
+      "/nix/store/1b5rrdnmn8pzzzpd4k5c8i0jqzhxmpfr-bash-5.2-p15.drv": { "dynamicOutputs": {}, "outputs": ["out"] },
+      "/nix/store/4p3dz8yqblsnd6n3fw5mj2c8bmpdzpgf-stdenv-linux.drv": ["out"]
+    },
+    "inputSrcs": [],
+    "outputs": { "out": { "path": "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1" } },
+    "system": "x86_64-linux"
+  }
+}"#;
+
+        let drv = Drv::parse("hello", output)
+            .unwrap()
+            .remove("/nix/store/57677sld6ja212hkv1gh8bdm0amnk1hz-hello-2.12.1.drv")
+            .unwrap();
+        assert_eq!(
+            drv.builder,
+            "/nix/store/8fv91097mbh5049i9rglc73dx6kjg3qk-bash-5.2-p15/bin/bash"
+        );
+        assert_eq!(drv.args.len(), 2);
+        assert_eq!(drv.env["name"], "hello-2.12.1");
+        assert_eq!(drv.system, "x86_64-linux");
+        assert_eq!(
+            drv.outputs,
+            BTreeMap::from([(
+                "out".to_owned(),
+                Some("/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_owned())
+            )])
+        );
+        assert_eq!(
+            drv.input_drvs,
+            BTreeMap::from([
+                (
+                    "/nix/store/1b5rrdnmn8pzzzpd4k5c8i0jqzhxmpfr-bash-5.2-p15.drv".to_owned(),
+                    vec!["out".to_owned()]
+                ),
+                (
+                    "/nix/store/4p3dz8yqblsnd6n3fw5mj2c8bmpdzpgf-stdenv-linux.drv".to_owned(),
+                    vec!["out".to_owned()]
+                ),
+            ])
+        );
+    }
+}
@@ -6,6 +6,8 @@
   TARGET_FLAKE_REF: flake reference to evaluate
   TARGET_ATTRIBUTE_PATH: attribute to evaluate
   TARGET_SYSTEM: system to evaluate
+  TARGET_FLAKE_OUTPUT: flake output category to search, "packages" (default), "checks", "devShells",
+    "nixosConfigurations" or "homeConfigurations"
 
   Example:
   TARGET_FLAKE_REF="nixpkgs" TARGET_ATTRIBUTE_PATH="haskellPackages.hello" TARGET_SYSTEM="x86_64-linux" nix eval --json --file ./find-attribute-paths.nix
@@ -19,10 +21,12 @@ let
   targetFlakeRef = builtins.getEnv "TARGET_FLAKE_REF";
   targetAttributePath = builtins.getEnv "TARGET_ATTRIBUTE_PATH";
   targetSystem = let env = builtins.getEnv "TARGET_SYSTEM"; in if env == "" then builtins.currentSystem else env;
+  targetFlakeOutput = let env = builtins.getEnv "TARGET_FLAKE_OUTPUT"; in if env == "" then "packages" else env;
 
   # Get pkgs
   targetFlake = builtins.getFlake targetFlakeRef;
-  targetFlakePkgs = lib.getFlakePkgs targetFlake targetSystem;
+  targetFlakePkgs = lib.getFlakeOutput targetFlake targetFlakeOutput targetSystem;
+  outputType = lib.flakeOutputAttribute targetFlake targetFlakeOutput targetSystem;
   targetRootValue =
     if isNull targetAttributePath || targetAttributePath == ""
     then targetFlakePkgs
@@ -32,8 +36,29 @@ let
   describeDrv = drv: {
     derivationPath = lib.safePlatformDrvEval targetSystem (drv: drv.drvPath) drv;
     outputPath = lib.safePlatformDrvEval targetSystem (drv: drv.outPath) drv;
+    inherit outputType;
   };
 
+  # Configurations are not derivations, the derivation building each of them is found instead.
+  # Evaluating it can print many warnings, which end up as traces, and configurations that fail to
+  # evaluate are left out rather than failing the whole discovery.
+  configurationToplevel = lib.configurationToplevels.${targetFlakeOutput} or null;
+  targetConfigurations =
+    if isNull targetAttributePath || targetAttributePath == ""
+    then targetFlakePkgs
+    else
+      let name = builtins.head (nixpkgs.lib.splitString "." targetAttributePath);
+      in { ${name} = targetFlakePkgs.${name}; };
+  findConfiguration = name: configuration:
+    let
+      toplevel = lib.safeEval (lib.getValueAtPath configuration configurationToplevel);
+      foundDrvs =
+        if nixpkgs.lib.isDerivation toplevel
+        then [ (describeDrv toplevel // { attributePath = "${name}.${configurationToplevel}"; configuration = name; }) ]
+        else [ ];
+    in
+    builtins.trace (builtins.toJSON { inherit foundDrvs; }) foundDrvs;
+
   # Helper function to find derivations in a deeply nested attribute set.
   # To be used on key-value pairs in an attribute set.
   # While recursing, it builds the attribute path to the currently evaluated key-value pair.
@@ -72,4 +97,6 @@ let
   ;
 in
 # to prevent accumlutation in memory
-lib.collect (x: false) (builtins.mapAttrs (findRecursively targetAttributePath) targetRootValue)
+if configurationToplevel != null
+then lib.collect (x: false) (builtins.mapAttrs findConfiguration targetConfigurations)
+else lib.collect (x: false) (builtins.mapAttrs (findRecursively targetAttributePath) targetRootValue)
@@ -1,7 +1,13 @@
-use std::{collections::HashMap, process::Command};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    sync::mpsc,
+};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use super::command::StderrLine;
 use super::lib::Lib;
 use crate::error::{Error, Result};
 
@@ -11,8 +17,89 @@ pub struct AttributePaths {
     pub found_drvs: Vec<FoundDrv>,
 }
 
-#[derive(Default, Debug, Clone, Deserialize, PartialEq, Hash, Eq)]
+/// Category of flake outputs derivations are discovered in
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+    clap::ValueEnum,
+)]
 #[serde(rename_all = "camelCase")]
+pub enum FlakeOutput {
+    /// `packages`, or `legacyPackages` for flakes such as nixpkgs
+    #[default]
+    Packages,
+    /// `checks`
+    Checks,
+    /// `devShells`
+    #[value(name = "devShells")]
+    DevShells,
+    /// The system toplevel of each `nixosConfigurations` entry, for the target system
+    #[value(name = "nixosConfigurations")]
+    NixosConfigurations,
+    /// The activation package of each `homeConfigurations` entry, for the target system
+    #[value(name = "homeConfigurations")]
+    HomeConfigurations,
+}
+
+impl FlakeOutput {
+    /// Name of the output as passed to the nix expressions
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlakeOutput::Packages => "packages",
+            FlakeOutput::Checks => "checks",
+            FlakeOutput::DevShells => "devShells",
+            FlakeOutput::NixosConfigurations => "nixosConfigurations",
+            FlakeOutput::HomeConfigurations => "homeConfigurations",
+        }
+    }
+}
+
+/// Which packages nixpkgs lets through evaluation, through the `NIXPKGS_ALLOW_*` environment
+/// variables. Evaluating a package that is not allowed fails, so it is neither discovered nor
+/// described. Everything is allowed by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NixpkgsAllow {
+    /// Packages with a license that is not free
+    pub unfree: bool,
+    /// Packages with known vulnerabilities
+    pub insecure: bool,
+    /// Packages marked as broken
+    pub broken: bool,
+}
+
+impl Default for NixpkgsAllow {
+    fn default() -> Self {
+        NixpkgsAllow {
+            unfree: true,
+            insecure: true,
+            broken: true,
+        }
+    }
+}
+
+impl NixpkgsAllow {
+    /// The environment variables to pass to the nix evaluation
+    pub fn env_vars(&self) -> [(String, String); 3] {
+        let flag = |allow: bool| if allow { "1" } else { "0" }.to_owned();
+        [
+            ("NIXPKGS_ALLOW_UNFREE".to_owned(), flag(self.unfree)),
+            ("NIXPKGS_ALLOW_INSECURE".to_owned(), flag(self.insecure)),
+            ("NIXPKGS_ALLOW_BROKEN".to_owned(), flag(self.broken)),
+        ]
+    }
+}
+
+/// A top-level derivation found in the flake, serialized with snake_case keys like descriptions
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq, Hash, Eq)]
+#[serde(rename_all(deserialize = "camelCase"))]
 pub struct FoundDrv {
     pub attribute_path: String,
     /// drv path of the derivation
@@ -21,28 +108,73 @@ pub struct FoundDrv {
     /// The output path of the derivation
     /// We discard any values that are not null or String, which occasionally occur (namely false)
     pub output_path: Option<String>,
+    /// The system the derivation was found for, `None` for the host system
+    #[serde(skip_deserializing)]
+    pub system: Option<String>,
+    /// The flake output category the attribute path is relative to
+    #[serde(skip_deserializing)]
+    pub flake_output: FlakeOutput,
+    /// The attribute of the flake outputs the derivation was found in, e.g. "legacyPackages"
+    /// when `flake_output` is `packages`. Empty when the discovery was skipped.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub output_type: String,
+    /// Name of the NixOS or home-manager configuration the derivation builds, for the
+    /// configuration flake outputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct FindAttributePathsArgs<'a> {
+    pub flake_ref: &'a String,
+    pub system: &'a Option<String>,
+    pub attribute_path: &'a Option<String>,
+    pub flake_output: FlakeOutput,
+    pub offline: bool,
+    /// Run this nix executable instead of the one on the PATH
+    pub nix_binary: Option<&'a std::path::Path>,
+    /// Extra arguments passed to `nix eval` as is
+    pub nix_args: &'a [String],
+    pub nixpkgs_allow: NixpkgsAllow,
+    /// Limit the memory of the nix evaluation to this many bytes, on Linux
+    pub max_eval_memory: Option<u64>,
+    /// Fail when a found derivation cannot be parsed, instead of skipping it
+    pub strict: bool,
+    pub lib: &'a Lib,
 }
 
+/// Finds the derivations below `attribute_path`, sending each one through `tx` as soon as nix
+/// reports it. Returns the number of derivations that were sent.
 pub fn find_attribute_paths(
-    flake_ref: &String,
-    system: &Option<String>,
-    attribute_path: &Option<String>,
-    offline: &bool,
-    lib: &Lib,
-) -> Result<Vec<AttributePaths>> {
+    args: &FindAttributePathsArgs,
+    tx: mpsc::Sender<FoundDrv>,
+) -> Result<usize> {
+    let FindAttributePathsArgs {
+        flake_ref,
+        system,
+        attribute_path,
+        flake_output,
+        offline,
+        nix_binary,
+        nix_args,
+        nixpkgs_allow,
+        max_eval_memory,
+        strict,
+        lib,
+    } = *args;
     let expr = include_str!("find_attribute_paths.nix");
 
     // Create a scope so env_vars isn't needlessly mutable
     let env_vars: HashMap<String, String> = {
-        let mut res = HashMap::from([
-            ("TARGET_FLAKE_REF".to_owned(), flake_ref.to_owned()),
-            ("NIXPKGS_ALLOW_UNFREE".to_owned(), "1".to_owned()),
-            ("NIXPKGS_ALLOW_INSECURE".to_owned(), "1".to_owned()),
-            ("NIXPKGS_ALLOW_BROKEN".to_owned(), "1".to_owned()),
-        ]);
+        let mut res = HashMap::from([("TARGET_FLAKE_REF".to_owned(), flake_ref.to_owned())]);
+        res.extend(nixpkgs_allow.env_vars());
         if let Some(attribute_path) = attribute_path {
             res.insert("TARGET_ATTRIBUTE_PATH".to_owned(), attribute_path.clone());
         }
+        res.insert(
+            "TARGET_FLAKE_OUTPUT".to_owned(),
+            flake_output.as_str().to_owned(),
+        );
         if let Some(system) = system {
             res.insert("TARGET_SYSTEM".to_owned(), system.to_owned());
         }
@@ -50,42 +182,70 @@ pub fn find_attribute_paths(
     };
 
     // Run the nix command, with the provided environment variables and expression
-    let mut command: Command = Command::new("nix");
+    let mut command: Command = super::command::nix(nix_binary);
     command
         .arg("eval")
         .arg("-I")
         .arg(format!("lib={}", lib.path().to_string_lossy()))
-        .args(["--json", "--expr", expr])
+        .arg("--json")
+        .args(nix_args)
+        .args(["--expr", expr])
         .arg("--impure")
         .args(["--extra-experimental-features", "flakes nix-command"])
         .envs(env_vars);
 
-    if *offline {
+    if offline {
         command.arg("--offline");
     }
+    super::command::limit_memory(&mut command, max_eval_memory);
 
-    let output = command.output()?;
+    // The derivations are reported on stderr while the evaluation is still running, the result
+    // of the evaluation itself is not used
+    let mut child = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| super::command::spawn_failure(&command, err))?;
+    let stderr = child.stderr.take().expect("stderr is piped");
 
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut found = 0;
+    // Lines that are not traces, kept to report them if the command fails
+    let mut errors = String::new();
 
-    // Check if the nix command was successful
-    if !output.status.success() {
-        return Err(Error::NixCommand(output.status.code(), stderr.to_string()));
-    }
-
-    let mut res: Vec<AttributePaths> = Vec::new();
-
-    for line in stderr.lines() {
+    for line in BufReader::new(stderr).lines() {
+        let line = line?;
         log::info!("find_attribute_paths line: {}", line);
 
-        if !line.starts_with("trace: ") {
-            log::warn!(
-                "Unexpected output from nix command, attempting to continue: {}",
-                line
-            );
-        } else {
-            match serde_json::from_str(line.trim_start_matches("trace: ")) {
-                Ok(attribute_paths) => res.push(attribute_paths),
+        match StderrLine::parse(&line) {
+            // Traces of the evaluation itself, such as the warnings of NixOS configurations
+            StderrLine::Trace(trace) if !trace.starts_with("{\"foundDrvs\"") => {
+                log::debug!("Trace while finding attribute paths: {}", trace);
+            }
+            StderrLine::Warning(warning) => {
+                log::debug!("Warning while finding attribute paths: {}", warning);
+            }
+            StderrLine::Trace(trace) => match serde_json::from_str::<AttributePaths>(trace) {
+                Ok(attribute_paths) => {
+                    for mut found_drv in attribute_paths.found_drvs {
+                        found += 1;
+                        found_drv.system = system.clone();
+                        found_drv.flake_output = flake_output;
+                        // The receiver hanging up means nobody is interested in the rest
+                        if tx.send(found_drv).is_err() {
+                            let _ = child.kill();
+                            let _ = child.wait();
+                            return Ok(found);
+                        }
+                    }
+                }
+                Err(e) if strict => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(Error::SerdeJSON(
+                        attribute_path.clone().unwrap_or_else(|| flake_ref.clone()),
+                        e,
+                    ));
+                }
                 Err(e) => {
                     log::warn!(
                         "Error parsing found_derivation output: {} {}. Attempting to continue...",
@@ -93,9 +253,59 @@ pub fn find_attribute_paths(
                         e
                     );
                 }
-            };
+            },
+            StderrLine::Other(_) => {
+                log::warn!(
+                    "Unexpected output from nix command, attempting to continue: {}",
+                    line
+                );
+                errors.push_str(&line);
+                errors.push('\n');
+            }
         }
     }
 
-    Ok(res)
+    // Check if the nix command was successful
+    let status = child.wait()?;
+    if max_eval_memory.is_some() && super::command::out_of_memory(&status, &errors) {
+        return Err(Error::EvalOutOfMemory(
+            attribute_path.clone().unwrap_or_else(|| flake_ref.clone()),
+        ));
+    }
+    if !status.success() {
+        return Err(super::command::nix_failure(
+            status.code(),
+            errors,
+            offline,
+            attribute_path.as_deref(),
+        ));
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_found_drv_serde() {
+        let found_drv: FoundDrv = serde_json::from_str(
+            r#"{"attributePath": "hello", "derivationPath": null, "outputPath": "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1"}"#,
+        )
+        .unwrap();
+        assert_eq!(found_drv.attribute_path, "hello");
+
+        assert_eq!(
+            serde_json::to_string(&found_drv).unwrap(),
+            r#"{"attribute_path":"hello","derivation_path":null,"output_path":"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","system":null,"flake_output":"packages"}"#
+        );
+
+        let found_drv: FoundDrv = serde_json::from_str(
+            r#"{"attributePath": "laptop.config.system.build.toplevel", "derivationPath": null, "outputPath": null, "outputType": "nixosConfigurations", "configuration": "laptop"}"#,
+        )
+        .unwrap();
+        assert_eq!(found_drv.configuration.as_deref(), Some("laptop"));
+        assert_eq!(found_drv.output_type, "nixosConfigurations");
+    }
 }
@@ -0,0 +1,125 @@
+//! Resolve a flake reference to the exact revision nix evaluates, with `nix flake metadata`
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// What a flake reference resolved to, the subset of `nix flake metadata --json` that identifies
+/// the evaluated flake
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlakeMetadata {
+    /// The locked URL of the flake, e.g. `github:NixOS/nixpkgs/<rev>`
+    pub url: Option<String>,
+    /// The git revision of the flake, `None` for flakes that are not in a git repository or that
+    /// have uncommitted changes
+    pub revision: Option<String>,
+    /// The revision the uncommitted changes are on top of, suffixed with `-dirty`
+    pub dirty_revision: Option<String>,
+    /// Hash of the contents of the flake, as in the lock files of flakes depending on it
+    pub nar_hash: Option<String>,
+    /// Unix timestamp of the last commit, or the last modification for non-git flakes
+    pub last_modified: Option<i64>,
+}
+
+/// `nix flake metadata` reports the hash inside `locked`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Locked {
+    nar_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawFlakeMetadata {
+    url: Option<String>,
+    revision: Option<String>,
+    dirty_revision: Option<String>,
+    last_modified: Option<i64>,
+    locked: Option<Locked>,
+}
+
+impl FlakeMetadata {
+    /// Resolve `flake_ref` with `nix flake metadata`, run with `nix_binary` if given
+    pub fn get(
+        flake_ref: &str,
+        offline: bool,
+        nix_binary: Option<&std::path::Path>,
+    ) -> Result<Self> {
+        let mut command = super::command::nix(nix_binary);
+        command
+            .args(["flake", "metadata", "--json", flake_ref])
+            .args(["--extra-experimental-features", "flakes nix-command"]);
+        if offline {
+            command.arg("--offline");
+        }
+
+        let output = command
+            .output()
+            .map_err(|err| super::command::spawn_failure(&command, err))?;
+
+        if !output.status.success() {
+            return Err(super::command::nix_failure(
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr).to_string(),
+                offline,
+                None,
+            ));
+        }
+
+        Self::parse(flake_ref, &String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn parse(flake_ref: &str, output: &str) -> Result<Self> {
+        let raw: RawFlakeMetadata =
+            serde_json::from_str(output).map_err(|e| Error::SerdeJSON(flake_ref.to_owned(), e))?;
+
+        Ok(FlakeMetadata {
+            url: raw.url,
+            revision: raw.revision,
+            dirty_revision: raw.dirty_revision,
+            nar_hash: raw.locked.and_then(|locked| locked.nar_hash),
+            last_modified: raw.last_modified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let output = r#"{
+            "description": "A collection of packages for the Nix package manager",
+            "lastModified": 1700000000,
+            "locked": {
+                "lastModified": 1700000000,
+                "narHash": "sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+                "owner": "NixOS",
+                "repo": "nixpkgs",
+                "rev": "0123456789abcdef0123456789abcdef01234567",
+                "type": "github"
+            },
+            "locks": null,
+            "original": { "id": "nixpkgs", "type": "indirect" },
+            "originalUrl": "flake:nixpkgs",
+            "path": "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-source",
+            "resolvedUrl": "github:NixOS/nixpkgs/nixpkgs-unstable",
+            "revision": "0123456789abcdef0123456789abcdef01234567",
+            "url": "github:NixOS/nixpkgs/0123456789abcdef0123456789abcdef01234567"
+        }"#;
+
+        pretty_assertions::assert_eq!(
+            FlakeMetadata::parse("nixpkgs", output).unwrap(),
+            FlakeMetadata {
+                url: Some(
+                    "github:NixOS/nixpkgs/0123456789abcdef0123456789abcdef01234567".to_owned()
+                ),
+                revision: Some("0123456789abcdef0123456789abcdef01234567".to_owned()),
+                dirty_revision: None,
+                nar_hash: Some("sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=".to_owned()),
+                last_modified: Some(1700000000),
+            }
+        );
+    }
+}
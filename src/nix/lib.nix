@@ -33,6 +33,46 @@ rec {
   */
   getFlakePkgs = flake: targetSystem: flake.outputs.packages.${targetSystem} or flake.outputs.defaultPackage.${targetSystem} or flake.outputs.legacyPackages.${targetSystem} or { };
 
+  /* The derivations of a flake output category for a system, "packages" also covers `legacyPackages`
+    Type: flake -> str -> str -> attrs
+
+    Example:
+    getFlakeOutput (builtins.getFlake "nixpkgs") "checks" "x86_64-linux"
+  */
+  getFlakeOutput = flake: flakeOutput: targetSystem:
+    if flakeOutput == "packages"
+    then getFlakePkgs flake targetSystem
+    else if configurationToplevels ? ${flakeOutput}
+    then
+      nixpkgs.lib.filterAttrs
+        (name: configuration: safeEval configuration.pkgs.stdenv.hostPlatform.system == targetSystem)
+        (flake.outputs.${flakeOutput} or { })
+    else flake.outputs.${flakeOutput}.${targetSystem} or { };
+
+  /* The attribute of the flake outputs that `getFlakeOutput` reads, e.g. "legacyPackages" for the "packages" of nixpkgs
+    Type: flake -> str -> str -> str
+
+    Example:
+    flakeOutputAttribute (builtins.getFlake "nixpkgs") "packages" "x86_64-linux"
+    => "legacyPackages"
+  */
+  flakeOutputAttribute = flake: flakeOutput: targetSystem:
+    if flakeOutput == "packages"
+    then
+      nixpkgs.lib.findFirst
+        (attribute: flake.outputs ? ${attribute}.${targetSystem})
+        "packages"
+        [ "packages" "defaultPackage" "legacyPackages" ]
+    else flakeOutput;
+
+  /* Flake outputs of configurations, which are not per system but have a system of their own, with the
+    attribute path of the derivation building a configuration
+  */
+  configurationToplevels = {
+    nixosConfigurations = "config.system.build.toplevel";
+    homeConfigurations = "activationPackage";
+  };
+
 
   /* Follow "attribute path" (split by dot) to access value in tree of nested attribute sets and lists
     Type: (attrs | list) -> str -> any
@@ -71,6 +111,37 @@ rec {
     in
     recurse collection (builtins.split "\\." attributePath);
 
+  /* Describe a source of a derivation by where it comes from, see `Source` in describe_derivation.rs
+    Type: (derivation | path | str) -> attrs
+
+    Example:
+    describeSource (pkgs.fetchurl { url = "https://example.org/hello.tar.gz"; hash = "sha256-..."; })
+    => { type = "url"; url = "https://example.org/hello.tar.gz"; sha256 = "sha256-..."; }
+  */
+  describeSource = src:
+    if !nixpkgs.lib.isDerivation src
+    then { type = "path"; path = toString src; }
+    # fetchFromGitHub and friends
+    else if src ? gitRepoUrl && src ? rev
+    then { type = "git"; url = src.gitRepoUrl; rev = src.rev; }
+    # fetchgit
+    else if src ? url && src ? rev
+    then { type = "git"; url = src.url; rev = src.rev; }
+    # fetchurl and fetchzip
+    else if src ? urls || src ? url
+    then {
+      type = "url";
+      url = if src ? urls then builtins.head src.urls else src.url;
+      sha256 =
+        let hash = src.outputHash or null;
+        in
+        if hash != null && ((src.outputHashAlgo or null) == "sha256" || nixpkgs.lib.hasPrefix "sha256-" hash)
+        then hash
+        else null;
+    }
+    # Sources built by a derivation of their own
+    else { type = "path"; path = src.outPath; };
+
   /* Utility function for safe evaluation of any value, null if evaluation fails
   */
   safeEval = v: let
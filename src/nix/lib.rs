@@ -4,7 +4,11 @@ use crate::error::Result;
 use std::io::Write;
 use tempfile::NamedTempFile;
 
-#[derive(Clone, Debug)]
+/// The `lib.nix` file written to a temporary location, removed again when dropped.
+///
+/// Not `Clone`, so the file cannot be removed while another copy still refers to it; share it
+/// behind a reference or an `Arc` instead.
+#[derive(Debug)]
 pub struct Lib {
     inner: std::path::PathBuf,
 }
@@ -17,7 +21,8 @@ impl Lib {
 
         write!(file, "{}", lib)?;
 
-        let inner = file.into_temp_path().keep().unwrap();
+        // Keep the file past the NamedTempFile, it is removed when the Lib is dropped
+        let inner = file.into_temp_path().keep().map_err(|e| e.error)?;
 
         Ok(Lib { inner })
     }
@@ -26,3 +31,29 @@ impl Lib {
         &self.inner
     }
 }
+
+impl Drop for Lib {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.inner) {
+            log::warn!("Could not remove {}: {}", self.inner.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removed_on_drop() {
+        let lib = Lib::new().unwrap();
+        let path = lib.path().to_owned();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            include_str!("lib.nix")
+        );
+
+        drop(lib);
+        assert!(!path.exists());
+    }
+}
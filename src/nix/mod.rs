@@ -1,8 +1,14 @@
+pub(crate) mod command;
 mod describe_derivation;
+pub mod drv;
 mod find_attribute_paths;
+pub mod flake_metadata;
 pub(crate) mod lib;
 pub mod narinfo;
+pub mod netrc;
+mod store_derivation;
 pub mod substituters;
 
 pub use describe_derivation::*;
 pub use find_attribute_paths::*;
+pub use store_derivation::*;
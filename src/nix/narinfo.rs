@@ -6,14 +6,14 @@
 pub struct NarInfo {
     /// The path to the store where the nar is located.
     pub store_path: String,
-    /// The URL where the nar can be downloaded.
+    /// The URL where the nar can be downloaded, empty when read from the local store.
     pub url: String,
     /// The hash of the nar.
     pub nar_hash: String,
     /// The size of the nar in bytes.
     pub nar_size: usize,
     /// The compression method used on the nar.
-    pub compression: String,
+    pub compression: Compression,
     /// The hash of the file.
     pub file_hash: Option<String>,
     /// The size of the file in bytes.
@@ -24,17 +24,506 @@ pub struct NarInfo {
     pub system: Option<String>,
     /// The references of the nar.
     pub references: Option<Vec<String>>,
-    /// The signature of the nar.
+    /// The first signature of the nar, see `signatures` for all of them.
     pub sig: Option<String>,
+    /// Every signature of the nar, in the order they are listed. Empty when it is not signed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<String>,
     /// The content addressable storage identifier of the nar, if any.
     pub ca: Option<String>,
+    /// The binary cache the narinfo was fetched from, as given in the list of servers. `None`
+    /// when read from the local store or parsed directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<String>,
+}
+
+/// Compression method of a nar, serialized as the string used in narinfo files.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Compression {
+    None,
+    Xz,
+    Bzip2,
+    Zstd,
+    Gzip,
+    Brotli,
+    Lz4,
+    /// A method this version of nixtract does not know about
+    Other(String),
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Compression::None => "none",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+            Compression::Lz4 => "lz4",
+            Compression::Other(other) => other,
+        }
+    }
+}
+
+impl From<&str> for Compression {
+    fn from(value: &str) -> Self {
+        match value {
+            "none" => Compression::None,
+            "xz" => Compression::Xz,
+            "bzip2" => Compression::Bzip2,
+            "zstd" => Compression::Zstd,
+            "gzip" => Compression::Gzip,
+            "br" => Compression::Brotli,
+            "lz4" => Compression::Lz4,
+            other => Compression::Other(other.to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl serde::Serialize for Compression {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Compression {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(Compression::from(value.as_str()))
+    }
+}
+
+impl schemars::JsonSchema for Compression {
+    fn schema_name() -> String {
+        String::schema_name()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Where narinfo is read from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NarInfoSource {
+    /// The binary caches
+    #[default]
+    Remote,
+    /// `nix path-info` of the local store, falling back to the binary caches for paths that are
+    /// not valid in it. Much faster on machines where the paths are realised.
+    Local,
+}
+
+/// Controls how narinfo files are fetched from the binary caches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchConfig {
+    /// Whether the local store is asked before the binary caches
+    pub source: NarInfoSource,
+    /// How many times a request to a single cache is retried after a transient failure
+    /// (server error, rate limiting, timeout or connection error).
+    pub retries: usize,
+    /// Delay before the first retry, doubled for every subsequent retry.
+    pub initial_backoff: std::time::Duration,
+    /// How long to wait for a connection to a cache, see [`FetchConfig::client`].
+    pub connect_timeout: std::time::Duration,
+    /// How long a whole request may take, including reading the response, see
+    /// [`FetchConfig::client`]. Also bounds the `nix path-info` query of the local store.
+    pub timeout: std::time::Duration,
+    /// Credentials for private caches, keyed by host
+    pub credentials: std::collections::HashMap<String, super::netrc::Credentials>,
+    /// How many requests may be in flight to a single cache at once, shared by the clones of this
+    /// configuration
+    pub per_host: HostLimit,
+    /// Directory where fetched narinfo is kept, keyed by store hash, and read from before asking
+    /// the caches. Narinfo of a store path never changes, so entries are never invalidated.
+    pub cache_dir: Option<std::path::PathBuf>,
+    /// The nix executable queried for narinfo of the local store, instead of the one on the PATH
+    pub nix_binary: Option<std::path::PathBuf>,
+    /// Only read narinfo from local sources, like nix in offline mode: the local store, the
+    /// `cache_dir` and `file://` caches. HTTP caches are skipped.
+    pub offline: bool,
+    /// Fail on narinfo with keys nixtract does not know, see [`NarInfo::parse_strict`], instead
+    /// of ignoring them. The `cache_dir` is then only written to, its entries may come from runs
+    /// that were not strict.
+    pub strict: bool,
+}
+
+/// `$XDG_CACHE_HOME/nixtract/narinfo`, or `~/.cache/nixtract/narinfo` if it is not set
+pub fn default_cache_dir() -> Option<std::path::PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|cache_home| !cache_home.is_empty())
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::PathBuf::from(home).join(".cache"))
+        })?;
+    Some(cache_home.join("nixtract").join("narinfo"))
+}
+
+/// Caps the number of concurrent requests to each cache, keyed by the cache URL as given in the
+/// list of servers. Clones share their counts, so a limit applies across all threads fetching
+/// with clones of the same [`FetchConfig`].
+#[derive(Debug, Clone, Default)]
+pub struct HostLimit {
+    limit: Option<usize>,
+    in_flight: std::sync::Arc<(
+        std::sync::Mutex<std::collections::HashMap<String, usize>>,
+        std::sync::Condvar,
+    )>,
+}
+
+/// Equal if they have the same limit, the requests currently in flight are not compared
+impl PartialEq for HostLimit {
+    fn eq(&self, other: &Self) -> bool {
+        self.limit == other.limit
+    }
+}
+
+impl Eq for HostLimit {}
+
+impl HostLimit {
+    /// At most `limit` concurrent requests per cache, `None` for no limit
+    pub fn new(limit: Option<usize>) -> Self {
+        HostLimit {
+            // A limit of 0 would block forever
+            limit: limit.map(|limit| limit.max(1)),
+            in_flight: Default::default(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Wait until a request to `server` is allowed, it is counted as in flight until the returned
+    /// permit is dropped
+    fn acquire<'a>(&'a self, server: &'a str) -> HostPermit<'a> {
+        if let Some(limit) = self.limit {
+            let (in_flight, released) = &*self.in_flight;
+            let mut in_flight = in_flight.lock().unwrap();
+            while in_flight.get(server).copied().unwrap_or(0) >= limit {
+                in_flight = released.wait(in_flight).unwrap();
+            }
+            *in_flight.entry(server.to_owned()).or_insert(0) += 1;
+        }
+        HostPermit {
+            host_limit: self,
+            server,
+        }
+    }
+}
+
+/// A request in flight to a cache, see [`HostLimit::acquire`]
+struct HostPermit<'a> {
+    host_limit: &'a HostLimit,
+    server: &'a str,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        if self.host_limit.limit.is_none() {
+            return;
+        }
+        let (in_flight, released) = &*self.host_limit.in_flight;
+        let mut in_flight = in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(self.server) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(self.server);
+            }
+        }
+        released.notify_all();
+    }
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        FetchConfig {
+            source: NarInfoSource::default(),
+            retries: 3,
+            initial_backoff: std::time::Duration::from_millis(500),
+            connect_timeout: std::time::Duration::from_secs(10),
+            timeout: std::time::Duration::from_secs(30),
+            credentials: std::collections::HashMap::new(),
+            per_host: HostLimit::default(),
+            cache_dir: None,
+            nix_binary: None,
+            offline: false,
+            strict: false,
+        }
+    }
+}
+
+impl FetchConfig {
+    /// The delay to wait before the given retry (starting at 1).
+    pub fn backoff(&self, retry: usize) -> std::time::Duration {
+        let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(exponent))
+    }
+
+    /// The credentials to send with a request to this URL, if any are configured for its host
+    fn credentials(&self, url: &str) -> Option<&super::netrc::Credentials> {
+        let url = reqwest::Url::parse(url).ok()?;
+        self.credentials.get(url.host_str()?)
+    }
+
+    /// An HTTP client with the timeouts of this configuration, to share between all fetches so
+    /// connections to the caches are kept alive. A cache that times out is treated like any
+    /// other failed fetch, so a hung server does not stall the fetch forever.
+    pub fn client(&self) -> reqwest::Result<reqwest::blocking::Client> {
+        reqwest::blocking::Client::builder()
+            .pool_idle_timeout(std::time::Duration::from_secs(90))
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .build()
+    }
+}
+
+/// Whether a response status is worth retrying on the same server, as opposed to e.g. a 404
+/// which means the path is simply not in that cache.
+fn is_transient(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// Set a narinfo field that can only appear once, a second value would silently replace the first
+fn set_once<T>(field: &mut Option<T>, key: &str, value: T) -> crate::error::Result<()> {
+    match field.replace(value) {
+        Some(_) => Err(crate::error::Error::NarInfoDuplicateField(key.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Name of the local store in [`FetchOutcome::attempts`]
+const LOCAL_STORE: &str = "local store";
+
+/// What a single source answered when asked for a narinfo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchResult {
+    Found,
+    /// The source does not have it, e.g. the cache responded with a 404
+    NotFound,
+    /// The source could not be reached or failed, after all retries, with the last error
+    Failed(String),
+    /// The last request did not complete in time
+    TimedOut,
+    /// The source was not asked, and why
+    Skipped(String),
+}
+
+impl FetchResult {
+    fn from_reqwest(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            FetchResult::TimedOut
+        } else {
+            FetchResult::Failed(err.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for FetchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchResult::Found => write!(f, "found"),
+            FetchResult::NotFound => write!(f, "not found"),
+            FetchResult::Failed(error) => write!(f, "failed: {}", error),
+            FetchResult::TimedOut => write!(f, "timed out"),
+            FetchResult::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+/// The narinfo if it was found, and what every source that was asked for it answered, in order.
+/// No attempts means there was no source to ask.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FetchOutcome {
+    pub info: Option<NarInfo>,
+    /// The source, a binary cache URL, the narinfo cache directory or "local store", and its
+    /// answer
+    pub attempts: Vec<(String, FetchResult)>,
+}
+
+impl std::fmt::Display for FetchOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.attempts.is_empty() {
+            return write!(f, "no source to ask");
+        }
+        let attempts: Vec<String> = self
+            .attempts
+            .iter()
+            .map(|(source, result)| format!("{} {}", source, result))
+            .collect();
+        write!(f, "{}", attempts.join(", "))
+    }
+}
+
+/// A path as listed by `nix path-info --json`, `None` fields mean the path is not valid
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfo {
+    /// Only in the list format of nix before 2.19, the newer format is keyed by path
+    path: Option<String>,
+    nar_hash: Option<String>,
+    nar_size: Option<usize>,
+    /// Only with `--closure-size`
+    closure_size: Option<u64>,
+    deriver: Option<String>,
+    #[serde(default)]
+    references: Vec<String>,
+    #[serde(default)]
+    signatures: Vec<String>,
+    ca: Option<String>,
+}
+
+/// The output of `nix path-info --json`, which changed format in nix 2.19
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum PathInfos {
+    List(Vec<PathInfo>),
+    Map(std::collections::HashMap<String, Option<PathInfo>>),
+}
+
+impl PathInfos {
+    fn parse(output_path: &str, json: &str) -> crate::error::Result<Option<PathInfo>> {
+        let path_infos: PathInfos = serde_json::from_str(json)
+            .map_err(|err| crate::error::Error::SerdeJSON(output_path.to_owned(), err))?;
+        Ok(match path_infos {
+            PathInfos::List(list) => list
+                .into_iter()
+                .find(|path_info| path_info.path.as_deref() == Some(output_path)),
+            PathInfos::Map(mut map) => map.remove(output_path).flatten(),
+        })
+    }
+}
+
+/// Size of a realised store path and of its closure, uncompressed, as reported by
+/// `nix path-info --closure-size`. Unlike the `nar_size` of a narinfo from a binary cache, this
+/// is what the path takes in the local store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreSize {
+    pub self_size: u64,
+    pub closure_size: u64,
+}
+
+impl StoreSize {
+    /// The size of `output_path` in the local store, `None` if it is not realised there
+    pub fn query(
+        output_path: &str,
+        timeout: Option<std::time::Duration>,
+        nix_binary: Option<&std::path::Path>,
+    ) -> crate::error::Result<Option<Self>> {
+        // Do not bother nix for the paths that were never built nor substituted
+        if !std::path::Path::new(output_path).exists() {
+            return Ok(None);
+        }
+
+        let mut command = super::command::nix(nix_binary);
+        command
+            .args(["path-info", "--json", "--closure-size", output_path])
+            .args(["--extra-experimental-features", "nix-command"]);
+        let output = super::command::output_with_timeout(&mut command, timeout)?
+            .ok_or_else(|| crate::error::Error::NixTimeout(output_path.to_owned()))?;
+
+        if !output.status.success() {
+            log::debug!(
+                "{} is not valid in the local store: {}",
+                output_path,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Ok(None);
+        }
+
+        Self::from_path_info(output_path, &String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn from_path_info(output_path: &str, json: &str) -> crate::error::Result<Option<Self>> {
+        Ok(PathInfos::parse(output_path, json)?.and_then(|path_info| {
+            Some(StoreSize {
+                self_size: u64::try_from(path_info.nar_size?).ok()?,
+                closure_size: path_info.closure_size?,
+            })
+        }))
+    }
+}
+
+/// Where the narinfo files of a binary cache can be read from, based on the scheme of its URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CacheLocation {
+    /// Served over HTTP(S), the base URL
+    Http(String),
+    /// A cache on the local filesystem, the directory containing the narinfo files
+    File(std::path::PathBuf),
+}
+
+impl CacheLocation {
+    /// Resolve the URL of a substituter, `None` if we do not know how to read from it
+    fn parse(server: &str) -> Option<Self> {
+        let (scheme, rest) = server.split_once("://")?;
+        // Parameters such as `?priority=40` are not part of the location, except for s3
+        let location = server
+            .split_once('?')
+            .map_or(server, |(location, _)| location);
+        match scheme {
+            "http" | "https" => Some(CacheLocation::Http(
+                location.trim_end_matches('/').to_owned(),
+            )),
+            "file" => Some(CacheLocation::File(std::path::PathBuf::from(
+                rest.split_once('?').map_or(rest, |(path, _)| path),
+            ))),
+            // Read public buckets through their HTTPS endpoint, e.g. s3://bucket?region=eu-west-1
+            "s3" => {
+                let (bucket, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let bucket = bucket.trim_end_matches('/');
+                let param = |key: &str| {
+                    query
+                        .split('&')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(k, _)| *k == key)
+                        .map(|(_, v)| v.to_owned())
+                };
+                match (param("endpoint"), param("region")) {
+                    (Some(endpoint), _) => {
+                        let endpoint = if endpoint.contains("://") {
+                            endpoint
+                        } else {
+                            format!("https://{}", endpoint)
+                        };
+                        Some(CacheLocation::Http(format!(
+                            "{}/{}",
+                            endpoint.trim_end_matches('/'),
+                            bucket
+                        )))
+                    }
+                    (None, Some(region)) => Some(CacheLocation::Http(format!(
+                        "https://{}.s3.{}.amazonaws.com",
+                        bucket, region
+                    ))),
+                    (None, None) => None,
+                }
+            }
+            _ => None,
+        }
+    }
 }
 
 impl NarInfo {
     /// Fetches the narinfo file for a given output path from a list of servers.
     ///
+    /// `http(s)://` and `file://` caches are supported, as well as public `s3://` buckets for which a
+    /// `region` or `endpoint` is given. Other caches are skipped with a warning.
+    ///
     /// # Arguments
     ///
+    /// * `client` - The HTTP client used for the requests, share it between calls to reuse connections.
+    /// * `config` - How often and how fast transient failures are retried before moving to the next server.
     /// * `output_path` - The output path of the narinfo file.
     /// * `servers` - A slice of server URLs to fetch the narinfo file from.
     ///
@@ -51,10 +540,13 @@ impl NarInfo {
     /// ```
     /// use nixtract::narinfo::NarInfo;
     ///
+    /// let config = nixtract::narinfo::FetchConfig::default();
+    /// let client = config.client().unwrap();
     /// let output_path = "/nix/store/abc123";
-    /// let servers = vec!["server1.example.com".to_string(), "server2.example.com".to_string()];
+    /// let servers = vec!["https://server1.example.com".to_string(), "file:///var/cache/nix".to_string()];
     ///
-    /// match NarInfo::fetch(output_path, &servers) {
+    ///
+    /// match NarInfo::fetch(&client, &config, output_path, &servers) {
     ///     Ok(Some(narinfo)) => {
     ///         // Narinfo file found, do something with it
     ///     },
@@ -66,7 +558,24 @@ impl NarInfo {
     ///     }
     /// }
     /// ```
-    pub fn fetch(output_path: &str, servers: &[String]) -> crate::error::Result<Option<Self>> {
+    pub fn fetch(
+        client: &reqwest::blocking::Client,
+        config: &FetchConfig,
+        output_path: &str,
+        servers: &[String],
+    ) -> crate::error::Result<Option<Self>> {
+        Ok(Self::fetch_outcome(client, config, output_path, servers)?.info)
+    }
+
+    /// Like [`NarInfo::fetch`], but also tells what every source that was asked answered, to
+    /// understand why a narinfo could not be obtained. The sources are asked in order until one
+    /// has it: the local store with [`NarInfoSource::Local`], the `cache_dir`, then the servers.
+    pub fn fetch_outcome(
+        client: &reqwest::blocking::Client,
+        config: &FetchConfig,
+        output_path: &str,
+        servers: &[String],
+    ) -> crate::error::Result<FetchOutcome> {
         // Strip the /nix/store prefix, and everything after the first -
         let hash = output_path
             .strip_prefix("/nix/store/")
@@ -75,30 +584,289 @@ impl NarInfo {
             .next()
             .ok_or_else(|| crate::error::Error::NarInfoInvalidPath(output_path.to_string()))?;
 
+        let mut outcome = FetchOutcome::default();
+        let found = |mut outcome: FetchOutcome, source: String, narinfo: Self| {
+            outcome.attempts.push((source, FetchResult::Found));
+            outcome.info = Some(narinfo);
+            Ok(outcome)
+        };
+
+        if config.source == NarInfoSource::Local {
+            match Self::fetch_local(output_path, config.timeout, config.nix_binary.as_deref())? {
+                Ok(narinfo) => return found(outcome, LOCAL_STORE.to_owned(), narinfo),
+                Err(result) => outcome.attempts.push((LOCAL_STORE.to_owned(), result)),
+            }
+        }
+
+        // Entries were parsed by earlier runs that may not have been strict, they cannot be
+        // checked again
+        if let Some(cache_dir) = config.cache_dir.as_ref().filter(|_| !config.strict) {
+            let source = cache_dir.display().to_string();
+            match Self::read_cached(cache_dir, hash) {
+                Some(narinfo) => return found(outcome, source, narinfo),
+                None => outcome.attempts.push((source, FetchResult::NotFound)),
+            }
+        }
+
         for server in servers {
-            let url = format!(
-                "{}{}{}.narinfo",
-                server,
-                if server.ends_with('/') { "" } else { "/" },
-                hash
+            let narinfo = match CacheLocation::parse(server) {
+                Some(CacheLocation::Http(_)) if config.offline => {
+                    log::debug!("Skipping binary cache {} in offline mode", server);
+                    Err(FetchResult::Skipped("offline mode".to_owned()))
+                }
+                Some(CacheLocation::Http(base)) => {
+                    let _permit = config.per_host.acquire(server);
+                    Self::fetch_http(client, config, &format!("{}/{}.narinfo", base, hash))?
+                }
+                Some(CacheLocation::File(directory)) => {
+                    Self::fetch_file(&directory.join(format!("{}.narinfo", hash)), config.strict)?
+                }
+                None => {
+                    log::warn!("Skipping unsupported binary cache: {}", server);
+                    Err(FetchResult::Skipped("unsupported binary cache".to_owned()))
+                }
+            };
+
+            match narinfo {
+                Ok(mut narinfo) => {
+                    narinfo.served_by = Some(server.clone());
+                    if let Some(cache_dir) = &config.cache_dir {
+                        Self::write_cached(cache_dir, hash, &narinfo);
+                    }
+                    return found(outcome, server.clone(), narinfo);
+                }
+                Err(result) => outcome.attempts.push((server.clone(), result)),
+            }
+        }
+
+        log::debug!("No narinfo for {}: {}", output_path, outcome);
+        Ok(outcome)
+    }
+
+    /// The references as full store paths, like `store_path` and the output paths of
+    /// descriptions. `references` holds them relative to the store directory, as in the narinfo.
+    pub fn reference_paths(&self) -> Vec<String> {
+        self.references
+            .iter()
+            .flatten()
+            .map(|reference| format!("/nix/store/{}", reference))
+            .collect()
+    }
+
+    /// The narinfo of `hash` kept in `cache_dir` by an earlier fetch, if any. Entries that cannot
+    /// be read are fetched again.
+    fn read_cached(cache_dir: &std::path::Path, hash: &str) -> Option<Self> {
+        let path = cache_dir.join(format!("{}.json", hash));
+        let cached = match std::fs::read(&path) {
+            Ok(cached) => cached,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::warn!("Could not read cached narinfo {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&cached) {
+            Ok(narinfo) => {
+                log::debug!("Read narinfo from {}", path.display());
+                Some(narinfo)
+            }
+            Err(err) => {
+                log::warn!(
+                    "Ignoring invalid cached narinfo {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Keep `narinfo` in `cache_dir`. Failing to do so only costs a fetch on the next run, so it
+    /// is only logged.
+    fn write_cached(cache_dir: &std::path::Path, hash: &str, narinfo: &Self) {
+        // Written to a temporary file first, concurrent readers never see a partial entry
+        let write = || -> std::io::Result<()> {
+            std::fs::create_dir_all(cache_dir)?;
+            let mut file = tempfile::NamedTempFile::new_in(cache_dir)?;
+            serde_json::to_writer(&mut file, narinfo)?;
+            file.persist(cache_dir.join(format!("{}.json", hash)))?;
+            Ok(())
+        };
+        if let Err(err) = write() {
+            log::warn!(
+                "Could not cache narinfo in {}: {}",
+                cache_dir.display(),
+                err
+            );
+        }
+    }
+
+    /// Query the local store with `nix path-info`, not found if the path is not valid in it
+    fn fetch_local(
+        output_path: &str,
+        timeout: std::time::Duration,
+        nix_binary: Option<&std::path::Path>,
+    ) -> crate::error::Result<std::result::Result<Self, FetchResult>> {
+        log::info!("Querying path info of {} from the local store", output_path);
+        let mut command = super::command::nix(nix_binary);
+        command
+            .args(["path-info", "--json", output_path])
+            .args(["--extra-experimental-features", "nix-command"]);
+        let output = match super::command::output_with_timeout(&mut command, Some(timeout))? {
+            Some(output) => output,
+            None => {
+                log::warn!(
+                    "Querying the local store for {} timed out, trying the binary caches",
+                    output_path
+                );
+                return Ok(Err(FetchResult::TimedOut));
+            }
+        };
+
+        if !output.status.success() {
+            log::debug!(
+                "{} is not in the local store: {}",
+                output_path,
+                String::from_utf8_lossy(&output.stderr)
             );
+            return Ok(Err(FetchResult::NotFound));
+        }
+
+        Ok(
+            Self::from_path_info(output_path, &String::from_utf8_lossy(&output.stdout))?
+                .ok_or(FetchResult::NotFound),
+        )
+    }
+
+    /// Map the output of `nix path-info --json` to a narinfo, with references and deriver relative
+    /// to the store directory like in narinfo files. There is no nar file to download, so `url`
+    /// is empty and `compression` is none.
+    fn from_path_info(output_path: &str, json: &str) -> crate::error::Result<Option<Self>> {
+        let path_info = match PathInfos::parse(output_path, json)? {
+            Some(path_info) => path_info,
+            None => return Ok(None),
+        };
+        let (nar_hash, nar_size) = match (path_info.nar_hash, path_info.nar_size) {
+            (Some(nar_hash), Some(nar_size)) => (nar_hash, nar_size),
+            _ => return Ok(None),
+        };
+
+        let base_name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_owned();
+        let references: Vec<String> = path_info
+            .references
+            .iter()
+            .map(|reference| base_name(reference))
+            .collect();
 
+        Ok(Some(NarInfo {
+            store_path: output_path.to_owned(),
+            url: String::new(),
+            nar_hash,
+            nar_size,
+            compression: Compression::None,
+            file_hash: None,
+            file_size: None,
+            deriver: path_info.deriver.as_deref().map(base_name),
+            system: None,
+            references: if references.is_empty() {
+                None
+            } else {
+                Some(references)
+            },
+            sig: path_info.signatures.first().cloned(),
+            signatures: path_info.signatures,
+            ca: path_info.ca,
+            served_by: None,
+        }))
+    }
+
+    /// Read a narinfo file from a cache on the local filesystem, `None` if it does not exist
+    fn fetch_file(
+        path: &std::path::Path,
+        strict: bool,
+    ) -> crate::error::Result<std::result::Result<Self, FetchResult>> {
+        log::info!("Reading narinfo from {}", path.display());
+        match std::fs::read_to_string(path) {
+            Ok(narinfo) => Ok(Ok(Self::parse_with(&narinfo, strict).map_err(|err| {
+                crate::error::Error::NarInfoInvalid(path.display().to_string(), Box::new(err))
+            })?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Err(FetchResult::NotFound))
+            }
+            Err(err) => {
+                log::warn!("Could not read narinfo: {}", err);
+                Ok(Err(FetchResult::Failed(err.to_string())))
+            }
+        }
+    }
+
+    /// Fetch a narinfo file over HTTP, retrying transient failures, or why it could not be fetched
+    fn fetch_http(
+        client: &reqwest::blocking::Client,
+        config: &FetchConfig,
+        url: &str,
+    ) -> crate::error::Result<std::result::Result<Self, FetchResult>> {
+        let mut failure = FetchResult::Failed("no request was made".to_owned());
+        for attempt in 0..=config.retries {
+            if attempt > 0 {
+                let backoff = config.backoff(attempt);
+                log::debug!(
+                    "Retrying narinfo fetch from {} in {:?} (retry {}/{})",
+                    url,
+                    backoff,
+                    attempt,
+                    config.retries
+                );
+                std::thread::sleep(backoff);
+            }
+
+            // Only the URL is logged, never the credentials
             log::info!("Fetching narinfo from {}", url);
-            match reqwest::blocking::get(&url) {
+            let request = match config.credentials(url) {
+                None => client.get(url),
+                Some(super::netrc::Credentials::Bearer(token)) => {
+                    client.get(url).bearer_auth(token)
+                }
+                Some(super::netrc::Credentials::Basic { login, password }) => {
+                    client.get(url).basic_auth(login, Some(password))
+                }
+            };
+            match request.send() {
                 Ok(response) => {
                     if response.status().is_success() {
-                        let narinfo = response.text()?;
-                        let narinfo = Self::parse(&narinfo)?;
-                        return Ok(Some(narinfo));
+                        // The timeout can also expire while reading the body
+                        let narinfo = match response.text() {
+                            Ok(narinfo) => narinfo,
+                            Err(err) => {
+                                log::warn!("Could not fetch narinfo: {}", err);
+                                failure = FetchResult::from_reqwest(&err);
+                                continue;
+                            }
+                        };
+                        let narinfo = Self::parse_with(&narinfo, config.strict).map_err(|err| {
+                            crate::error::Error::NarInfoInvalid(url.to_string(), Box::new(err))
+                        })?;
+                        return Ok(Ok(narinfo));
+                    } else if is_transient(response.status()) {
+                        log::warn!("Cache responded with error code: {}", response.status());
+                        failure = FetchResult::Failed(format!("HTTP {}", response.status()));
                     } else {
+                        // The path is not in this cache (e.g. 404), retrying won't help
                         log::warn!("Cache responded with error code: {}", response.status());
+                        return Ok(Err(match response.status() {
+                            reqwest::StatusCode::NOT_FOUND => FetchResult::NotFound,
+                            status => FetchResult::Failed(format!("HTTP {}", status)),
+                        }));
                     }
                 }
-                Err(err) => log::warn!("Could not fetch narinfo: {}", err),
+                Err(err) => {
+                    log::warn!("Could not fetch narinfo: {}", err);
+                    failure = FetchResult::from_reqwest(&err);
+                }
             }
         }
 
-        Ok(None)
+        Ok(Err(failure))
     }
 
     /// Parses a `narinfo` string into a `NarInfo` struct.
@@ -120,10 +888,23 @@ impl NarInfo {
     /// # Errors
     ///
     /// This function will return an error if:
-    /// * A line in the `narinfo` string does not contain a ':' delimiter.
-    /// * The `narinfo` string contains an unknown key.
+    /// * A non-blank line in the `narinfo` string does not contain a ':' delimiter.
+    /// * A size field is not an integer.
     /// * A required field is missing from the `narinfo` string.
+    /// * A field other than `References` and `Sig` appears more than once.
+    ///
+    /// Unknown keys are logged and ignored, see [`NarInfo::parse_strict`] to fail on them instead.
     pub fn parse(narinfo: &str) -> crate::error::Result<Self> {
+        Self::parse_with(narinfo, false)
+    }
+
+    /// Like [`NarInfo::parse`], but an unknown key is an error, for audits where a field nixtract
+    /// does not understand must not go unnoticed
+    pub fn parse_strict(narinfo: &str) -> crate::error::Result<Self> {
+        Self::parse_with(narinfo, true)
+    }
+
+    fn parse_with(narinfo: &str, strict: bool) -> crate::error::Result<Self> {
         let mut store_path = None;
         let mut url = None;
         let mut compression = None;
@@ -134,28 +915,48 @@ impl NarInfo {
         let mut deriver = None;
         let mut system = None;
         let mut references = Vec::new();
-        let mut sig = None;
+        let mut signatures = Vec::new();
         let mut ca = None;
 
-        for line in narinfo.lines() {
+        for (index, line) in narinfo.lines().enumerate() {
+            // Line numbers are 1-based in errors
+            let line_number = index + 1;
+
+            // Blank lines, typically trailing ones, carry no information
+            if line.trim().is_empty() {
+                continue;
+            }
+
             let (key, value) = line
                 .split_once(':')
-                .ok_or_else(|| crate::error::Error::NarInfoNoDelimiter(line.to_string()))
+                .ok_or_else(|| {
+                    crate::error::Error::NarInfoNoDelimiter(line_number, line.to_string())
+                })
                 .map(|(key, value)| (key.trim(), value.trim()))?;
+            let parse_int = |value: &str| {
+                value
+                    .parse()
+                    .map_err(|err| crate::error::Error::NarInfoParseIntError(line_number, err))
+            };
 
             match key {
-                "StorePath" => store_path = Some(value.to_string()),
-                "URL" => url = Some(value.to_string()),
-                "Compression" => compression = Some(value.to_string()),
-                "FileHash" => file_hash = Some(value.to_string()),
-                "FileSize" => file_size = Some(value.parse()?),
-                "NarHash" => nar_hash = Some(value.to_string()),
-                "NarSize" => nar_size = Some(value.parse()?),
-                "Deriver" => deriver = Some(value.to_string()),
-                "System" => system = Some(value.to_string()),
-                "References" => references = value.split(' ').map(|s| s.to_string()).collect(),
-                "Sig" => sig = Some(value.to_string()),
-                "CA" => ca = Some(value.to_string()),
+                "StorePath" => set_once(&mut store_path, key, value.to_string())?,
+                "URL" => set_once(&mut url, key, value.to_string())?,
+                "Compression" => set_once(&mut compression, key, Compression::from(value))?,
+                "FileHash" => set_once(&mut file_hash, key, value.to_string())?,
+                "FileSize" => set_once(&mut file_size, key, parse_int(value)?)?,
+                "NarHash" => set_once(&mut nar_hash, key, value.to_string())?,
+                "NarSize" => set_once(&mut nar_size, key, parse_int(value)?)?,
+                "Deriver" => set_once(&mut deriver, key, value.to_string())?,
+                "System" => set_once(&mut system, key, value.to_string())?,
+                // Long lists of references can be split over several lines
+                "References" => references.extend(value.split_whitespace().map(str::to_string)),
+                // Nix writes one line per signature
+                "Sig" => signatures.push(value.to_string()),
+                "CA" => set_once(&mut ca, key, value.to_string())?,
+                _ if strict => {
+                    return Err(crate::error::Error::NarInfoUnknownField(key.to_string()))
+                }
                 _ => {
                     log::warn!(
                         "Found an unknown key while parsing a .narinfo file ({}). Please report this issue to github.com/tweag/nixtract",
@@ -173,8 +974,8 @@ impl NarInfo {
             ));
         }
 
-        // If Compression was not present, assume bzip: https://github.com/nixos/nix/blob/0d26358bda1637e54ebacac18e7b5af7381cf5f3/src/libstore/nar-info.cc#L96
-        let compression = compression.unwrap_or_else(|| "bzip".to_string());
+        // If Compression was not present, assume bzip2: https://github.com/nixos/nix/blob/0d26358bda1637e54ebacac18e7b5af7381cf5f3/src/libstore/nar-info.cc#L96
+        let compression = compression.unwrap_or(Compression::Bzip2);
 
         // If References was empty, parse it as None instead of the empty vector
         let references = if references.is_empty() {
@@ -197,8 +998,10 @@ impl NarInfo {
             deriver,
             system,
             references,
-            sig,
+            sig: signatures.first().cloned(),
+            signatures,
             ca,
+            served_by: None,
         })
     }
 }
@@ -210,6 +1013,8 @@ mod tests {
     #[test]
     fn test_fetch() {
         let result = NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &FetchConfig::default(),
             "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
             &["https://cache.nixos.org".to_owned()],
         )
@@ -218,7 +1023,7 @@ mod tests {
         let expected = NarInfo {
             store_path: "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_string(),
             url: "nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz".to_string(),
-            compression: "xz".to_string(),
+            compression: Compression::Xz,
             file_hash: Some("sha256:1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g".to_string()),
             file_size: Some(50184),
             nar_hash: "sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26".to_string(),
@@ -230,12 +1035,509 @@ mod tests {
                 "gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23".to_string(),
             ]),
             sig: Some("cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()),
+            signatures: vec!["cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()],
             ca: None,
+            served_by: Some("https://cache.nixos.org".to_string()),
         };
 
         pretty_assertions::assert_eq!(result, Some(expected));
     }
 
+    #[test]
+    fn test_from_path_info() {
+        let output_path = "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1";
+        let expected = NarInfo {
+            store_path: output_path.to_string(),
+            url: String::new(),
+            compression: Compression::None,
+            file_hash: None,
+            file_size: None,
+            nar_hash: "sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26".to_string(),
+            nar_size: 226552,
+            deriver: Some("57677sld6ja212hkv1gh8bdm0amnk1hz-hello-2.12.1.drv".to_string()),
+            system: None,
+            references: Some(vec![
+                "cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_string(),
+                "gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23".to_string(),
+            ]),
+            sig: Some("cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()),
+            signatures: vec!["cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()],
+            ca: None,
+            served_by: None,
+        };
+
+        // The list format of nix before 2.19
+        let list = r#"[{"path":"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","narHash":"sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26","narSize":226552,"references":["/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","/nix/store/gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23"],"deriver":"/nix/store/57677sld6ja212hkv1gh8bdm0amnk1hz-hello-2.12.1.drv","registrationTime":1700000000,"signatures":["cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw=="],"valid":true}]"#;
+        pretty_assertions::assert_eq!(
+            NarInfo::from_path_info(output_path, list).unwrap(),
+            Some(expected.clone())
+        );
+
+        // The map format of later versions
+        let map = r#"{"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1":{"narHash":"sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26","narSize":226552,"references":["/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","/nix/store/gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23"],"deriver":"/nix/store/57677sld6ja212hkv1gh8bdm0amnk1hz-hello-2.12.1.drv","signatures":["cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw=="],"ca":null}}"#;
+        pretty_assertions::assert_eq!(
+            NarInfo::from_path_info(output_path, map).unwrap(),
+            Some(expected)
+        );
+
+        // Paths that are not valid in the local store
+        let invalid = r#"[{"path":"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","valid":false}]"#;
+        assert_eq!(NarInfo::from_path_info(output_path, invalid).unwrap(), None);
+        let invalid = r#"{"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1":null}"#;
+        assert_eq!(NarInfo::from_path_info(output_path, invalid).unwrap(), None);
+    }
+
+    #[test]
+    fn test_store_size() {
+        let output_path = "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1";
+        let expected = StoreSize {
+            self_size: 226552,
+            closure_size: 30893024,
+        };
+
+        let list = r#"[{"path":"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1","narHash":"sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26","narSize":226552,"closureSize":30893024,"references":[],"valid":true}]"#;
+        assert_eq!(
+            StoreSize::from_path_info(output_path, list).unwrap(),
+            Some(expected)
+        );
+        let map = r#"{"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1":{"narHash":"sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26","narSize":226552,"closureSize":30893024,"references":[]}}"#;
+        assert_eq!(
+            StoreSize::from_path_info(output_path, map).unwrap(),
+            Some(expected)
+        );
+        let invalid = r#"{"/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1":null}"#;
+        assert_eq!(
+            StoreSize::from_path_info(output_path, invalid).unwrap(),
+            None
+        );
+
+        // Paths that do not exist are not even queried
+        assert_eq!(
+            StoreSize::query(
+                "/nix/store/00000000000000000000000000000000-missing",
+                None,
+                Some(std::path::Path::new("/nonexistent/nix"))
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = FetchConfig {
+            retries: 3,
+            initial_backoff: std::time::Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        assert_eq!(config.backoff(1), std::time::Duration::from_millis(100));
+        assert_eq!(config.backoff(2), std::time::Duration::from_millis(200));
+        assert_eq!(config.backoff(3), std::time::Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let cache = tempfile::tempdir().unwrap();
+        let cache_dir = cache.path().join("narinfo");
+        let server = tempfile::tempdir().unwrap();
+        let narinfo_path = server
+            .path()
+            .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo");
+        std::fs::write(
+            &narinfo_path,
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+
+        let config = FetchConfig {
+            cache_dir: Some(cache_dir.clone()),
+            ..Default::default()
+        };
+        let servers = vec![format!("file://{}", server.path().display())];
+        let fetch = || {
+            NarInfo::fetch(
+                &reqwest::blocking::Client::new(),
+                &config,
+                "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+                &servers,
+            )
+            .unwrap()
+        };
+
+        let fetched = fetch().unwrap();
+        assert!(cache_dir
+            .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.json")
+            .exists());
+
+        // The cache no longer has it, but it is still known
+        std::fs::remove_file(&narinfo_path).unwrap();
+        assert_eq!(fetch(), Some(fetched));
+
+        // Unless strict, the entry was not necessarily parsed strictly
+        let strict = FetchConfig {
+            strict: true,
+            ..config.clone()
+        };
+        let narinfo = NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &strict,
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &servers,
+        )
+        .unwrap();
+        assert_eq!(narinfo, None);
+    }
+
+    #[test]
+    fn test_fetch_offline() {
+        let server = tempfile::tempdir().unwrap();
+        std::fs::write(
+            server
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+
+        let config = FetchConfig {
+            offline: true,
+            retries: 0,
+            ..Default::default()
+        };
+        let file_server = format!("file://{}", server.path().display());
+        // Nothing listens on the discard port, asking it would fail
+        let servers = vec!["http://127.0.0.1:9".to_owned(), file_server.clone()];
+        let narinfo = NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &config,
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &servers,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(narinfo.served_by, Some(file_server));
+    }
+
+    #[test]
+    fn test_fetch_outcome() {
+        let empty = tempfile::tempdir().unwrap();
+        let server = tempfile::tempdir().unwrap();
+        std::fs::write(
+            server
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+
+        let config = FetchConfig {
+            offline: true,
+            ..Default::default()
+        };
+        let empty_server = format!("file://{}", empty.path().display());
+        let file_server = format!("file://{}", server.path().display());
+        let fetch = |servers: &[String]| {
+            NarInfo::fetch_outcome(
+                &reqwest::blocking::Client::new(),
+                &config,
+                "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+                servers,
+            )
+            .unwrap()
+        };
+
+        let servers = vec![
+            empty_server.clone(),
+            "ssh://builder".to_owned(),
+            "https://cache.nixos.org".to_owned(),
+            file_server.clone(),
+        ];
+        let outcome = fetch(&servers);
+        assert!(outcome.info.is_some());
+        assert_eq!(
+            outcome.attempts,
+            vec![
+                (empty_server.clone(), FetchResult::NotFound),
+                (
+                    "ssh://builder".to_owned(),
+                    FetchResult::Skipped("unsupported binary cache".to_owned())
+                ),
+                (
+                    "https://cache.nixos.org".to_owned(),
+                    FetchResult::Skipped("offline mode".to_owned())
+                ),
+                (file_server, FetchResult::Found),
+            ]
+        );
+
+        // Why nothing was found
+        let outcome = fetch(&servers[..1]);
+        assert_eq!(outcome.info, None);
+        assert_eq!(outcome.to_string(), format!("{} not found", empty_server));
+        assert_eq!(fetch(&[]).to_string(), "no source to ask");
+    }
+
+    #[test]
+    fn test_host_limit() {
+        let host_limit = HostLimit::new(Some(2));
+        let clone = host_limit.clone();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+
+        let first = host_limit.acquire("https://cache.nixos.org");
+        let _second = host_limit.acquire("https://cache.nixos.org");
+        // Other caches have their own limit
+        let _other = host_limit.acquire("https://example.cachix.org");
+
+        let third = std::thread::spawn(move || {
+            let _third = clone.acquire("https://cache.nixos.org");
+            started_tx.send(()).unwrap();
+        });
+        assert!(started_rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+
+        drop(first);
+        started_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        third.join().unwrap();
+
+        assert_eq!(HostLimit::new(Some(0)).limit(), Some(1));
+    }
+
+    #[test]
+    fn test_cache_location() {
+        assert_eq!(
+            CacheLocation::parse("https://cache.nixos.org/"),
+            Some(CacheLocation::Http("https://cache.nixos.org".to_owned()))
+        );
+        assert_eq!(
+            CacheLocation::parse("https://cache.example.org?priority=30&trusted=1"),
+            Some(CacheLocation::Http("https://cache.example.org".to_owned()))
+        );
+        assert_eq!(
+            CacheLocation::parse("file:///var/cache/nix"),
+            Some(CacheLocation::File("/var/cache/nix".into()))
+        );
+        assert_eq!(
+            CacheLocation::parse("s3://my-bucket?region=eu-west-1"),
+            Some(CacheLocation::Http(
+                "https://my-bucket.s3.eu-west-1.amazonaws.com".to_owned()
+            ))
+        );
+        assert_eq!(
+            CacheLocation::parse("s3://my-bucket?endpoint=minio.example.org&scheme=https"),
+            Some(CacheLocation::Http(
+                "https://minio.example.org/my-bucket".to_owned()
+            ))
+        );
+        assert_eq!(CacheLocation::parse("s3://my-bucket"), None);
+        assert_eq!(CacheLocation::parse("ssh://builder"), None);
+    }
+
+    #[test]
+    fn test_fetch_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+";
+        std::fs::write(
+            directory
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            narinfo,
+        )
+        .unwrap();
+
+        let servers = vec![
+            "ssh://builder".to_owned(),
+            format!("file://{}", directory.path().display()),
+        ];
+        let result = NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &FetchConfig::default(),
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &servers,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(result.nar_size, 226552);
+        // The skipped cache is not the one that served it
+        assert_eq!(result.served_by.as_ref(), Some(&servers[1]));
+
+        let result = NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &FetchConfig::default(),
+            "/nix/store/00000000000000000000000000000000-missing",
+            &servers,
+        )
+        .unwrap();
+        assert_eq!(result, None);
+
+        // Parse errors mention the file they come from
+        let bad = directory
+            .path()
+            .join("11111111111111111111111111111111.narinfo");
+        std::fs::write(&bad, "NarSize: large\n").unwrap();
+        match NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &FetchConfig::default(),
+            "/nix/store/11111111111111111111111111111111-bad",
+            &servers,
+        ) {
+            Err(crate::error::Error::NarInfoInvalid(source, _)) => {
+                assert_eq!(source, bad.display().to_string())
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_fetch_timeout() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _connections: Vec<_> = listener.incoming().collect();
+        });
+
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(
+            directory
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+
+        let config = FetchConfig {
+            retries: 0,
+            timeout: std::time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let servers = vec![
+            format!("http://{}", address),
+            format!("file://{}", directory.path().display()),
+        ];
+
+        // The hung server is skipped in favour of the next one
+        let result = NarInfo::fetch(
+            &config.client().unwrap(),
+            &config,
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &servers,
+        )
+        .unwrap();
+        assert!(result.is_some());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_fetch_local_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // A local store that never answers
+        let directory = tempfile::tempdir().unwrap();
+        let nix = directory.path().join("nix");
+        std::fs::write(
+            &nix,
+            "#!/bin/sh
+exec sleep 10
+",
+        )
+        .unwrap();
+        std::fs::set_permissions(&nix, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(
+            directory
+                .path()
+                .join("cg8a576pz2yfc1wbhxm1zy4x7lrk8pix.narinfo"),
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+",
+        )
+        .unwrap();
+
+        let config = FetchConfig {
+            source: NarInfoSource::Local,
+            timeout: std::time::Duration::from_millis(200),
+            nix_binary: Some(nix),
+            ..Default::default()
+        };
+        let outcome = NarInfo::fetch_outcome(
+            &config.client().unwrap(),
+            &config,
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &[format!("file://{}", directory.path().display())],
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.attempts[0],
+            (LOCAL_STORE.to_owned(), FetchResult::TimedOut)
+        );
+        assert!(outcome.info.is_some());
+    }
+
+    #[test]
+    fn test_fetch_credentials() {
+        // Answers 404 and passes the request on, to check its headers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            use std::io::{Read, Write};
+            for mut stream in listener.incoming().map_while(|stream| stream.ok()) {
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).unwrap();
+                let _ = request_tx.send(String::from_utf8_lossy(&request[..read]).to_lowercase());
+                stream
+                    .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+
+        let config = FetchConfig {
+            credentials: [(
+                "127.0.0.1".to_owned(),
+                super::super::netrc::Credentials::Bearer("t0ken".to_owned()),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let result = NarInfo::fetch(
+            &config.client().unwrap(),
+            &config,
+            "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+            &[format!("http://{}", address)],
+        )
+        .unwrap();
+        assert_eq!(result, None);
+        assert!(request_rx
+            .recv()
+            .unwrap()
+            .contains("authorization: bearer t0ken"));
+    }
+
     #[test]
     fn test_parse() {
         let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
@@ -253,7 +1555,7 @@ Sig: cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUz
         let expected = NarInfo {
             store_path: "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_string(),
             url: "nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz".to_string(),
-            compression: "xz".to_string(),
+            compression: Compression::Xz,
             file_hash: Some("sha256:1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g".to_string()),
             file_size: Some(50184),
             nar_hash: "sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26".to_string(),
@@ -265,10 +1567,116 @@ Sig: cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUz
                 "gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23".to_string(),
             ]),
             sig: Some("cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()),
+            signatures: vec!["cache.nixos.org-1:WzRvexDdRP62D8j/4rAk73vAc4gUtAN7qpZesuRc74+My03WcvWxg/LUztmWikOaMqJQJMvB1ria6AIX30yrDw==".to_string()],
             ca: None,
+            served_by: None,
         };
 
         let result = NarInfo::parse(narinfo).unwrap();
         pretty_assertions::assert_eq!(result, expected);
+        assert_eq!(
+            result.reference_paths(),
+            vec![
+                "/nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1",
+                "/nix/store/gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23",
+            ]
+        );
+
+        // Trailing blank lines are fine
+        let result = NarInfo::parse(&format!("{}\n\n", narinfo)).unwrap();
+        pretty_assertions::assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+
+URL nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+";
+        match NarInfo::parse(narinfo) {
+            Err(crate::error::Error::NarInfoNoDelimiter(3, line)) => {
+                assert!(line.starts_with("URL nar/"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+NarSize: large
+";
+        assert!(matches!(
+            NarInfo::parse(narinfo),
+            Err(crate::error::Error::NarInfoParseIntError(2, _))
+        ));
+
+        let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+StorePath: /nix/store/gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23
+";
+        match NarInfo::parse(narinfo) {
+            Err(crate::error::Error::NarInfoDuplicateField(key)) => assert_eq!(key, "StorePath"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        let narinfo = "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+Signature: cache.nixos.org-1:future
+";
+        assert!(NarInfo::parse(narinfo).is_ok());
+        match NarInfo::parse_strict(narinfo) {
+            Err(crate::error::Error::NarInfoUnknownField(key)) => assert_eq!(key, "Signature"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_repeated_fields() {
+        let narinfo = NarInfo::parse(
+            "StorePath: /nix/store/cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+URL: nar/1wjh5hhqfi30fx8pqi0901c9n035qbwsv1rmizvmpydva2lpri2g.nar.xz
+NarHash: sha256:0scilhfg9qij3wiz1irrln5nb5nk3nxfkns6yqfh2kvbaixywv26
+NarSize: 226552
+References: cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1
+References: gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23
+Sig: cache.nixos.org-1:first
+Sig: example.cachix.org-1:second
+",
+        )
+        .unwrap();
+        assert_eq!(
+            narinfo.references,
+            Some(vec![
+                "cg8a576pz2yfc1wbhxm1zy4x7lrk8pix-hello-2.12.1".to_owned(),
+                "gqghjch4p1s69sv4mcjksb2kb65rwqjy-glibc-2.38-23".to_owned(),
+            ])
+        );
+        assert_eq!(narinfo.sig.as_deref(), Some("cache.nixos.org-1:first"));
+        assert_eq!(
+            narinfo.signatures,
+            ["cache.nixos.org-1:first", "example.cachix.org-1:second"]
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        for value in ["none", "xz", "bzip2", "zstd", "gzip", "br", "lz4", "lzip"] {
+            let compression = Compression::from(value);
+            assert_eq!(compression.as_str(), value);
+
+            let json = serde_json::to_string(&compression).unwrap();
+            assert_eq!(json, format!("\"{}\"", value));
+            assert_eq!(
+                serde_json::from_str::<Compression>(&json).unwrap(),
+                compression
+            );
+        }
+        assert_eq!(
+            Compression::from("lzip"),
+            Compression::Other("lzip".to_owned())
+        );
     }
 }
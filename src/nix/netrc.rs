@@ -0,0 +1,106 @@
+//! Credentials for private binary caches, given per host or read from a `netrc` file like the
+//! `netrc-file` setting of nix.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// How to authenticate to a binary cache, never shown by [`Debug`] so it cannot end up in logs
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Sent as `Authorization: Bearer <token>`
+    Bearer(String),
+    /// Sent as HTTP basic authentication, this is what a `netrc` file provides
+    Basic { login: String, password: String },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            Credentials::Basic { login, .. } => write!(f, "Basic({}, <redacted>)", login),
+        }
+    }
+}
+
+/// Parse the contents of a `netrc` file into credentials keyed by host. Only `machine` entries
+/// with a `password` are kept, `default` entries are ignored.
+pub fn parse(netrc: &str) -> HashMap<String, Credentials> {
+    let mut credentials = HashMap::new();
+    let mut machine: Option<&str> = None;
+    let mut login = "";
+    let mut password: Option<&str> = None;
+
+    let mut finish = |machine: Option<&str>, login: &str, password: Option<&str>| {
+        if let (Some(machine), Some(password)) = (machine, password) {
+            credentials.insert(
+                machine.to_owned(),
+                Credentials::Basic {
+                    login: login.to_owned(),
+                    password: password.to_owned(),
+                },
+            );
+        }
+    };
+
+    let mut tokens = netrc.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                finish(machine, login, password);
+                machine = if token == "machine" {
+                    tokens.next()
+                } else {
+                    None
+                };
+                login = "";
+                password = None;
+            }
+            "login" => login = tokens.next().unwrap_or_default(),
+            "password" => password = tokens.next(),
+            _ => {}
+        }
+    }
+    finish(machine, login, password);
+
+    credentials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let credentials = parse(
+            "machine cache.example.org login nix password s3cret
+
+machine other.example.org
+  login ci
+  password hunter2
+default login anonymous password guest
+",
+        );
+
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(
+            credentials["cache.example.org"],
+            Credentials::Basic {
+                login: "nix".to_owned(),
+                password: "s3cret".to_owned()
+            }
+        );
+        assert_eq!(
+            credentials["other.example.org"],
+            Credentials::Basic {
+                login: "ci".to_owned(),
+                password: "hunter2".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_debug_redacted() {
+        let credentials = Credentials::Bearer("t0ken".to_owned());
+        assert!(!format!("{:?}", credentials).contains("t0ken"));
+    }
+}
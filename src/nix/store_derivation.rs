@@ -0,0 +1,266 @@
+//! Describing derivations from their `.drv` files, for store paths that are not reachable through
+//! an attribute path of the flake. Only what is in the `.drv` file is known, the `meta` of the
+//! derivation is not.
+
+use super::drv::Drv;
+use super::{
+    BuildInputType, BuiltInput, DerivationDescription, DescribeDerivationArgs, NixpkgsMetadata,
+    Output, ParsedName, RuntimeSource,
+};
+use crate::error::{Error, Result};
+
+/// Split a derivation name into its name and version like `builtins.parseDrvName`: the version
+/// starts after the first dash that is not followed by a letter
+pub fn parse_drv_name(name: &str) -> ParsedName {
+    let split = name
+        .char_indices()
+        .find(|&(index, c)| {
+            c == '-'
+                && name[index + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|next| !next.is_alphabetic())
+        })
+        .map(|(index, _)| index);
+
+    match split {
+        Some(index) => ParsedName {
+            name: name[..index].to_owned(),
+            version: name[index + 1..].to_owned(),
+        },
+        None => ParsedName {
+            name: name.to_owned(),
+            version: String::new(),
+        },
+    }
+}
+
+/// The derivation that produced `store_path`, `None` if nix does not know it, e.g. for paths
+/// added with `nix-store --add`
+pub fn deriver(
+    store_path: &str,
+    timeout: Option<std::time::Duration>,
+    nix_binary: Option<&std::path::Path>,
+) -> Result<Option<String>> {
+    let mut command = super::command::nix_store(nix_binary);
+    command.args(["--query", "--deriver", store_path]);
+
+    let output = super::command::output_with_timeout(&mut command, timeout)?
+        .ok_or_else(|| Error::NixTimeout(store_path.to_owned()))?;
+
+    if !output.status.success() {
+        return Err(Error::NixCommand(
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let deriver = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    // The deriver can also be recorded but since garbage collected
+    if deriver.is_empty() || deriver == "unknown-deriver" {
+        return Ok(None);
+    }
+    Ok(Some(deriver))
+}
+
+/// Which attribute of the derivation an input is listed in, from the environment of the `.drv`
+/// file where the attributes are space separated store paths
+fn build_input_type(drv: &Drv, output_path: &str) -> BuildInputType {
+    BuildInputType::KNOWN
+        .into_iter()
+        .find(|build_input_type| {
+            drv.env
+                .get(build_input_type.as_str())
+                .is_some_and(|paths| paths.split_whitespace().any(|path| path == output_path))
+        })
+        .unwrap_or_else(|| BuildInputType::Other("inputDrvs".to_owned()))
+}
+
+/// Describe the derivation at `args.attribute_path`, a `.drv` path, from the `.drv` file. The
+/// build inputs are its input derivations, with their derivation path as attribute path.
+pub fn describe_store_derivation(args: &DescribeDerivationArgs) -> Result<DerivationDescription> {
+    let derivation_path = &args.attribute_path;
+    let drv = Drv::show(derivation_path, args.eval_timeout, args.nix_binary)?;
+
+    // The output paths of the inputs are in their own .drv files
+    let input_paths: Vec<&str> = drv.input_drvs.keys().map(String::as_str).collect();
+    let inputs = Drv::show_many(&input_paths, args.eval_timeout, args.nix_binary)?;
+
+    let mut build_inputs = Vec::new();
+    for (input_path, output_names) in &drv.input_drvs {
+        for output_name in output_names {
+            let output_path = inputs
+                .get(input_path)
+                .and_then(|input| input.outputs.get(output_name).cloned().flatten());
+            build_inputs.push(BuiltInput {
+                attribute_path: input_path.clone(),
+                build_input_type: output_path.as_deref().map_or_else(
+                    || BuildInputType::Other("inputDrvs".to_owned()),
+                    |output_path| build_input_type(&drv, output_path),
+                ),
+                output_path,
+                // The .drv file lists the outputs it uses one by one
+                all_output_paths: Vec::new(),
+            });
+        }
+    }
+
+    let name = drv.env.get("name").cloned().unwrap_or_default();
+    let parsed_name = parse_drv_name(&name);
+    // Like describe_derivation.nix, only fixed-output derivations have a hash
+    let hash = drv.env.get("outputHash").filter(|hash| !hash.is_empty());
+    let outputs: Vec<Output> = drv
+        .outputs
+        .iter()
+        .map(|(output_name, output_path)| Output {
+            name: output_name.clone(),
+            output_path: output_path.clone(),
+            hash: hash.cloned(),
+            hash_algo: hash.and_then(|_| drv.env.get("outputHashAlgo").cloned()),
+            hash_mode: hash.map(|_| {
+                drv.env
+                    .get("outputHashMode")
+                    .cloned()
+                    .unwrap_or_else(|| "flat".to_owned())
+            }),
+            nar_info: None,
+        })
+        .collect();
+    let main_output = drv
+        .outputs
+        .get_key_value("out")
+        .or_else(|| drv.outputs.iter().next());
+    let output_path = main_output.and_then(|(_, output_path)| output_path.clone());
+    let main_output = main_output.map(|(output_name, _)| output_name.clone());
+
+    let mut description = DerivationDescription {
+        schema_version: super::SCHEMA_VERSION.to_owned(),
+        attribute_path: derivation_path.clone(),
+        system: drv.system.clone(),
+        flake_output: args.flake_output,
+        output_type: String::new(),
+        derivation_path: Some(derivation_path.clone()),
+        output_path,
+        outputs,
+        // The .drv file has no meta, only the main output is known
+        outputs_to_install: main_output.into_iter().collect(),
+        nixpkgs_metadata: NixpkgsMetadata {
+            description: String::new(),
+            pname: drv
+                .env
+                .get("pname")
+                .cloned()
+                .unwrap_or_else(|| parsed_name.name.clone()),
+            version: drv
+                .env
+                .get("version")
+                .cloned()
+                .unwrap_or_else(|| parsed_name.version.clone()),
+            broken: false,
+            unfree: false,
+            insecure: false,
+            known_vulnerabilities: Vec::new(),
+            homepage: String::new(),
+            licenses: None,
+            maintainers: Vec::new(),
+        },
+        purl: super::purl(args.flake_ref, derivation_path, &parsed_name),
+        name,
+        parsed_name,
+        meta_position: None,
+        sources: Vec::new(),
+        platforms: Vec::new(),
+        build_inputs,
+        eval_warnings: Vec::new(),
+        nar_info: None,
+        self_size: None,
+        closure_size: None,
+        drv: None,
+    };
+
+    if args.runtime_only {
+        match args.runtime_source {
+            RuntimeSource::Eval => description
+                .build_inputs
+                .retain(|input| input.build_input_type.is_runtime()),
+            RuntimeSource::Store => {
+                super::describe_derivation::filter_runtime_inputs(args, &mut description)?
+            }
+        }
+    }
+    if args.include_nar_info {
+        description.fetch_nar_info(args.http_client, args.narinfo_fetch, args.binary_caches)?;
+    }
+    if args.include_store_size {
+        super::describe_derivation::query_store_size(args, &mut description)?;
+    }
+    if args.include_drv {
+        description.drv = Some(drv);
+    }
+
+    Ok(description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_drv_name() {
+        let parsed = |name: &str| {
+            let parsed_name = parse_drv_name(name);
+            (parsed_name.name, parsed_name.version)
+        };
+        assert_eq!(
+            parsed("hello-2.12.1"),
+            ("hello".to_owned(), "2.12.1".to_owned())
+        );
+        assert_eq!(
+            parsed("python3.11-requests-2.31.0"),
+            ("python3.11-requests".to_owned(), "2.31.0".to_owned())
+        );
+        assert_eq!(
+            parsed("stdenv-linux"),
+            ("stdenv-linux".to_owned(), String::new())
+        );
+    }
+
+    #[test]
+    fn test_build_input_type() {
+        let drv = Drv {
+            builder: String::new(),
+            args: Vec::new(),
+            env: std::collections::BTreeMap::from([
+                (
+                    "buildInputs".to_owned(),
+                    "/nix/store/a-zlib-1.3 /nix/store/b-openssl-3.0".to_owned(),
+                ),
+                (
+                    "nativeBuildInputs".to_owned(),
+                    "/nix/store/c-cmake-3.27".to_owned(),
+                ),
+                ("srcs".to_owned(), "/nix/store/d-source".to_owned()),
+            ]),
+            input_drvs: Default::default(),
+            system: "x86_64-linux".to_owned(),
+            outputs: Default::default(),
+        };
+
+        assert_eq!(
+            build_input_type(&drv, "/nix/store/b-openssl-3.0"),
+            BuildInputType::BuildInput
+        );
+        assert_eq!(
+            build_input_type(&drv, "/nix/store/c-cmake-3.27"),
+            BuildInputType::NativeBuildInput
+        );
+        assert_eq!(
+            build_input_type(&drv, "/nix/store/d-source"),
+            BuildInputType::Sources
+        );
+        assert_eq!(
+            build_input_type(&drv, "/nix/store/e-bash-5.2"),
+            BuildInputType::Other("inputDrvs".to_owned())
+        );
+    }
+}
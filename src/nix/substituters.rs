@@ -1,21 +1,26 @@
 //! This modules parses the get the substituters from the local nix install and the flake ref and combines them into a single list
 
+use std::path::Path;
+
 use crate::error::{Error, Result};
 
 pub type Substituters = Vec<String>;
 
-fn from_flake_ref(flake_ref: &str) -> Result<Substituters> {
+fn from_flake_ref(flake_ref: &str, nix_binary: Option<&Path>) -> Result<Substituters> {
     let expr = format!(
         "(import ((builtins.getFlake \"{}\").outPath + \"/flake.nix\")).nixConfig.extra-substituters or []",
         flake_ref
     );
 
     // Call nix eval on the flake to get the json representation of the nixConfig
-    let output = std::process::Command::new("nix")
+    let mut command = super::command::nix(nix_binary);
+    command
         .args(["eval", "--json", "--impure"])
         .args(["--expr", &expr])
-        .args(["--extra-experimental-features", "flakes nix-command"])
-        .output()?;
+        .args(["--extra-experimental-features", "flakes nix-command"]);
+    let output = command
+        .output()
+        .map_err(|err| super::command::spawn_failure(&command, err))?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -33,11 +38,14 @@ fn from_flake_ref(flake_ref: &str) -> Result<Substituters> {
     Ok(extra_substituters)
 }
 
-fn from_nix_conf() -> Result<Substituters> {
-    let output = std::process::Command::new("nix")
+fn from_nix_conf(nix_binary: Option<&Path>) -> Result<Substituters> {
+    let mut command = super::command::nix(nix_binary);
+    command
         .args(["show-config", "--json"])
-        .args(["--extra-experimental-features", "flakes nix-command"])
-        .output()?;
+        .args(["--extra-experimental-features", "flakes nix-command"]);
+    let output = command
+        .output()
+        .map_err(|err| super::command::spawn_failure(&command, err))?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -65,10 +73,78 @@ fn from_nix_conf() -> Result<Substituters> {
     Ok(substituters)
 }
 
-pub fn get_substituters(flake_ref: String) -> Result<Substituters> {
-    let mut substituters = from_nix_conf()?;
-    substituters.extend(from_flake_ref(&flake_ref)?);
-    Ok(substituters)
+/// Priority of binary caches that do not set one, the same default as nix
+const DEFAULT_PRIORITY: u32 = 50;
+
+/// The `priority` parameter of a substituter URL, e.g. `https://cache.example.org?priority=30`
+fn priority(substituter: &str) -> u32 {
+    substituter
+        .split_once('?')
+        .and_then(|(_, query)| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == "priority")
+        })
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(DEFAULT_PRIORITY)
+}
+
+/// Remove substituters listed more than once, keeping the first occurrence, and order them so
+/// the ones with the highest priority (lowest value) are tried first
+fn combine(substituters: impl IntoIterator<Item = String>) -> Substituters {
+    let mut seen = std::collections::HashSet::new();
+    let mut combined: Substituters = substituters
+        .into_iter()
+        .filter(|substituter| seen.insert(substituter.trim_end_matches('/').to_owned()))
+        .collect();
+    // Stable, so substituters with the same priority keep their order
+    combined.sort_by_key(|substituter| priority(substituter));
+    combined
+}
+
+/// Environment variable with the binary caches to use instead of the substituters of nix, e.g.
+/// set by a CI pipeline. The caches are separated by spaces or commas.
+pub const BINARY_CACHES_ENV: &str = "NIXTRACT_BINARY_CACHES";
+
+/// The binary caches listed in `value`, separated by spaces or commas
+fn parse_list(value: &str) -> Substituters {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|cache| !cache.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The binary caches of [`BINARY_CACHES_ENV`], `None` if it is unset or lists none
+fn from_env() -> Option<Substituters> {
+    let value = std::env::var(BINARY_CACHES_ENV).ok()?;
+    Some(parse_list(&value)).filter(|caches| !caches.is_empty())
+}
+
+/// The binary caches listed in [`BINARY_CACHES_ENV`] if it is set. Otherwise the substituters of
+/// the nix configuration and of the flake, run with `nix_binary` if given. Caches given explicitly,
+/// e.g. with `--binary-caches`, take precedence over both and this is not called.
+pub fn get_substituters(flake_ref: String, nix_binary: Option<&Path>) -> Result<Substituters> {
+    get_flakes_substituters(&[flake_ref], nix_binary)
+}
+
+/// Like [`get_substituters`], with the substituters of all of `flake_refs` for runs extracting
+/// several flakes
+pub fn get_flakes_substituters(
+    flake_refs: &[String],
+    nix_binary: Option<&Path>,
+) -> Result<Substituters> {
+    if let Some(caches) = from_env() {
+        log::debug!("Using the binary caches of {}", BINARY_CACHES_ENV);
+        return Ok(combine(caches));
+    }
+
+    let mut substituters = from_nix_conf(nix_binary)?;
+    for flake_ref in flake_refs {
+        substituters.extend(from_flake_ref(flake_ref, nix_binary)?);
+    }
+    Ok(combine(substituters))
 }
 
 #[cfg(test)]
@@ -78,25 +154,64 @@ mod tests {
     #[test]
     fn test_from_flake_ref() {
         let flake_ref = "github:tweag/nixtract";
-        let substituters = from_flake_ref(flake_ref);
+        let substituters = from_flake_ref(flake_ref, None);
 
         assert!(substituters.is_ok());
     }
 
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            parse_list(" https://cache.nixos.org,https://example.cachix.org  file:///var/cache\n"),
+            vec![
+                "https://cache.nixos.org",
+                "https://example.cachix.org",
+                "file:///var/cache"
+            ]
+        );
+        assert!(parse_list(" , ").is_empty());
+    }
+
     #[test]
     fn test_from_nix_conf() {
-        let substituters = from_nix_conf();
+        let substituters = from_nix_conf(None);
 
         assert!(substituters.is_ok());
     }
 
+    #[test]
+    fn test_combine() {
+        let substituters = combine([
+            "https://cache.nixos.org/".to_owned(),
+            "https://slow.example.org?priority=90".to_owned(),
+            "https://nix-community.cachix.org".to_owned(),
+            "https://cache.nixos.org".to_owned(),
+            "https://fast.example.org?priority=10".to_owned(),
+        ]);
+
+        assert_eq!(
+            substituters,
+            vec![
+                "https://fast.example.org?priority=10",
+                "https://cache.nixos.org/",
+                "https://nix-community.cachix.org",
+                "https://slow.example.org?priority=90",
+            ]
+        );
+    }
+
     /// This test gets the substituters and passes them along to the
     /// NarInfo::fetch function to ensure they are correct.
     #[test]
     fn test_get_substituters() {
         let store_path = "/nix/store/1gxz5nfzfnhyxjdyzi04r86sh61y4i00-hello-2.12.1";
-        let substituters = get_substituters("nixpkgs".to_owned()).unwrap();
-        let nar_info = crate::narinfo::NarInfo::fetch(store_path, &substituters);
+        let substituters = get_substituters("nixpkgs".to_owned(), None).unwrap();
+        let nar_info = crate::narinfo::NarInfo::fetch(
+            &reqwest::blocking::Client::new(),
+            &crate::narinfo::FetchConfig::default(),
+            store_path,
+            &substituters,
+        );
 
         assert!(nar_info.is_ok_and(|n| n.is_some_and(|n| n.store_path == store_path)))
     }
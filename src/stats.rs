@@ -0,0 +1,112 @@
+//! Aggregate statistics of a nixtract run, useful to sanity-check that an
+//! extraction covered what was expected.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::DerivationDescription;
+
+/// Shared between the worker threads and the caller, read it once the iterator is exhausted
+pub type SharedStats = Arc<Mutex<Stats>>;
+
+/// Counts of what happened to the derivations encountered during a run
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Stats {
+    /// Derivations that were described and sent to the caller
+    pub described: usize,
+    /// Derivations that were not described again because they were already seen
+    pub skipped: usize,
+    /// Derivations that matched one of the exclude patterns
+    pub excluded: usize,
+    /// Derivations that could not be described
+    pub failed: usize,
+    /// Narinfo files that were successfully fetched from a binary cache
+    pub narinfo_fetched: usize,
+    /// Described derivations whose narinfo could not be fetched, their size is missing from
+    /// `closure_nar_size`
+    pub narinfo_missing: usize,
+    /// Sum of the `nar_size` of the described output paths, the size of the closure once
+    /// unpacked
+    pub closure_nar_size: usize,
+    /// Output paths already counted in `closure_nar_size`, a description can be sent again for
+    /// another attribute path with the same output path
+    #[serde(skip)]
+    sized_paths: HashSet<String>,
+    /// Derivations whose licenses the license policy does not allow, dropped or flagged
+    pub license_violations: usize,
+}
+
+impl Stats {
+    /// Count the narinfo of a description towards the closure size, once per output path. Call it
+    /// only when narinfo was requested, a missing narinfo makes the size partial.
+    pub fn record_nar_info(&mut self, description: &DerivationDescription) {
+        if let Some(output_path) = &description.output_path {
+            if !self.sized_paths.insert(output_path.clone()) {
+                return;
+            }
+        }
+        match &description.nar_info {
+            Some(nar_info) => {
+                self.narinfo_fetched += 1;
+                self.closure_nar_size += nar_info.nar_size;
+            }
+            None => self.narinfo_missing += 1,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} described, {} skipped, {} excluded, {} failed, {} narinfo fetched, {} license violations",
+            self.described,
+            self.skipped,
+            self.excluded,
+            self.failed,
+            self.narinfo_fetched,
+            self.license_violations
+        )?;
+
+        // Only known when narinfo was fetched
+        if self.narinfo_fetched > 0 || self.narinfo_missing > 0 {
+            write!(
+                f,
+                ", closure size {:.1} MiB",
+                self.closure_nar_size as f64 / (1024.0 * 1024.0)
+            )?;
+            if self.narinfo_missing > 0 {
+                write!(
+                    f,
+                    " (partial, {} output paths without narinfo are not counted)",
+                    self.narinfo_missing
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_closure_size() {
+        let mut stats = Stats {
+            described: 2,
+            ..Default::default()
+        };
+        assert!(!stats.to_string().contains("closure size"));
+
+        stats.closure_nar_size = 3 * 1024 * 1024;
+        stats.narinfo_fetched = 2;
+        assert!(stats.to_string().ends_with(", closure size 3.0 MiB"));
+
+        stats.narinfo_missing = 1;
+        assert!(stats.to_string().ends_with(
+            ", closure size 3.0 MiB (partial, 1 output paths without narinfo are not counted)"
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+//! Async entry point to nixtract, enabled with the `async` feature.
+//!
+//! The extraction itself still runs on the rayon thread pool, the results are
+//! bridged into a tokio channel so async callers never block their runtime.
+
+use futures::Stream;
+
+use crate::{error::Result, DerivationDescription, NixtractBuilder, NixtractConfig};
+
+impl NixtractBuilder {
+    /// Start extracting, returns a stream of the described derivations.
+    ///
+    /// A run that fails, with `fail_fast`, `strict` or `timeout_total`, ends the stream with its
+    /// error, see [`Descriptions::error`](crate::Descriptions::error).
+    ///
+    /// The setup (fetching the substituters and finding the top-level derivations) happens on a
+    /// dedicated thread, so awaiting this does not block the async runtime.
+    pub async fn run_stream(self) -> Result<impl Stream<Item = Result<DerivationDescription>>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (setup_tx, setup_rx) = tokio::sync::oneshot::channel();
+
+        std::thread::spawn(move || match self.run() {
+            Ok(mut descriptions) => {
+                let _ = setup_tx.send(Ok(()));
+                for description in descriptions.by_ref() {
+                    // Stop forwarding once the stream has been dropped
+                    if tx.send(Ok(description)).is_err() {
+                        return;
+                    }
+                }
+                if let Some(e) = descriptions.error() {
+                    let _ = tx.send(Err(e));
+                }
+            }
+            Err(e) => {
+                let _ = setup_tx.send(Err(e));
+            }
+        });
+
+        setup_rx
+            .await
+            .expect("The nixtract thread exited before finishing its setup")?;
+
+        Ok(futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|description| (description, rx))
+        }))
+    }
+}
+
+/// Async version of [`crate::nixtract`], returning a stream instead of a blocking iterator
+pub async fn nixtract_stream(
+    flake_ref: impl Into<String>,
+    system: Option<impl Into<String>>,
+    attribute_path: Option<impl Into<String>>,
+    config: NixtractConfig,
+) -> Result<impl Stream<Item = Result<DerivationDescription>>> {
+    let mut builder = crate::nixtract_builder()
+        .flake_ref(flake_ref)
+        .config(config);
+    if let Some(system) = system {
+        builder = builder.system(system);
+    }
+    if let Some(attribute_path) = attribute_path {
+        builder = builder.attribute_path(attribute_path);
+    }
+
+    builder.run_stream().await
+}
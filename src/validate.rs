@@ -0,0 +1,143 @@
+//! Check an existing JSONL output for completeness, every build input it references should be
+//! described in it as well. Extractions log failures instead of stopping, so this is how to tell
+//! a long extraction did not silently drop dependencies.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::BufRead;
+
+use crate::DerivationDescription;
+
+/// A build input whose output path is not described anywhere in the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DanglingReference {
+    /// Attribute path of the description the build input belongs to
+    pub attribute_path: String,
+    /// Attribute path of the build input
+    pub build_input: String,
+    /// The output path that is not described
+    pub output_path: String,
+}
+
+/// Result of validating an output, it is complete if there are neither invalid lines nor
+/// dangling references
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Validation {
+    /// Lines that were parsed into a description
+    pub descriptions: usize,
+    /// Line numbers, starting at 1, and parsing errors of lines that are not a description
+    pub invalid_lines: Vec<(usize, String)>,
+    /// Build inputs that are not described, in the order they appear in
+    pub dangling: Vec<DanglingReference>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.invalid_lines.is_empty() && self.dangling.is_empty()
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (line, error) in &self.invalid_lines {
+            writeln!(f, "line {}: not a description: {}", line, error)?;
+        }
+        for dangling in &self.dangling {
+            writeln!(
+                f,
+                "{} depends on {} ({}), which is not described",
+                dangling.attribute_path, dangling.build_input, dangling.output_path
+            )?;
+        }
+        write!(
+            f,
+            "{} descriptions, {} invalid lines, {} dangling references",
+            self.descriptions,
+            self.invalid_lines.len(),
+            self.dangling.len()
+        )
+    }
+}
+
+/// Parse every line of a JSONL output and check that the output path of every build input is
+/// described. Build inputs without an output path cannot be checked and are ignored.
+pub fn validate(reader: impl BufRead) -> std::io::Result<Validation> {
+    let mut validation = Validation::default();
+    let mut described = HashSet::new();
+    // Checked once every line is read, a dependency can be written after its dependents
+    let mut references = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let description: DerivationDescription = match serde_json::from_str(&line) {
+            Ok(description) => description,
+            Err(e) => {
+                validation.invalid_lines.push((index + 1, e.to_string()));
+                continue;
+            }
+        };
+        validation.descriptions += 1;
+
+        described.extend(description.output_path.clone());
+        described.extend(
+            description
+                .outputs
+                .iter()
+                .filter_map(|output| output.output_path.clone()),
+        );
+        for build_input in description.build_inputs {
+            if let Some(output_path) = build_input.output_path {
+                references.push(DanglingReference {
+                    attribute_path: description.attribute_path.clone(),
+                    build_input: build_input.attribute_path,
+                    output_path,
+                });
+            }
+        }
+    }
+
+    validation.dangling = references
+        .into_iter()
+        .filter(|reference| !described.contains(&reference.output_path))
+        .collect();
+
+    Ok(validation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::tests::description;
+
+    #[test]
+    fn test_validate() {
+        let hello = description("hello", "2.12.1", &[]);
+        let hello_path = hello.output_path.clone().unwrap();
+        let app = description("app", "1.0", &[&hello_path, "/nix/store/missing-zlib"]);
+
+        // The dependency is written after its dependent, and blank lines are ignored
+        let output = format!(
+            "{}\n\n{}\nnot json\n",
+            serde_json::to_string(&app).unwrap(),
+            serde_json::to_string(&hello).unwrap()
+        );
+        let validation = validate(output.as_bytes()).unwrap();
+
+        assert_eq!(validation.descriptions, 2);
+        assert_eq!(validation.invalid_lines.len(), 1);
+        assert_eq!(validation.invalid_lines[0].0, 4);
+        assert_eq!(
+            validation.dangling,
+            vec![DanglingReference {
+                attribute_path: "app".to_owned(),
+                build_input: app.build_inputs[1].attribute_path.clone(),
+                output_path: "/nix/store/missing-zlib".to_owned(),
+            }]
+        );
+        assert!(!validation.is_valid());
+    }
+}